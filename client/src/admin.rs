@@ -0,0 +1,341 @@
+use crate::api::Api;
+use crate::settings::SettingsMessage;
+use crate::Message;
+use chrono::{TimeZone, Utc};
+use iced::pure::{button, column, container, row, text, text_input, Element};
+use iced::{Alignment, Command, Length};
+use proto::client::admin::{AdminInvite, UserDetails, UserSummary};
+
+#[derive(Debug, Clone)]
+pub enum AdminMessage {
+    ShowUsers,
+    ShowInvites,
+    Users(Vec<UserSummary>, String), // page, next page token
+    MoreUsers,
+    Invites(Vec<AdminInvite>, String), // page, next page token
+    MoreInvites,
+    Select(String),
+    User(UserDetails),
+    SetDisabled(String, bool),
+    RevokeSessions(String),
+    NewPasswordChange(String),
+    ResetPassword(String),
+    DeleteUser(String),
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum View {
+    Users,
+    User,
+    Invites,
+}
+
+#[derive(Debug, Clone)]
+pub struct Admin {
+    api: Api,
+    view: View,
+    users: Option<Vec<UserSummary>>,
+    next_users: String,
+    user: Option<UserDetails>,
+    invites: Option<Vec<AdminInvite>>,
+    next_invites: String,
+    new_password: String,
+}
+
+fn admin_message(msg: AdminMessage) -> Message {
+    Message::Settings(SettingsMessage::Admin(msg))
+}
+
+fn on_error(e: crate::api::Error) -> Message {
+    admin_message(AdminMessage::Error(format!("{:?}", e)))
+}
+
+impl Admin {
+    pub fn new(api: Api) -> Self {
+        Self {
+            api,
+            view: View::Users,
+            users: None,
+            next_users: String::new(),
+            user: None,
+            invites: None,
+            next_invites: String::new(),
+            new_password: String::new(),
+        }
+    }
+
+    fn list_users(&self, page_token: String) -> Command<Message> {
+        let mut api = self.api.clone();
+        Command::perform(
+            async move { api.list_users(page_token).await },
+            |res| match res {
+                Ok((users, next)) => admin_message(AdminMessage::Users(users, next)),
+                Err(e) => on_error(e),
+            },
+        )
+    }
+
+    fn list_invites(&self, page_token: String) -> Command<Message> {
+        let mut api = self.api.clone();
+        Command::perform(
+            async move { api.list_all_invites(page_token).await },
+            |res| match res {
+                Ok((invites, next)) => admin_message(AdminMessage::Invites(invites, next)),
+                Err(e) => on_error(e),
+            },
+        )
+    }
+
+    fn get_user(&self, username: String) -> Command<Message> {
+        let mut api = self.api.clone();
+        Command::perform(
+            async move { api.get_user(username).await },
+            |res| match res {
+                Ok(user) => admin_message(AdminMessage::User(user)),
+                Err(e) => on_error(e),
+            },
+        )
+    }
+
+    pub fn update(&mut self, msg: AdminMessage) -> Command<Message> {
+        match msg {
+            AdminMessage::ShowUsers => {
+                self.view = View::Users;
+                self.users = None;
+                self.user = None;
+                return self.list_users(String::new());
+            }
+            AdminMessage::ShowInvites => {
+                self.view = View::Invites;
+                self.invites = None;
+                return self.list_invites(String::new());
+            }
+            AdminMessage::Users(users, next) => {
+                self.users.get_or_insert_with(Vec::new).extend(users);
+                self.next_users = next;
+            }
+            AdminMessage::MoreUsers => return self.list_users(self.next_users.clone()),
+            AdminMessage::Invites(invites, next) => {
+                self.invites.get_or_insert_with(Vec::new).extend(invites);
+                self.next_invites = next;
+            }
+            AdminMessage::MoreInvites => return self.list_invites(self.next_invites.clone()),
+            AdminMessage::Select(username) => {
+                self.view = View::User;
+                self.user = None;
+                self.new_password.clear();
+                return self.get_user(username);
+            }
+            AdminMessage::User(user) => self.user = Some(user),
+            AdminMessage::SetDisabled(username, disabled) => {
+                let mut api = self.api.clone();
+                return Command::perform(
+                    async move {
+                        api.set_user_disabled(username.clone(), disabled)
+                            .await
+                            .map(|_| username)
+                    },
+                    |res| match res {
+                        Ok(username) => admin_message(AdminMessage::Select(username)),
+                        Err(e) => on_error(e),
+                    },
+                );
+            }
+            AdminMessage::RevokeSessions(username) => {
+                let mut api = self.api.clone();
+                return Command::perform(
+                    async move {
+                        api.revoke_user_sessions(username.clone())
+                            .await
+                            .map(|_| username)
+                    },
+                    |res| match res {
+                        Ok(username) => admin_message(AdminMessage::Select(username)),
+                        Err(e) => on_error(e),
+                    },
+                );
+            }
+            AdminMessage::NewPasswordChange(password) => self.new_password = password,
+            AdminMessage::ResetPassword(username) => {
+                let mut api = self.api.clone();
+                let new_password = self.new_password.clone();
+                self.new_password.clear();
+                return Command::perform(
+                    async move {
+                        api.reset_password(username.clone(), new_password)
+                            .await
+                            .map(|_| username)
+                    },
+                    |res| match res {
+                        Ok(username) => admin_message(AdminMessage::Select(username)),
+                        Err(e) => on_error(e),
+                    },
+                );
+            }
+            AdminMessage::DeleteUser(username) => {
+                let mut api = self.api.clone();
+                return Command::perform(async move { api.delete_user(username).await }, |res| {
+                    match res {
+                        Ok(()) => admin_message(AdminMessage::ShowUsers),
+                        Err(e) => on_error(e),
+                    }
+                });
+            }
+            AdminMessage::Error(e) => eprintln!("{}", e),
+        }
+        Command::none()
+    }
+
+    pub fn display(&self) -> Element<AdminMessage> {
+        let tabs = row()
+            .spacing(10)
+            .push(button(text("Users")).on_press(AdminMessage::ShowUsers))
+            .push(button(text("Invites")).on_press(AdminMessage::ShowInvites));
+        let body = match &self.view {
+            View::Users => match &self.users {
+                Some(users) => Self::show_users(users, !self.next_users.is_empty()),
+                None => text("Loading...").into(),
+            },
+            View::User => match &self.user {
+                Some(user) => Self::show_user(user, &self.new_password),
+                None => text("Loading...").into(),
+            },
+            View::Invites => match &self.invites {
+                Some(invites) => Self::show_invites(invites, !self.next_invites.is_empty()),
+                None => text("Loading...").into(),
+            },
+        };
+        column()
+            .align_items(Alignment::Center)
+            .spacing(16)
+            .push(tabs)
+            .push(body)
+            .into()
+    }
+
+    fn show_users(users: &[UserSummary], more: bool) -> Element<AdminMessage> {
+        let mut columns = column().spacing(10);
+        for user in users.iter() {
+            let mut status = user.roles.join(", ");
+            if user.disabled {
+                status.push_str(" (disabled)");
+            }
+            columns = columns.push(
+                row()
+                    .align_items(Alignment::Center)
+                    .spacing(10)
+                    .push(text(&user.username).width(Length::Fill))
+                    .push(text(status))
+                    .push(button(text(">")).on_press(AdminMessage::Select(user.username.clone()))),
+            );
+        }
+        if more {
+            columns = columns.push(button(text("More")).on_press(AdminMessage::MoreUsers));
+        }
+        columns.into()
+    }
+
+    fn show_user<'a>(user: &'a UserDetails, new_password: &str) -> Element<'a, AdminMessage> {
+        let invited = if user.invited_by.is_empty() {
+            "Signed up without invite".to_string()
+        } else {
+            format!(
+                "Invited by {} on {}",
+                user.invited_by,
+                Utc.timestamp(user.signup_date as i64, 0)
+            )
+        };
+        let username = &user.username;
+        let mut columns = column()
+            .spacing(10)
+            .push(text(username).size(40))
+            .push(text(format!("Roles: {}", user.roles.join(", "))))
+            .push(text(invited))
+            .push(text(if user.totp_enabled {
+                "Two-factor authentication enabled"
+            } else {
+                "Two-factor authentication disabled"
+            }))
+            .push(text(format!("{} sessions", user.sessions.len())));
+        for session in user.sessions.iter() {
+            let device = if session.device_name.is_empty() {
+                &session.user_agent
+            } else {
+                &session.device_name
+            };
+            columns = columns.push(text(format!(
+                "{} from {}, last used {}",
+                device,
+                session.from,
+                Utc.timestamp(session.last_use as i64, 0)
+            )));
+        }
+        columns
+            .push(
+                row()
+                    .spacing(10)
+                    .push(
+                        button(text(if user.disabled { "Enable" } else { "Disable" }))
+                            .on_press(AdminMessage::SetDisabled(username.clone(), !user.disabled)),
+                    )
+                    .push(
+                        button(text("Logout everywhere"))
+                            .on_press(AdminMessage::RevokeSessions(username.clone())),
+                    )
+                    .push(
+                        button(text("Delete")).on_press(AdminMessage::DeleteUser(username.clone())),
+                    ),
+            )
+            .push(
+                row()
+                    .spacing(10)
+                    .push(
+                        text_input(
+                            "New password",
+                            new_password,
+                            AdminMessage::NewPasswordChange,
+                        )
+                        .password()
+                        .padding(10),
+                    )
+                    .push(
+                        button(text("Reset password"))
+                            .on_press(AdminMessage::ResetPassword(username.clone())),
+                    ),
+            )
+            .into()
+    }
+
+    fn show_invites(invites: &[AdminInvite], more: bool) -> Element<AdminMessage> {
+        let mut columns = column().spacing(10);
+        for AdminInvite { owner, invite } in invites.iter() {
+            let invite = match invite {
+                Some(invite) => invite,
+                None => continue,
+            };
+            let status = if invite.used {
+                format!("used by {}", invite.used_by.join(", "))
+            } else {
+                format!("{}/{} uses", invite.used_by.len(), invite.max_uses)
+            };
+            columns = columns.push(
+                container(
+                    column()
+                        .push(
+                            row()
+                                .spacing(10)
+                                .push(text(owner))
+                                .push(text(&invite.token)),
+                        )
+                        .push(text(status)),
+                )
+                .width(Length::Fill),
+            );
+        }
+        if more {
+            columns = columns.push(button(text("More")).on_press(AdminMessage::MoreInvites));
+        }
+        columns.into()
+    }
+}
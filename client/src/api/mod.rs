@@ -2,14 +2,23 @@ use std::future::Future;
 use tonic::metadata::MetadataValue;
 use tonic::transport::{Channel, Endpoint};
 
+use proto::client::admin::{
+    delete_user_res, get_user_res, list_invites_res, list_users_res, reset_password_res,
+    revoke_user_sessions_res, set_user_disabled_res, AdminInvite, DeleteUserReq, GetUserReq,
+    ListInvitesReq, ListUsersReq, ResetPasswordReq, RevokeUserSessionsReq, SetUserDisabledReq,
+    UserDetails, UserSummary,
+};
 use proto::client::auth::{
-    get_access_token_res, get_refresh_token_res, signup_res, GetAccessTokenReq, GetRefreshTokenReq,
-    SignupReq,
+    get_access_token_res, get_refresh_token_res, signup_res, verify_mfa_res, GetAccessTokenReq,
+    GetRefreshTokenReq, LogoutReq, SignupReq, VerifyMfaReq,
 };
 use proto::client::user::{
-    change_password_res, create_invite_token_res, delete_refresh_token_res, get_invite_tokens_res,
-    get_refresh_tokens_res, ChangePasswordReq, CreateInviteTokenReq, DeleteRefreshTokenReq,
-    GetInviteTokensReq, GetRefreshTokensReq, InviteToken, RefreshToken,
+    change_password_res, confirm_totp_res, create_invite_token_res, delete_refresh_token_res,
+    disable_totp_res, enroll_totp_res, get_invite_tokens_res, get_refresh_tokens_res, get_totp_res,
+    regenerate_recovery_codes_res, revoke_all_sessions_res, revoke_invite_token_res,
+    ChangePasswordReq, ConfirmTotpReq, CreateInviteTokenReq, DeleteRefreshTokenReq, DisableTotpReq,
+    EnrollTotpReq, GetInviteTokensReq, GetRefreshTokensReq, GetTotpReq, InviteToken, RefreshToken,
+    RegenerateRecoveryCodesReq, RevokeAllSessionsReq, RevokeInviteTokenReq,
 };
 use serde::{Deserialize, Serialize};
 use std::sync::{
@@ -21,8 +30,11 @@ use tokio::sync::Mutex;
 
 const ADDR: &str = "http://127.0.0.1:5051";
 
+const INVITE_DURATION: u32 = 60 * 60 * 24 * 7; /* 1 week in seconds */
+
 type AuthClient = proto::client::auth::auth_client::AuthClient<tonic::transport::Channel>;
 type UserClient = proto::client::user::user_client::UserClient<tonic::transport::Channel>;
+type AdminClient = proto::client::admin::admin_client::AdminClient<tonic::transport::Channel>;
 type TonicRes<T> = Result<tonic::Response<T>, tonic::Status>;
 
 #[derive(Debug, Clone)]
@@ -38,6 +50,8 @@ struct AccessTokenClaims {
     pub sub: String, /*  Username  */
     exp: usize,      /* expiration */
     iss: String,     /*   access   */
+    #[serde(default)]
+    roles: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,6 +64,7 @@ struct RefreshTokenClaims {
 #[derive(Debug, Clone)]
 struct Clients {
     user_client: Arc<Mutex<UserClient>>,
+    admin_client: Arc<Mutex<AdminClient>>,
 }
 
 // struct AuthIntercept(String);
@@ -66,7 +81,8 @@ struct Clients {
 impl Clients {
     pub fn new(channel: Channel) -> Self {
         Self {
-            user_client: Arc::new(Mutex::new(UserClient::new(channel))),
+            user_client: Arc::new(Mutex::new(UserClient::new(channel.clone()))),
+            admin_client: Arc::new(Mutex::new(AdminClient::new(channel))),
         }
     }
 }
@@ -84,10 +100,22 @@ struct Creds {
 pub struct Api {
     creds: Arc<Mutex<Option<Creds>>>,
     _as_creds: Arc<AtomicBool>,
+    _is_staff: Arc<AtomicBool>,          // Any role in the access token
     auth_client: Arc<Mutex<AuthClient>>, // THe only not connected client
     channel: Channel,
 }
 
+// Shown in the session list of the settings page
+#[cfg(not(target_arch = "wasm32"))]
+fn device_name() -> String {
+    format!("AnApp desktop ({})", std::env::consts::OS)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn device_name() -> String {
+    "AnApp web".to_string()
+}
+
 fn get_now() -> u32 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -105,6 +133,7 @@ impl Api {
         Ok(Self {
             creds: Arc::new(Mutex::new(None)),
             _as_creds: Arc::new(AtomicBool::new(false)),
+            _is_staff: Arc::new(AtomicBool::new(false)),
             auth_client,
             channel,
         })
@@ -118,16 +147,38 @@ impl Api {
             .map_or("Unknown".to_string(), |c| c.username.clone())
     }
 
+    // The local creds are dropped even if the server cannot be reached
     pub async fn logout(&mut self) {
         let mut creds = self.creds.lock().await;
+        if let Some(creds) = creds.as_ref() {
+            let req = tonic::Request::new(LogoutReq {
+                username: creds.username.clone(),
+                refresh_token: creds.refresh_token.clone(),
+            });
+            if let Err(e) = self.auth_client.lock().await.logout(req).await {
+                eprintln!("Logout error: {}", e);
+            }
+        }
         *creds = None;
         self._as_creds.store(false, Ordering::Relaxed);
+        self._is_staff.store(false, Ordering::Relaxed);
     }
 
     pub fn as_creds(&self) -> bool {
         self._as_creds.load(Ordering::Relaxed)
     }
 
+    // Only used to show the admin page, the server checks the roles
+    pub fn is_staff(&self) -> bool {
+        self._is_staff.load(Ordering::Relaxed)
+    }
+
+    fn store_roles(&self, access_token: &str) {
+        let is_staff = jsonwebtoken::dangerous_insecure_decode::<AccessTokenClaims>(access_token)
+            .map_or(false, |token| !token.claims.roles.is_empty());
+        self._is_staff.store(is_staff, Ordering::Relaxed);
+    }
+
     // async fn get_access_token(
     //     &self,
     //     username: String,
@@ -171,10 +222,16 @@ impl Api {
     //     Ok(access_token)
     // }
 
-    pub async fn login(&mut self, username: String, password: String) -> Result<(), Error> {
+    // Some(mfa_token) when the account needs a TOTP code, see verify_mfa
+    pub async fn login(
+        &mut self,
+        username: String,
+        password: String,
+    ) -> Result<Option<String>, Error> {
         let req = tonic::Request::new(GetRefreshTokenReq {
             username: username.clone(),
             password,
+            device_name: device_name(),
         });
         let res = self
             .auth_client
@@ -185,11 +242,43 @@ impl Api {
             .map_err(|e| Error::Internal(e.to_string()))?;
         let res = match res.into_inner().payload {
             Some(get_refresh_token_res::Payload::Ok(bdy)) => bdy,
+            Some(get_refresh_token_res::Payload::Mfa(mfa)) => return Ok(Some(mfa.mfa_token)),
             Some(get_refresh_token_res::Payload::Error(e)) => {
                 return Err(Error::ServerError(format!("{:?}", e)))
             }
             None => return Err(Error::Internal("Empty payload".to_string())),
         };
+        self.store_roles(&res.access_token);
+        *(self.creds.lock().await) = Some(Creds {
+            clients: Clients::new(self.channel.clone()),
+            username,
+            refresh_token: res.refresh_token,
+            access_token: res.access_token,
+            access_exp: res.access_exp,
+        });
+        self._as_creds.store(true, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    pub async fn verify_mfa(
+        &mut self,
+        username: String,
+        mfa_token: String,
+        code: String,
+    ) -> Result<(), Error> {
+        let req = tonic::Request::new(VerifyMfaReq { mfa_token, code });
+        let res = self
+            .auth_client
+            .lock()
+            .await
+            .verify_mfa(req)
+            .await
+            .map_err(|e| Error::CredentialsError(e.message().to_string()))?;
+        let res = match res.into_inner().payload {
+            Some(verify_mfa_res::Payload::Ok(bdy)) => bdy,
+            None => return Err(Error::Internal("Empty payload".to_string())),
+        };
+        self.store_roles(&res.access_token);
         *(self.creds.lock().await) = Some(Creds {
             clients: Clients::new(self.channel.clone()),
             username,
@@ -211,6 +300,7 @@ impl Api {
             username: username.clone(),
             password,
             invite_code,
+            device_name: device_name(),
         });
         let res = self
             .auth_client
@@ -227,6 +317,7 @@ impl Api {
             }
             None => return Err(Error::Internal("Empty Payload".to_string())),
         };
+        self.store_roles(&res.access_token);
         *(self.creds.lock().await) = Some(Creds {
             clients: Clients::new(self.channel.clone()),
             username,
@@ -265,15 +356,20 @@ impl Api {
                     Ok(res) => res,
                     Err(e) => return Err(Error::Internal(e.to_string())),
                 };
-                let (access_token, access_exp) = match res.into_inner().payload {
-                    Some(get_access_token_res::Payload::Ok(bdy)) => (bdy.access_token, bdy.exp),
+                let (access_token, access_exp, refresh_token) = match res.into_inner().payload {
+                    Some(get_access_token_res::Payload::Ok(bdy)) => {
+                        (bdy.access_token, bdy.exp, bdy.refresh_token)
+                    }
                     Some(get_access_token_res::Payload::Error(e)) => {
                         return Err(Error::ServerError(format!("{:?}", e)))
                     }
                     None => return Err(Error::Internal("Empty Payload".to_string())),
                 };
+                self.store_roles(&access_token);
                 creds.access_token = access_token.clone();
                 creds.access_exp = access_exp;
+                // The old one is now rotated, using it again would revoke the session
+                creds.refresh_token = refresh_token;
                 *creds_out = Some(creds);
                 MetadataValue::from_str(&access_token)
             } else {
@@ -304,6 +400,20 @@ impl Api {
             .clone())
     }
 
+    async fn get_admin_client(&self) -> Result<AdminClient, Error> {
+        Ok(self
+            .creds
+            .lock()
+            .await
+            .as_ref()
+            .ok_or(Error::CredentialsError("Not conected".to_string()))?
+            .clients
+            .admin_client
+            .lock()
+            .await
+            .clone())
+    }
+
     pub async fn get_refresh_tokens(&mut self) -> Result<Vec<RefreshToken>, Error> {
         let req = tonic::Request::new(GetRefreshTokensReq {});
         let mut user_client = self.get_user_client().await?;
@@ -326,6 +436,7 @@ impl Api {
         let req = tonic::Request::new(ChangePasswordReq {
             new_password,
             old_password,
+            keep_current_session: true,
         });
         let mut user_client = self.get_user_client().await?;
         match self
@@ -340,6 +451,28 @@ impl Api {
         }
     }
 
+    // Return the number of revoked sessions, the local creds are dropped with the current one
+    pub async fn revoke_all_sessions(&mut self, keep_current_session: bool) -> Result<u32, Error> {
+        let req = tonic::Request::new(RevokeAllSessionsReq {
+            keep_current_session,
+        });
+        let mut user_client = self.get_user_client().await?;
+        let revoked = match self
+            .priv_call(&mut user_client, &UserClient::revoke_all_sessions, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(revoke_all_sessions_res::Payload::Ok(res)) => res.revoked,
+            _ => return Err(Error::Internal("aaa".to_string())),
+        };
+        if !keep_current_session {
+            self.logout().await;
+        }
+        Ok(revoked)
+    }
+
     pub async fn delete_refresh_token(&mut self, refresh_token: String) -> Result<(), Error> {
         let req = tonic::Request::new(DeleteRefreshTokenReq { refresh_token });
         let mut user_client = self.get_user_client().await?;
@@ -356,7 +489,11 @@ impl Api {
     }
 
     pub async fn create_invite(&mut self) -> Result<InviteToken, Error> {
-        let req = tonic::Request::new(CreateInviteTokenReq {});
+        let req = tonic::Request::new(CreateInviteTokenReq {
+            expiration_date: get_now() + INVITE_DURATION,
+            max_uses: 1,
+            note: String::new(),
+        });
         let mut user_client = self.get_user_client().await?;
         match self
             .priv_call(&mut user_client, &UserClient::create_invite_token, req)
@@ -367,7 +504,7 @@ impl Api {
         {
             Some(create_invite_token_res::Payload::Ok(invite)) => match invite.token {
                 Some(invite) => Ok(invite),
-				_ => Err(Error::Internal("aaa".to_string())),
+                _ => Err(Error::Internal("aaa".to_string())),
             },
             _ => Err(Error::Internal("aaa".to_string())),
         }
@@ -387,4 +524,304 @@ impl Api {
             _ => Err(Error::Internal("aaa".to_string())),
         }
     }
+
+    pub async fn revoke_invite(&mut self, token: String) -> Result<(), Error> {
+        let req = tonic::Request::new(RevokeInviteTokenReq { token });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(&mut user_client, &UserClient::revoke_invite_token, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(revoke_invite_token_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return if enabled and the number of recovery codes left
+    pub async fn get_totp(&mut self) -> Result<(bool, u32), Error> {
+        let req = tonic::Request::new(GetTotpReq {});
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(&mut user_client, &UserClient::get_totp, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(get_totp_res::Payload::Ok(totp)) => Ok((totp.enabled, totp.recovery_codes_left)),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the secret and the otpauth uri
+    pub async fn enroll_totp(&mut self) -> Result<(String, String), Error> {
+        let req = tonic::Request::new(EnrollTotpReq {});
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(&mut user_client, &UserClient::enroll_totp, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(enroll_totp_res::Payload::Ok(totp)) => Ok((totp.secret, totp.uri)),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the recovery codes
+    pub async fn confirm_totp(&mut self, code: String) -> Result<Vec<String>, Error> {
+        let req = tonic::Request::new(ConfirmTotpReq { code });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(&mut user_client, &UserClient::confirm_totp, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(confirm_totp_res::Payload::Ok(totp)) => Ok(totp.recovery_codes),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn disable_totp(&mut self, code: String) -> Result<(), Error> {
+        let req = tonic::Request::new(DisableTotpReq { code });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(&mut user_client, &UserClient::disable_totp, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(disable_totp_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn regenerate_recovery_codes(&mut self, code: String) -> Result<Vec<String>, Error> {
+        let req = tonic::Request::new(RegenerateRecoveryCodesReq { code });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(
+                &mut user_client,
+                &UserClient::regenerate_recovery_codes,
+                req,
+            )
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(regenerate_recovery_codes_res::Payload::Ok(res)) => Ok(res.recovery_codes),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the users and the next page token, empty on the last page
+    pub async fn list_users(
+        &mut self,
+        page_token: String,
+    ) -> Result<(Vec<UserSummary>, String), Error> {
+        let req = tonic::Request::new(ListUsersReq {
+            page_token,
+            page_size: 0,
+        });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::list_users, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(list_users_res::Payload::Ok(res)) => Ok((res.users, res.next_page_token)),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn get_user(&mut self, username: String) -> Result<UserDetails, Error> {
+        let req = tonic::Request::new(GetUserReq { username });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::get_user, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(get_user_res::Payload::Ok(res)) => match res.user {
+                Some(user) => Ok(user),
+                _ => Err(Error::Internal("aaa".to_string())),
+            },
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn set_user_disabled(
+        &mut self,
+        username: String,
+        disabled: bool,
+    ) -> Result<(), Error> {
+        let req = tonic::Request::new(SetUserDisabledReq { username, disabled });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::set_user_disabled, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(set_user_disabled_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the number of revoked sessions
+    pub async fn revoke_user_sessions(&mut self, username: String) -> Result<u32, Error> {
+        let req = tonic::Request::new(RevokeUserSessionsReq { username });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::revoke_user_sessions, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(revoke_user_sessions_res::Payload::Ok(res)) => Ok(res.revoked),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn reset_password(
+        &mut self,
+        username: String,
+        new_password: String,
+    ) -> Result<(), Error> {
+        let req = tonic::Request::new(ResetPasswordReq {
+            username,
+            new_password,
+        });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::reset_password, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(reset_password_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn delete_user(&mut self, username: String) -> Result<(), Error> {
+        let req = tonic::Request::new(DeleteUserReq { username });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::delete_user, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(delete_user_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the invites and the next page token, empty on the last page
+    pub async fn list_all_invites(
+        &mut self,
+        page_token: String,
+    ) -> Result<(Vec<AdminInvite>, String), Error> {
+        let req = tonic::Request::new(ListInvitesReq {
+            page_token,
+            page_size: 0,
+        });
+        let mut admin_client = self.get_admin_client().await?;
+        match self
+            .priv_call(&mut admin_client, &AdminClient::list_invites, req)
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(list_invites_res::Payload::Ok(res)) => Ok((res.invites, res.next_page_token)),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    // Return the full token, only shown once, and its public part
+    pub async fn create_personal_access_token(
+        &mut self,
+        name: String,
+        scopes: Vec<String>,
+    ) -> Result<(String, PersonalAccessToken), Error> {
+        let req = tonic::Request::new(CreatePersonalAccessTokenReq {
+            name,
+            scopes,
+            expiration_date: 0,
+        });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(
+                &mut user_client,
+                &UserClient::create_personal_access_token,
+                req,
+            )
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(create_personal_access_token_res::Payload::Ok(res)) => match res.token {
+                Some(token) => Ok((res.secret, token)),
+                _ => Err(Error::Internal("aaa".to_string())),
+            },
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn get_personal_access_tokens(&mut self) -> Result<Vec<PersonalAccessToken>, Error> {
+        let req = tonic::Request::new(GetPersonalAccessTokensReq {});
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(
+                &mut user_client,
+                &UserClient::get_personal_access_tokens,
+                req,
+            )
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(get_personal_access_tokens_res::Payload::Ok(res)) => Ok(res.tokens),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
+
+    pub async fn revoke_personal_access_token(&mut self, id: String) -> Result<(), Error> {
+        let req = tonic::Request::new(RevokePersonalAccessTokenReq { id });
+        let mut user_client = self.get_user_client().await?;
+        match self
+            .priv_call(
+                &mut user_client,
+                &UserClient::revoke_personal_access_token,
+                req,
+            )
+            .await?
+            .map_err(|e| Error::Internal(e.to_string()))?
+            .into_inner()
+            .payload
+        {
+            Some(revoke_personal_access_token_res::Payload::Ok(_)) => Ok(()),
+            _ => Err(Error::Internal("aaa".to_string())),
+        }
+    }
 }
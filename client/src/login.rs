@@ -18,6 +18,8 @@ pub struct Login {
     invite_code: String,
     show_signup: bool,
     is_loading: bool,
+    mfa_token: Option<String>, // Some -> waiting for the TOTP code
+    code: String,
 }
 
 #[derive(Debug, Clone)]
@@ -26,6 +28,8 @@ pub enum LoginMessage {
     PasswordChanged(String),
     Error(api::Error),
     InviteCodeChanged(String),
+    CodeChanged(String),
+    MfaRequired(String),
     Loading(bool),
     OkClicked,
     SwapClicked,
@@ -41,6 +45,8 @@ impl Login {
             invite_code: "".to_string(),
             show_signup: false,
             is_loading: false,
+            mfa_token: None,
+            code: "".to_string(),
         }
     }
 
@@ -51,9 +57,17 @@ impl Login {
             LoginMessage::UsernameChanged(username) => self.username = username,
             LoginMessage::PasswordChanged(password) => self.password = password,
             LoginMessage::InviteCodeChanged(invite_code) => self.invite_code = invite_code,
+            LoginMessage::CodeChanged(code) => self.code = code,
+            LoginMessage::MfaRequired(mfa_token) => {
+                self.is_loading = false;
+                self.password.clear();
+                self.code.clear();
+                self.mfa_token = Some(mfa_token);
+            }
             LoginMessage::Error(e) => {
                 self.is_loading = false;
                 self.password.clear();
+                self.code.clear();
                 eprintln!("{:?}", e)
             }
             LoginMessage::OkClicked => {
@@ -69,7 +83,13 @@ impl Login {
                 let username = self.username.to_string();
                 let password = self.password.to_string();
                 let invite_code = self.invite_code.to_string();
-                if self.show_signup {
+                if let Some(mfa_token) = self.mfa_token.clone() {
+                    let code = self.code.to_string();
+                    return Command::perform(
+                        async move { api.verify_mfa(username, mfa_token, code).await },
+                        res,
+                    );
+                } else if self.show_signup {
                     return Command::perform(
                         async move { api.signup(username, password, invite_code).await },
                         res,
@@ -77,12 +97,21 @@ impl Login {
                 } else {
                     return Command::perform(
                         async move { api.login(username, password).await },
-                        res,
+                        |res| match res {
+                            Ok(Some(mfa_token)) => {
+                                Message::Login(LoginMessage::MfaRequired(mfa_token))
+                            }
+                            Ok(None) => Message::Login(LoginMessage::Loading(false)),
+                            Err(e) => Message::Login(LoginMessage::Error(e)),
+                        },
                     );
                 }
             }
             LoginMessage::SwapClicked => {
-                self.show_signup = !self.show_signup;
+                // Back from the TOTP code to the login form
+                if self.mfa_token.take().is_none() {
+                    self.show_signup = !self.show_signup;
+                }
                 self.password.clear();
             }
         }
@@ -100,19 +129,32 @@ impl Login {
             .max_width(600)
             .padding(20)
             .spacing(16)
-            .push(title)
-            .push(
-                text_input("Username", &self.username, LoginMessage::UsernameChanged)
-                    .padding(10)
-                    .size(32),
-            )
-            .push(
-                text_input("Password", &self.password, LoginMessage::PasswordChanged)
-                    .padding(10)
-                    .size(32)
-                    .password(),
+            .push(title);
+        if self.mfa_token.is_some() {
+            inputs = inputs.push(
+                text_input(
+                    "Authenticator or recovery code",
+                    &self.code,
+                    LoginMessage::CodeChanged,
+                )
+                .padding(10)
+                .size(32),
             );
-        if self.show_signup {
+        } else {
+            inputs = inputs
+                .push(
+                    text_input("Username", &self.username, LoginMessage::UsernameChanged)
+                        .padding(10)
+                        .size(32),
+                )
+                .push(
+                    text_input("Password", &self.password, LoginMessage::PasswordChanged)
+                        .padding(10)
+                        .size(32)
+                        .password(),
+                );
+        }
+        if self.show_signup && self.mfa_token.is_none() {
             inputs = inputs.push(
                 text_input(
                     "Invite code",
@@ -125,6 +167,8 @@ impl Login {
         }
         let switch_text = if self.is_loading {
             "..."
+        } else if self.mfa_token.is_some() {
+            "Back to Login"
         } else if self.show_signup {
             "Switch to Login"
         } else {
@@ -132,6 +176,8 @@ impl Login {
         };
         let ok_text = if self.is_loading {
             "..."
+        } else if self.mfa_token.is_some() {
+            "Verify"
         } else if self.show_signup {
             "Signup"
         } else {
@@ -12,6 +12,8 @@ use grpc_web_client::Client as Channel;
 mod login;
 use login::{Login, LoginMessage};
 
+mod admin;
+
 mod settings;
 use settings::{Settings, SettingsMessage};
 
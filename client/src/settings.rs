@@ -1,13 +1,14 @@
+use crate::admin::{Admin, AdminMessage};
 use crate::api::Api;
 use crate::Message;
 use chrono::{TimeZone, Utc};
-use iced::pure::{button, column, container, row, text, text_input, Element};
+use iced::pure::{button, checkbox, column, container, row, text, text_input, Element};
 use iced::{
     alignment::{Horizontal, Vertical},
     Alignment, Color, Command, Length,
 };
 use iced_pure::widget::{button as pureButton, container as pureContainer};
-use proto::client::user::{InviteToken, RefreshToken};
+use proto::client::user::{InviteToken, PersonalAccessToken, RefreshToken};
 
 #[derive(Debug, Clone)]
 pub enum SettingsMessage {
@@ -17,11 +18,29 @@ pub enum SettingsMessage {
     Error(String),
     GoTo(Option<Page>),
     DeleteToken(String),
+    RevokeAllSessions,
+    Logout,
     OldPasswordChange(String),
     NewPasswordChange(String),
     NewPasswordChangeBis(String),
     ChangePassword,
     CreateInvite,
+    RevokeInvite(String),
+    Totp(bool, u32),              // enabled, recovery codes left
+    TotpEnrolled(String, String), // secret, otpauth uri
+    RecoveryCodes(Vec<String>),
+    RegenerateRecoveryCodes,
+    EnrollTotp,
+    TotpCodeChange(String),
+    ConfirmTotp,
+    DisableTotp,
+    Admin(AdminMessage),
+    AccessTokens(Vec<PersonalAccessToken>),
+    AccessTokenNameChange(String),
+    AccessTokenScope(String, bool),
+    CreateAccessToken,
+    AccessTokenCreated(String),
+    RevokeAccessToken(String),
 }
 
 struct TokenRow {}
@@ -50,11 +69,36 @@ impl pureButton::StyleSheet for TokenRow {
     }
 }
 
+struct QrModule(bool); // dark
+
+impl pureContainer::StyleSheet for QrModule {
+    fn style(&self) -> pureContainer::Style {
+        let color = if self.0 { Color::BLACK } else { Color::WHITE };
+        pureContainer::Style {
+            background: color.into(),
+            ..pureContainer::Style::default()
+        }
+    }
+}
+
+const QR_MODULE_SIZE: u16 = 5; /* pixels */
+
+const ACCESS_TOKEN_SCOPES: [&str; 5] = [
+    "sessions:read",
+    "sessions:write",
+    "invites:read",
+    "invites:write",
+    "admin",
+];
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Page {
     Password(bool), // bool -> is loading
     RefreshTokens,
     Invites,
+    Totp,
+    AccessTokens,
+    Admin,
 }
 
 #[derive(Debug, Clone)]
@@ -62,6 +106,16 @@ pub struct Settings {
     api: Api,
     refresh_tokens: Option<Vec<RefreshToken>>,
     invites: Option<Vec<InviteToken>>,
+    totp_enabled: Option<bool>,
+    totp_enrollment: Option<(String, String)>, // secret, otpauth uri
+    totp_code: String,
+    recovery_codes_left: u32,
+    recovery_codes: Option<Vec<String>>, // Just generated, shown once
+    access_tokens: Option<Vec<PersonalAccessToken>>,
+    access_token_name: String,
+    access_token_scopes: Vec<String>,
+    new_access_token: Option<String>, // Just created, shown once
+    admin: Admin,
     page: Option<Page>,
     old_password: String,
     new_password: String,
@@ -71,9 +125,19 @@ pub struct Settings {
 impl Settings {
     pub fn new(api: Api) -> Self {
         Self {
+            admin: Admin::new(api.clone()),
             api,
             refresh_tokens: None,
             invites: None,
+            totp_enabled: None,
+            totp_enrollment: None,
+            totp_code: String::new(),
+            recovery_codes_left: 0,
+            recovery_codes: None,
+            access_tokens: None,
+            access_token_name: String::new(),
+            access_token_scopes: Vec::new(),
+            new_access_token: None,
             page: None,
             old_password: String::new(),
             new_password: String::new(),
@@ -116,9 +180,83 @@ impl Settings {
                             }
                         });
                     }
-                    None => {}
+                    Some(Page::Totp) => {
+                        self.totp_enabled = None;
+                        self.totp_enrollment = None;
+                        self.totp_code.clear();
+                        self.recovery_codes = None;
+                        let mut api = self.api.clone();
+                        return Command::perform(
+                            async move { api.get_totp().await },
+                            |res| match res {
+                                Ok((enabled, left)) => {
+                                    Message::Settings(SettingsMessage::Totp(enabled, left))
+                                }
+                                Err(e) => {
+                                    Message::Settings(SettingsMessage::Error(format!("{:?}", e)))
+                                }
+                            },
+                        );
+                    }
+                    Some(Page::AccessTokens) => {
+                        self.access_tokens = None;
+                        let mut api = self.api.clone();
+                        return Command::perform(
+                            async move { api.get_personal_access_tokens().await },
+                            |res| match res {
+                                Ok(t) => Message::Settings(SettingsMessage::AccessTokens(t)),
+                                Err(e) => {
+                                    Message::Settings(SettingsMessage::Error(format!("{:?}", e)))
+                                }
+                            },
+                        );
+                    }
+                    Some(Page::Admin) => return self.admin.update(AdminMessage::ShowUsers),
+                    None => self.new_access_token = None,
                 }
             }
+            SettingsMessage::Admin(msg) => return self.admin.update(msg),
+            SettingsMessage::AccessTokens(tokens) => self.access_tokens = Some(tokens),
+            SettingsMessage::AccessTokenNameChange(name) => self.access_token_name = name,
+            SettingsMessage::AccessTokenScope(scope, checked) => {
+                self.access_token_scopes.retain(|s| *s != scope);
+                if checked {
+                    self.access_token_scopes.push(scope);
+                }
+            }
+            SettingsMessage::CreateAccessToken => {
+                let mut api = self.api.clone();
+                let name = self.access_token_name.clone();
+                let scopes = self.access_token_scopes.clone();
+                return Command::perform(
+                    async move { api.create_personal_access_token(name, scopes).await },
+                    |res| match res {
+                        Ok((secret, _token)) => {
+                            Message::Settings(SettingsMessage::AccessTokenCreated(secret))
+                        }
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
+            SettingsMessage::AccessTokenCreated(secret) => {
+                self.access_token_name.clear();
+                self.access_token_scopes.clear();
+                self.new_access_token = Some(secret);
+                return self.update(SettingsMessage::GoTo(Some(Page::AccessTokens)));
+            }
+            SettingsMessage::RevokeAccessToken(id) => {
+                self.access_tokens = None;
+                let mut api = self.api.clone();
+                return Command::perform(
+                    async move { api.revoke_personal_access_token(id).await },
+                    |res| match res {
+                        Ok(()) => {
+                            Message::Settings(SettingsMessage::GoTo(Some(Page::AccessTokens)))
+                        }
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
             SettingsMessage::Error(e) => eprintln!("{}", e),
             SettingsMessage::DeleteToken(t) => {
                 let mut api = self.api.clone();
@@ -132,6 +270,22 @@ impl Settings {
                     }
                 });
             }
+            SettingsMessage::RevokeAllSessions => {
+                let mut api = self.api.clone();
+                self.refresh_tokens = None;
+                return Command::perform(
+                    async move { api.revoke_all_sessions(false).await },
+                    |res| match res {
+                        Ok(_revoked) => Message::Settings(SettingsMessage::GoTo(None)),
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
+            SettingsMessage::Logout => {
+                let mut api = self.api.clone();
+                self.page = None;
+                return Command::perform(async move { api.logout().await }, |_| Message::None);
+            }
             SettingsMessage::OldPasswordChange(old_pwd) => self.old_password = old_pwd,
             SettingsMessage::NewPasswordChange(new_pwd) => self.new_password = new_pwd,
             SettingsMessage::NewPasswordChangeBis(new_pwd_bis) => self.new2_password = new_pwd_bis,
@@ -151,6 +305,75 @@ impl Settings {
                 );
             }
             SettingsMessage::Invites(invites) => self.invites = Some(invites),
+            SettingsMessage::RevokeInvite(token) => {
+                self.invites = None;
+                let mut api = self.api.clone();
+                return Command::perform(async move { api.revoke_invite(token).await }, |res| {
+                    match res {
+                        Ok(()) => Message::Settings(SettingsMessage::GoTo(Some(Page::Invites))),
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    }
+                });
+            }
+            SettingsMessage::Totp(enabled, left) => {
+                self.totp_enabled = Some(enabled);
+                self.recovery_codes_left = left;
+            }
+            SettingsMessage::RecoveryCodes(codes) => {
+                self.totp_enabled = Some(true);
+                self.totp_enrollment = None;
+                self.recovery_codes_left = codes.len() as u32;
+                self.recovery_codes = Some(codes);
+            }
+            SettingsMessage::RegenerateRecoveryCodes => {
+                let mut api = self.api.clone();
+                let code = self.totp_code.clone();
+                self.totp_code.clear();
+                return Command::perform(
+                    async move { api.regenerate_recovery_codes(code).await },
+                    |res| match res {
+                        Ok(codes) => Message::Settings(SettingsMessage::RecoveryCodes(codes)),
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
+            SettingsMessage::TotpEnrolled(secret, uri) => {
+                self.totp_enrollment = Some((secret, uri))
+            }
+            SettingsMessage::TotpCodeChange(code) => self.totp_code = code,
+            SettingsMessage::EnrollTotp => {
+                let mut api = self.api.clone();
+                return Command::perform(async move { api.enroll_totp().await }, |res| match res {
+                    Ok((secret, uri)) => {
+                        Message::Settings(SettingsMessage::TotpEnrolled(secret, uri))
+                    }
+                    Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                });
+            }
+            SettingsMessage::ConfirmTotp => {
+                let mut api = self.api.clone();
+                let code = self.totp_code.clone();
+                self.totp_code.clear();
+                return Command::perform(
+                    async move { api.confirm_totp(code).await },
+                    |res| match res {
+                        Ok(codes) => Message::Settings(SettingsMessage::RecoveryCodes(codes)),
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
+            SettingsMessage::DisableTotp => {
+                let mut api = self.api.clone();
+                let code = self.totp_code.clone();
+                self.totp_code.clear();
+                return Command::perform(
+                    async move { api.disable_totp(code).await },
+                    |res| match res {
+                        Ok(()) => Message::Settings(SettingsMessage::GoTo(Some(Page::Totp))),
+                        Err(e) => Message::Settings(SettingsMessage::Error(format!("{:?}", e))),
+                    },
+                );
+            }
             SettingsMessage::CreateInvite => {
                 self.invites = None;
                 let mut api = self.api.clone();
@@ -189,9 +412,24 @@ impl Settings {
                 .push(button(text("Back")).on_press(SettingsMessage::GoTo(None)))
                 .push(text("Invites"))
                 .into(),
+            Some(Page::Totp) => row()
+                .spacing(10)
+                .push(button(text("Back")).on_press(SettingsMessage::GoTo(None)))
+                .push(text("Two-factor authentication"))
+                .into(),
+            Some(Page::AccessTokens) => row()
+                .spacing(10)
+                .push(button(text("Back")).on_press(SettingsMessage::GoTo(None)))
+                .push(text("Access tokens"))
+                .into(),
+            Some(Page::Admin) => row()
+                .spacing(10)
+                .push(button(text("Back")).on_press(SettingsMessage::GoTo(None)))
+                .push(text("Administration"))
+                .into(),
         };
         let body = match self.page {
-            None => Self::menu(),
+            None => Self::menu(self.api.is_staff()),
             Some(Page::RefreshTokens) => {
                 if let Some(refresh_tokens) = &self.refresh_tokens {
                     Self::show_refresh_tokens(refresh_tokens)
@@ -212,6 +450,26 @@ impl Settings {
                     text("Loading...").into()
                 }
             }
+            Some(Page::Totp) => match self.totp_enabled {
+                Some(enabled) => Self::show_totp(
+                    enabled,
+                    self.recovery_codes_left,
+                    &self.recovery_codes,
+                    &self.totp_enrollment,
+                    &self.totp_code,
+                ),
+                None => text("Loading...").into(),
+            },
+            Some(Page::AccessTokens) => match &self.access_tokens {
+                Some(tokens) => Self::show_access_tokens(
+                    tokens,
+                    &self.new_access_token,
+                    &self.access_token_name,
+                    &self.access_token_scopes,
+                ),
+                None => text("Loading...").into(),
+            },
+            Some(Page::Admin) => self.admin.display().map(SettingsMessage::Admin),
         };
         let content: Element<'_, SettingsMessage> = container(
             column()
@@ -229,8 +487,8 @@ impl Settings {
         content.map(Message::Settings)
     }
 
-    fn menu<'a>() -> Element<'a, SettingsMessage> {
-        column()
+    fn menu<'a>(is_staff: bool) -> Element<'a, SettingsMessage> {
+        let column = column()
             .align_items(Alignment::Center)
             .max_width(600)
             .padding(20)
@@ -244,18 +502,46 @@ impl Settings {
                 button(text("Change password"))
                     .on_press(SettingsMessage::GoTo(Some(Page::Password(false)))),
             )
-            .push(button(text("Logout")))
+            .push(
+                button(text("Two-factor authentication"))
+                    .on_press(SettingsMessage::GoTo(Some(Page::Totp))),
+            )
+            .push(
+                button(text("Access tokens"))
+                    .on_press(SettingsMessage::GoTo(Some(Page::AccessTokens))),
+            );
+        let column = if is_staff {
+            column.push(
+                button(text("Administration")).on_press(SettingsMessage::GoTo(Some(Page::Admin))),
+            )
+        } else {
+            column
+        };
+        column
+            .push(button(text("Logout")).on_press(SettingsMessage::Logout))
             .into()
     }
 
     fn show_refresh_tokens<'a>(tokens: &'a Vec<RefreshToken>) -> Element<'a, SettingsMessage> {
-        let mut columns = column().spacing(20);
+        let mut columns = column()
+            .spacing(20)
+            .push(button(text("Log out everywhere")).on_press(SettingsMessage::RevokeAllSessions));
         for token in tokens.iter() {
             let first_line = row()
                 .push(text(&token.token).size(30))
                 .push(text(Utc.timestamp(token.last_use as i64, 0).to_string()).size(22));
+            let device = if token.device_name.is_empty() {
+                &token.user_agent
+            } else {
+                &token.device_name
+            };
+            let from = if token.compromised {
+                format!("{} from {} (compromised)", device, token.from)
+            } else {
+                format!("{} from {}", device, token.from)
+            };
             let second_line = row()
-                .push(text(&token.from).size(24))
+                .push(text(from).size(24))
                 .push(text(Utc.timestamp(token.creation_date as i64, 0).to_string()).size(22));
             columns = columns.push(
                 container(
@@ -288,12 +574,36 @@ impl Settings {
             .spacing(20)
             .push(button(text("Create invite")).on_press(SettingsMessage::CreateInvite));
         for invite in invites.iter() {
+            let status = if invite.used {
+                format!("used by {}", invite.used_by.join(", "))
+            } else if invite.expiration_date != 0 {
+                format!(
+                    "{}/{} uses, expire {}",
+                    invite.used_by.len(),
+                    invite.max_uses,
+                    Utc.timestamp(invite.expiration_date as i64, 0)
+                )
+            } else {
+                format!("{}/{} uses", invite.used_by.len(), invite.max_uses)
+            };
             columns = columns.push(
-                container(row().push(text(&invite.token)).push(text(if invite.used {
-                    "used"
-                } else {
-                    "not used"
-                })))
+                container(
+                    row()
+                        .align_items(Alignment::Center)
+                        .push(
+                            column()
+                                .width(Length::Fill)
+                                .push(row().push(text(&invite.token)).push(text(&invite.note)))
+                                .push(text(status)),
+                        )
+                        .push(
+                            button(text("D"))
+                                .width(Length::Shrink)
+                                .padding(10)
+                                .style(TokenRow {})
+                                .on_press(SettingsMessage::RevokeInvite(invite.token.clone())),
+                        ),
+                )
                 .width(Length::Fill)
                 .padding(20)
                 .style(TokenRow {}),
@@ -302,6 +612,142 @@ impl Settings {
         columns.into()
     }
 
+    fn show_totp<'a>(
+        enabled: bool,
+        recovery_codes_left: u32,
+        recovery_codes: &'a Option<Vec<String>>,
+        enrollment: &'a Option<(String, String)>,
+        code: &str,
+    ) -> Element<'a, SettingsMessage> {
+        let code_input = text_input("Authenticator code", code, SettingsMessage::TotpCodeChange)
+            .padding(10)
+            .size(32);
+        let mut column = column()
+            .align_items(Alignment::Center)
+            .max_width(600)
+            .padding(20)
+            .spacing(16);
+        if let Some(codes) = recovery_codes {
+            column = column.push(text(
+                "Recovery codes, each one replaces a code once. Save them, they are only shown now",
+            ));
+            for code in codes.iter() {
+                column = column.push(text(code));
+            }
+        }
+        match (enabled, enrollment) {
+            (true, _) => column
+                .push(text("Enabled, a code is asked at login"))
+                .push(text(format!("{} recovery codes left", recovery_codes_left)))
+                .push(code_input)
+                .push(
+                    row()
+                        .spacing(10)
+                        .push(
+                            button(text("New recovery codes"))
+                                .on_press(SettingsMessage::RegenerateRecoveryCodes),
+                        )
+                        .push(button(text("Disable")).on_press(SettingsMessage::DisableTotp)),
+                )
+                .into(),
+            (false, Some((secret, uri))) => column
+                .push(text("Scan with an authenticator app, or enter the secret"))
+                .push(Self::qr_code(uri))
+                .push(text(secret))
+                .push(code_input)
+                .push(button(text("Confirm")).on_press(SettingsMessage::ConfirmTotp))
+                .into(),
+            (false, None) => column
+                .push(text("Disabled"))
+                .push(button(text("Enable")).on_press(SettingsMessage::EnrollTotp))
+                .into(),
+        }
+    }
+
+    fn show_access_tokens<'a>(
+        tokens: &'a [PersonalAccessToken],
+        new_token: &'a Option<String>,
+        name: &str,
+        scopes: &[String],
+    ) -> Element<'a, SettingsMessage> {
+        let mut columns = column().spacing(20);
+        if let Some(secret) = new_token {
+            columns = columns
+                .push(text("New token, save it, it is only shown now"))
+                .push(text(secret));
+        }
+        let mut form = column().spacing(10).push(
+            text_input("Token name", name, SettingsMessage::AccessTokenNameChange).padding(10),
+        );
+        for scope in ACCESS_TOKEN_SCOPES {
+            let checked = scopes.iter().any(|s| s == scope);
+            form = form.push(checkbox(scope, checked, move |checked| {
+                SettingsMessage::AccessTokenScope(scope.to_string(), checked)
+            }));
+        }
+        columns = columns.push(
+            form.push(button(text("Create token")).on_press(SettingsMessage::CreateAccessToken)),
+        );
+        for token in tokens.iter() {
+            let last_use = match token.last_use {
+                0 => "never used".to_string(),
+                date => format!("last used {}", Utc.timestamp(date as i64, 0)),
+            };
+            columns = columns.push(
+                container(
+                    row()
+                        .align_items(Alignment::Center)
+                        .push(
+                            column()
+                                .width(Length::Fill)
+                                .push(text(&token.name).size(30))
+                                .push(text(token.scopes.join(", ")))
+                                .push(text(last_use)),
+                        )
+                        .push(
+                            button(text("D"))
+                                .width(Length::Shrink)
+                                .padding(10)
+                                .style(TokenRow {})
+                                .on_press(SettingsMessage::RevokeAccessToken(token.id.clone())),
+                        ),
+                )
+                .width(Length::Fill)
+                .padding(20)
+                .style(TokenRow {}),
+            );
+        }
+        columns.into()
+    }
+
+    // One square container per module, with the 4 modules quiet zone
+    fn qr_code<'a>(data: &str) -> Element<'a, SettingsMessage> {
+        let code = match qrcode::QrCode::new(data.as_bytes()) {
+            Ok(code) => code,
+            Err(e) => return text(format!("Cannot create the QR code: {}", e)).into(),
+        };
+        let width = code.width();
+        let colors = code.to_colors();
+        let mut lines = column();
+        for y in 0..width {
+            let mut line = row();
+            for x in 0..width {
+                let dark = colors[y * width + x] == qrcode::Color::Dark;
+                line = line.push(
+                    container(row())
+                        .width(Length::Units(QR_MODULE_SIZE))
+                        .height(Length::Units(QR_MODULE_SIZE))
+                        .style(QrModule(dark)),
+                );
+            }
+            lines = lines.push(line);
+        }
+        container(lines)
+            .padding(QR_MODULE_SIZE * 4)
+            .style(QrModule(false))
+            .into()
+    }
+
     fn change_password<'a>(
         old_password: &str,
         new_password: &str,
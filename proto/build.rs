@@ -1,4 +1,8 @@
-const SCHEMAS: [&str; 2] = ["schemas/auth.proto", "schemas/user.proto"];
+const SCHEMAS: [&str; 3] = [
+    "schemas/auth.proto",
+    "schemas/user.proto",
+    "schemas/admin.proto",
+];
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("cargo:rerun-if-changed=schemas");
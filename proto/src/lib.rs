@@ -7,6 +7,8 @@ pub mod server {
     pub mod auth;
     #[path = "grpc.user.rs"]
     pub mod user;
+    #[path = "grpc.admin.rs"]
+    pub mod admin;
 }
 
 #[cfg(feature = "client")]
@@ -15,4 +17,6 @@ pub mod client {
     pub mod auth;
     #[path = "grpc.user.rs"]
     pub mod user;
+    #[path = "grpc.admin.rs"]
+    pub mod admin;
 }
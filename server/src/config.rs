@@ -0,0 +1,538 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tonic::codegen::http::HeaderValue;
+
+/*
+    Priority: cli flags > ANAPP_* env > toml file > defaults
+*/
+
+#[derive(Parser, Debug)]
+#[command(about = "AnApp server")]
+struct Cli {
+    /// Path of the toml configuration file
+    #[arg(short, long, env = "ANAPP_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, env = "ANAPP_BIND")]
+    bind: Option<SocketAddr>,
+    #[arg(long, env = "ANAPP_DB_PATH")]
+    db_path: Option<PathBuf>,
+    #[arg(long, env = "ANAPP_JWT_SECRET", hide_env_values = true)]
+    jwt_secret: Option<String>,
+    #[arg(long, env = "ANAPP_SMTP_PASSWORD", hide_env_values = true)]
+    smtp_password: Option<String>,
+    /// kid of the jwt.keys entry used to sign access tokens
+    #[arg(long, env = "ANAPP_JWT_SIGNING_KEY")]
+    jwt_signing_key: Option<String>,
+    /// Access token lifetime in seconds
+    #[arg(long, env = "ANAPP_ACCESS_TOKEN_DURATION")]
+    access_token_duration: Option<u32>,
+    /// Refresh token lifetime in seconds
+    #[arg(long, env = "ANAPP_REFRESH_TOKEN_DURATION")]
+    refresh_token_duration: Option<u32>,
+    /// Revoke refresh tokens unused for this long in seconds, 0 -> never
+    #[arg(long, env = "ANAPP_REFRESH_TOKEN_IDLE_TIMEOUT")]
+    refresh_token_idle_timeout: Option<u32>,
+    /// Seconds between two purges of the expired refresh tokens
+    #[arg(long, env = "ANAPP_REFRESH_TOKEN_SWEEP_INTERVAL")]
+    refresh_token_sweep_interval: Option<u32>,
+    #[arg(long, env = "ANAPP_ARGON2_VARIANT")]
+    argon2_variant: Option<String>,
+    /// Argon2 memory cost in KiB
+    #[arg(long, env = "ANAPP_ARGON2_MEM_COST")]
+    argon2_mem_cost: Option<u32>,
+    /// Argon2 iterations
+    #[arg(long, env = "ANAPP_ARGON2_TIME_COST")]
+    argon2_time_cost: Option<u32>,
+    #[arg(long, env = "ANAPP_ARGON2_LANES")]
+    argon2_lanes: Option<u32>,
+    /// Bootstrap admin, can signup without invite and always has the admin role
+    #[arg(long, env = "ANAPP_ADMIN")]
+    admin: Option<String>,
+    /// Comma separated, empty -> allow all origins
+    #[arg(long, env = "ANAPP_ALLOWED_ORIGINS", value_delimiter = ',')]
+    allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct JwtKeyConfig {
+    pub kid: String,
+    pub algorithm: String, /* EdDSA or RS256 */
+    pub public_key: PathBuf,
+    pub private_key: Option<PathBuf>, /* Only needed for the signing key */
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub secret: String, /* Used when there is no signing_key */
+    pub signing_key: Option<String>,
+    pub keys: Vec<JwtKeyConfig>,
+    pub access_token_duration: u32,        /* seconds */
+    pub refresh_token_duration: u32,       /* seconds */
+    pub refresh_token_idle_timeout: u32,   /* seconds, 0 -> never */
+    pub refresh_token_sweep_interval: u32, /* seconds */
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            secret: "super secret".to_string(),
+            signing_key: None,
+            keys: Vec::new(),
+            access_token_duration: 60 * 10,            /* 10 minutes */
+            refresh_token_duration: 60 * 60 * 24 * 30, /* 1 month */
+            refresh_token_idle_timeout: 60 * 60 * 24 * 7, /* 1 week */
+            refresh_token_sweep_interval: 60 * 60,     /* 1 hour */
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Config {
+    pub variant: String,
+    pub mem_cost: u32, /* KiB */
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        let default = argon2::Config::default();
+        Self {
+            variant: default.variant.as_lowercase_str().to_string(),
+            mem_cost: default.mem_cost,
+            time_cost: default.time_cost,
+            lanes: default.lanes,
+        }
+    }
+}
+
+impl Argon2Config {
+    pub fn to_argon2(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            // Checked in Config::validate
+            variant: argon2::Variant::from_str(&self.variant).unwrap_or_default(),
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            ..argon2::Config::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    pub window: u32, /* seconds */
+    pub max_user_attempts: u32,
+    pub max_ip_attempts: u32,
+    pub lockout: u32,        /* seconds, first lockout */
+    pub max_lockout: u32,    /* seconds */
+    pub sweep_interval: u32, /* seconds */
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            window: 60 * 15, /* 15 minutes */
+            max_user_attempts: 5,
+            max_ip_attempts: 20,
+            lockout: 60,               /* 1 minute */
+            max_lockout: 60 * 60 * 24, /* 1 day */
+            sweep_interval: 60 * 60,   /* 1 hour */
+        }
+    }
+}
+
+/*
+    Applications allowed on the /oauth2 endpoints, with HTTP Basic or client_id/client_secret.
+    With redirect_uris they can sign users in (OpenID Connect), without secret they
+    are public clients: only the authorization code flow, always with PKCE.
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OAuthClientConfig {
+    pub id: String,
+    #[serde(default)]
+    pub secret: String, /* empty -> public client */
+    #[serde(default)]
+    pub name: String, /* shown on the login page, empty -> id */
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    pub issuer: String, /* public url of the server, empty -> http://[bind] */
+    pub clients: Vec<OAuthClientConfig>,
+}
+
+// OpenID Connect providers users can sign in with, see federation.rs
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FederationProviderConfig {
+    pub id: String, /* kept in the links, not to be changed */
+    #[serde(default)]
+    pub name: String, /* shown on the login button, empty -> id */
+    pub issuer: String, /* https, http only on the loopback */
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String, /* empty -> public client, PKCE only */
+    pub redirect_uri: String, /* handled by the app, sent to Auth.FederatedLogin */
+    #[serde(default)]
+    pub signup: bool, /* create a user for an identity linked to none */
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    pub providers: Vec<FederationProviderConfig>,
+}
+
+/*
+    Where the verification and password reset mails go, see mailer.rs:
+    "log" prints them, "file" appends them to file as json lines, "smtp" sends
+    them to a relay. smtp_tls: "starttls" upgrades the connection, "tls" is TLS
+    from the start (port 465), "none" is plain text and cannot carry the
+    credentials.
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub backend: String,
+    pub from: String,
+    pub file: PathBuf,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_tls: String,
+    pub smtp_username: String, /* empty -> no AUTH */
+    pub smtp_password: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            backend: "log".to_string(),
+            from: "AnApp <noreply@localhost>".to_string(),
+            file: PathBuf::from("mails.jsonl"),
+            smtp_host: "127.0.0.1".to_string(),
+            smtp_port: 25,
+            smtp_tls: "starttls".to_string(),
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+        }
+    }
+}
+
+/*
+    Where the users, refresh tokens and invites are stored, see storage:
+    "sled" in db_path with the rest, "sqlite" in their own file. A new SQLite
+    file starts with the content of the sled backend, nothing is copied back.
+*/
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: String,
+    pub sqlite_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: "sled".to_string(),
+            sqlite_path: PathBuf::from("my_db.sqlite"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub db_path: PathBuf,
+    pub storage: StorageConfig,
+    pub jwt: JwtConfig,
+    pub argon2: Argon2Config,
+    pub throttle: ThrottleConfig,
+    pub oauth: OAuthConfig,
+    pub federation: FederationConfig,
+    pub mail: MailConfig,
+    pub admin: String,
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:5051".parse().unwrap(),
+            db_path: PathBuf::from("my_db"),
+            storage: StorageConfig::default(),
+            jwt: JwtConfig::default(),
+            argon2: Argon2Config::default(),
+            throttle: ThrottleConfig::default(),
+            oauth: OAuthConfig::default(),
+            federation: FederationConfig::default(),
+            mail: MailConfig::default(),
+            admin: "tet".to_string(),
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let cli = Cli::parse();
+        let mut config = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("invalid config {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+        config.merge(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn issuer(&self) -> String {
+        match self.oauth.issuer.as_str() {
+            "" => format!("http://{}", self.bind),
+            issuer => issuer.to_string(),
+        }
+    }
+
+    fn merge(&mut self, cli: Cli) {
+        if let Some(bind) = cli.bind {
+            self.bind = bind;
+        }
+        if let Some(db_path) = cli.db_path {
+            self.db_path = db_path;
+        }
+        if let Some(secret) = cli.jwt_secret {
+            self.jwt.secret = secret;
+        }
+        if let Some(password) = cli.smtp_password {
+            self.mail.smtp_password = password;
+        }
+        if let Some(kid) = cli.jwt_signing_key {
+            self.jwt.signing_key = Some(kid);
+        }
+        if let Some(duration) = cli.access_token_duration {
+            self.jwt.access_token_duration = duration;
+        }
+        if let Some(duration) = cli.refresh_token_duration {
+            self.jwt.refresh_token_duration = duration;
+        }
+        if let Some(timeout) = cli.refresh_token_idle_timeout {
+            self.jwt.refresh_token_idle_timeout = timeout;
+        }
+        if let Some(interval) = cli.refresh_token_sweep_interval {
+            self.jwt.refresh_token_sweep_interval = interval;
+        }
+        if let Some(variant) = cli.argon2_variant {
+            self.argon2.variant = variant;
+        }
+        if let Some(mem_cost) = cli.argon2_mem_cost {
+            self.argon2.mem_cost = mem_cost;
+        }
+        if let Some(time_cost) = cli.argon2_time_cost {
+            self.argon2.time_cost = time_cost;
+        }
+        if let Some(lanes) = cli.argon2_lanes {
+            self.argon2.lanes = lanes;
+        }
+        if let Some(admin) = cli.admin {
+            self.admin = admin;
+        }
+        if let Some(origins) = cli.allowed_origins {
+            self.allowed_origins = origins.into_iter().filter(|o| !o.is_empty()).collect();
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.jwt.secret.is_empty() {
+            return Err("jwt.secret cannot be empty".to_string());
+        }
+        for (i, key) in self.jwt.keys.iter().enumerate() {
+            if key.kid.is_empty() {
+                return Err("jwt.keys: kid cannot be empty".to_string());
+            }
+            if self.jwt.keys[..i].iter().any(|k| k.kid == key.kid) {
+                return Err(format!("jwt.keys: duplicate kid \"{}\"", key.kid));
+            }
+            crate::jwt::parse_algorithm(&key.algorithm)
+                .map_err(|e| format!("jwt.keys \"{}\": {}", key.kid, e))?;
+        }
+        if let Some(kid) = &self.jwt.signing_key {
+            match self.jwt.keys.iter().find(|k| &k.kid == kid) {
+                Some(key) if key.private_key.is_some() => {}
+                Some(_) => {
+                    return Err(format!(
+                        "jwt.signing_key: key \"{}\" has no private_key",
+                        kid
+                    ))
+                }
+                None => return Err(format!("jwt.signing_key: unknown key \"{}\"", kid)),
+            }
+        }
+        if self.jwt.access_token_duration == 0 {
+            return Err("jwt.access_token_duration must be positive".to_string());
+        }
+        if self.jwt.refresh_token_duration < self.jwt.access_token_duration {
+            return Err(
+                "jwt.refresh_token_duration must be greater than jwt.access_token_duration"
+                    .to_string(),
+            );
+        }
+        if self.jwt.refresh_token_sweep_interval == 0 {
+            return Err("jwt.refresh_token_sweep_interval must be positive".to_string());
+        }
+        if argon2::Variant::from_str(&self.argon2.variant).is_err() {
+            return Err(format!(
+                "argon2.variant \"{}\" should be one of argon2d, argon2i, argon2id",
+                self.argon2.variant
+            ));
+        }
+        if self.argon2.lanes == 0 {
+            return Err("argon2.lanes must be positive".to_string());
+        }
+        if self.argon2.time_cost == 0 {
+            return Err("argon2.time_cost must be positive".to_string());
+        }
+        if self.argon2.mem_cost < 8 * self.argon2.lanes {
+            return Err("argon2.mem_cost must be at least 8 * argon2.lanes".to_string());
+        }
+        if self.throttle.window == 0 || self.throttle.sweep_interval == 0 {
+            return Err("throttle.window and throttle.sweep_interval must be positive".to_string());
+        }
+        if self.throttle.max_user_attempts == 0 || self.throttle.max_ip_attempts == 0 {
+            return Err(
+                "throttle.max_user_attempts and throttle.max_ip_attempts must be positive"
+                    .to_string(),
+            );
+        }
+        if self.throttle.lockout == 0 || self.throttle.max_lockout < self.throttle.lockout {
+            return Err(
+                "throttle.lockout must be positive and not greater than throttle.max_lockout"
+                    .to_string(),
+            );
+        }
+        for (i, client) in self.oauth.clients.iter().enumerate() {
+            if client.id.is_empty() || client.id.contains(':') {
+                return Err("oauth.clients: id cannot be empty or contain ':'".to_string());
+            }
+            if self.oauth.clients[..i].iter().any(|c| c.id == client.id) {
+                return Err(format!("oauth.clients: duplicate id \"{}\"", client.id));
+            }
+            if client.secret.is_empty() && client.redirect_uris.is_empty() {
+                return Err(format!(
+                    "oauth.clients \"{}\": a public client needs redirect_uris",
+                    client.id
+                ));
+            }
+            if !client.secret.is_empty() && client.secret.len() < 16 {
+                return Err(format!(
+                    "oauth.clients \"{}\": secret must be at least 16 characters",
+                    client.id
+                ));
+            }
+            // Relying parties, public clients included, check the ID tokens
+            // with the published keys, never with jwt.secret
+            if !client.redirect_uris.is_empty() && self.jwt.signing_key.is_none() {
+                return Err(format!(
+                    "oauth.clients \"{}\": ID tokens need an EdDSA or RS256 jwt.signing_key",
+                    client.id
+                ));
+            }
+            for uri in client.redirect_uris.iter() {
+                if !(uri.starts_with("http://") || uri.starts_with("https://")) || uri.contains('#')
+                {
+                    return Err(format!(
+                        "oauth.clients \"{}\": invalid redirect uri \"{}\"",
+                        client.id, uri
+                    ));
+                }
+            }
+        }
+        let issuer = &self.oauth.issuer;
+        if !issuer.is_empty()
+            && (!(issuer.starts_with("http://") || issuer.starts_with("https://"))
+                || issuer.ends_with('/')
+                || issuer.contains(['?', '#']))
+        {
+            return Err(format!(
+                "oauth.issuer: invalid url \"{}\", no trailing '/', query or fragment",
+                issuer
+            ));
+        }
+        let providers = &self.federation.providers;
+        for (i, provider) in providers.iter().enumerate() {
+            if provider.id.is_empty() || provider.id.contains(':') {
+                return Err("federation.providers: id cannot be empty or contain ':'".to_string());
+            }
+            if providers[..i].iter().any(|p| p.id == provider.id) {
+                return Err(format!(
+                    "federation.providers: duplicate id \"{}\"",
+                    provider.id
+                ));
+            }
+            if !crate::federation::is_secure_url(&provider.issuer) {
+                return Err(format!(
+                    "federation.providers \"{}\": issuer must be an https:// url, http:// is only for the loopback",
+                    provider.id
+                ));
+            }
+            if provider.client_id.is_empty() || provider.redirect_uri.is_empty() {
+                return Err(format!(
+                    "federation.providers \"{}\": client_id and redirect_uri are required",
+                    provider.id
+                ));
+            }
+        }
+        if !["sled", "sqlite"].contains(&self.storage.backend.as_str()) {
+            return Err(format!(
+                "storage.backend \"{}\" should be one of sled, sqlite",
+                self.storage.backend
+            ));
+        }
+        if !["log", "file", "smtp"].contains(&self.mail.backend.as_str()) {
+            return Err(format!(
+                "mail.backend \"{}\" should be one of log, file, smtp",
+                self.mail.backend
+            ));
+        }
+        if !["starttls", "tls", "none"].contains(&self.mail.smtp_tls.as_str()) {
+            return Err(format!(
+                "mail.smtp_tls \"{}\" should be one of starttls, tls, none",
+                self.mail.smtp_tls
+            ));
+        }
+        if self.mail.smtp_tls == "none" && !self.mail.smtp_username.is_empty() {
+            return Err("mail.smtp_username: the credentials are not sent without TLS".to_string());
+        }
+        if !crate::mailer::valid_mailbox(&self.mail.from) {
+            return Err(format!("mail.from: invalid address \"{}\"", self.mail.from));
+        }
+        // Invite keys are "username:random" on 16 bytes
+        if self.admin.len() < 3 || self.admin.len() >= 10 || self.admin.contains(':') {
+            return Err(
+                "admin must be between 3 and 9 characters and cannot contain ':'".to_string(),
+            );
+        }
+        for origin in self.allowed_origins.iter() {
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || HeaderValue::from_str(origin).is_err()
+            {
+                return Err(format!("allowed_origins: invalid origin \"{}\"", origin));
+            }
+        }
+        Ok(())
+    }
+}
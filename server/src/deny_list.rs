@@ -0,0 +1,89 @@
+use crate::get_now_plus;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// key : "[jti]" or "[session id]"
+// value : expiration timestamp, u32 big endian
+
+/*
+    Access tokens are checked without the database, a revoked one stay valid
+    until its expiration unless it is denied here. An entry only has to outlive
+    the tokens it denies: the access token duration.
+    The tree is loaded at startup and every check is done on the memory copy.
+*/
+
+#[derive(Clone)]
+pub struct DenyList {
+    db: sled::Tree,
+    cache: Arc<RwLock<HashMap<String, u32>>>,
+    ttl: u32, /* seconds */
+}
+
+fn decode_date(value: &[u8]) -> u32 {
+    value.try_into().map(u32::from_be_bytes).unwrap_or(0)
+}
+
+impl DenyList {
+    pub fn new(db: sled::Tree, ttl: u32) -> Self {
+        let now = get_now_plus(0) as u32;
+        let cache = db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, value)| {
+                (
+                    String::from_utf8_lossy(&key).to_string(),
+                    decode_date(&value),
+                )
+            })
+            .filter(|(_, until)| *until > now)
+            .collect();
+        Self {
+            db,
+            cache: Arc::new(RwLock::new(cache)),
+            ttl,
+        }
+    }
+
+    // Deny every access token with this jti or session id
+    pub fn deny(&self, id: &str) {
+        let until = get_now_plus(self.ttl) as u32;
+        let _res = self.db.insert(id, &until.to_be_bytes());
+        self.cache.write().unwrap().insert(id.to_string(), until);
+    }
+
+    pub fn is_denied(&self, id: &str) -> bool {
+        if id.is_empty() {
+            return false;
+        }
+        match self.cache.read().unwrap().get(id) {
+            Some(until) => *until > get_now_plus(0) as u32,
+            None => false,
+        }
+    }
+
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        self.cache.write().unwrap().retain(|_, until| *until > now);
+        let expired: Vec<sled::IVec> = self
+            .db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| decode_date(value) <= now)
+            .map(|(key, _)| key)
+            .collect();
+        expired
+            .into_iter()
+            .filter(|key| matches!(self.db.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.ttl.max(60) as u64));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use crate::get_now_plus;
+use crate::mailer::Mail;
+use crate::users::Users;
+use proto::prost::Message;
+use proto::server::user::{email_token::Purpose, EmailToken, UserEmail};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::iter;
+use std::time::Duration;
+
+// The address is the email of the UserRecord
+// addresses tree: "[lowercase email]" -> username, verified addresses only
+// tokens tree: "[SHA-256 of the token]" -> EmailToken protobuf
+// sent tree: "[username]:[purpose]" -> u32 big endian, date of the last mail
+
+/*
+    A user sets an address, a token is mailed to it and sent back to
+    Auth.VerifyEmail. A verified address belongs to a single user, it is the
+    one RequestPasswordReset mails a reset token to.
+    Tokens are single use and only stored hashed, like the recovery codes.
+
+    The addresses tree indexes the records, an entry the record of its user
+    does not confirm (left by an interrupted change) is ignored and replaced.
+*/
+
+const VERIFY_DURATION: u32 = 60 * 60 * 24; /* seconds */
+const RESET_DURATION: u32 = 60 * 60; /* seconds */
+const RESEND_DELAY: u32 = 60; /* seconds, between two mails of a kind to a user */
+const TOKEN_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct Emails {
+    users: Users,
+    addresses: sled::Tree,
+    tokens: sled::Tree,
+    sent: sled::Tree,
+}
+
+fn token_key(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn sent_key(username: &str, purpose: Purpose) -> String {
+    format!("{}:{}", username, purpose as i32)
+}
+
+fn decode_date(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.try_into().ok()?))
+}
+
+fn normalize(email: &str) -> String {
+    email.to_lowercase()
+}
+
+pub fn verification_mail(to: &str, username: &str, token: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Hello {},\n\n\
+            Enter this code in the application to verify your email address:\n\n\
+            {}\n\n\
+            It expires in 24 hours. If you did not ask for it, ignore this mail.\n",
+            username, token
+        ),
+    }
+}
+
+pub fn reset_mail(to: &str, username: &str, token: &str) -> Mail {
+    Mail {
+        to: to.to_string(),
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hello {},\n\n\
+            Enter this code in the application to choose a new password:\n\n\
+            {}\n\n\
+            It expires in 1 hour. If you did not ask for it, ignore this mail,\n\
+            your password stays the same.\n",
+            username, token
+        ),
+    }
+}
+
+impl Emails {
+    pub fn new(users: Users, addresses: sled::Tree, tokens: sled::Tree, sent: sled::Tree) -> Self {
+        Self {
+            users,
+            addresses,
+            tokens,
+            sent,
+        }
+    }
+
+    pub fn get(&self, username: &str) -> Option<UserEmail> {
+        self.users.get(username).and_then(|record| record.email)
+    }
+
+    // The record of the user has the address, verified
+    fn owns(&self, username: &str, address: &str) -> bool {
+        matches!(self.get(username),
+            Some(email) if email.verified && normalize(&email.email) == address)
+    }
+
+    // Owner of a verified address
+    pub fn find_verified(&self, email: &str) -> Option<String> {
+        let address = normalize(email);
+        match self.addresses.get(&address) {
+            Ok(Some(username)) => Some(String::from_utf8_lossy(&username).to_string())
+                .filter(|username| self.owns(username, &address)),
+            _ => None,
+        }
+    }
+
+    // Replace the address of the user, unverified. Return false if it is
+    // already the verified one.
+    pub fn set(&self, username: &str, email: &str) -> Result<bool, String> {
+        let previous = RefCell::new(None);
+        let changed = Cell::new(false);
+        let found = self.users.update(username, &|record| {
+            let current = record.email.take();
+            let same = matches!(&current,
+                Some(current) if current.verified && normalize(&current.email) == normalize(email));
+            changed.set(!same);
+            record.email = match same {
+                true => current.clone(),
+                false => Some(UserEmail {
+                    email: email.to_string(),
+                    verified: false,
+                    verified_date: 0,
+                }),
+            };
+            *previous.borrow_mut() = current;
+        })?;
+        if !found {
+            return Err("Unknown user".to_string());
+        }
+        match previous.into_inner() {
+            // Unless another user verified it since
+            Some(previous) if changed.get() && previous.verified => {
+                let _swapped = self
+                    .addresses
+                    .compare_and_swap(
+                        normalize(&previous.email),
+                        Some(username.as_bytes()),
+                        None as Option<&[u8]>,
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
+            _ => {}
+        }
+        Ok(changed.get())
+    }
+
+    // None if one of this kind was sent less than RESEND_DELAY ago
+    pub fn new_token(
+        &self,
+        purpose: Purpose,
+        username: &str,
+        email: &str,
+    ) -> Result<Option<String>, String> {
+        let now = get_now_plus(0) as u32;
+        let mut recent = false;
+        self.sent
+            .fetch_and_update(sent_key(username, purpose), |last| {
+                recent = matches!(last.and_then(decode_date),
+                    Some(date) if date.saturating_add(RESEND_DELAY) > now);
+                match (recent, last) {
+                    (true, Some(last)) => Some(last.to_vec()),
+                    _ => Some(now.to_be_bytes().to_vec()),
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        if recent {
+            return Ok(None);
+        }
+        let mut rng = thread_rng();
+        let token: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(TOKEN_LEN)
+            .collect();
+        let duration = match purpose {
+            Purpose::VerifyEmail => VERIFY_DURATION,
+            Purpose::ResetPassword => RESET_DURATION,
+        };
+        let stored = EmailToken {
+            purpose: purpose as i32,
+            username: username.to_string(),
+            email: email.to_string(),
+            creation_date: now,
+            expiration_date: get_now_plus(duration) as u32,
+        };
+        self.tokens
+            .insert(token_key(&token), stored.encode_to_vec())
+            .map_err(|e| e.to_string())?;
+        Ok(Some(token))
+    }
+
+    // Single use: removed whatever the outcome
+    fn take_token(&self, token: &str, purpose: Purpose) -> Option<EmailToken> {
+        let stored = match self.tokens.remove(token_key(token)) {
+            Ok(Some(value)) => EmailToken::decode(value.as_ref()).ok()?,
+            _ => return None,
+        };
+        if stored.purpose != purpose as i32 || stored.expiration_date < get_now_plus(0) as u32 {
+            return None;
+        }
+        Some(stored)
+    }
+
+    fn remove_tokens(&self, username: &str) {
+        let keys: Vec<sled::IVec> = self
+            .tokens
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                matches!(EmailToken::decode(value.as_ref()), Ok(token) if token.username == username)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            let _res = self.tokens.remove(key);
+        }
+    }
+
+    // Return the username and the address now verified
+    pub fn verify(&self, token: &str) -> Result<(String, String), String> {
+        let invalid = || "Invalid or expired token".to_string();
+        let taken = || "Email already used by another user".to_string();
+        let token = self
+            .take_token(token, Purpose::VerifyEmail)
+            .ok_or_else(invalid)?;
+        let username = token.username.as_bytes();
+        let address = normalize(&token.email);
+        // Claim the address before the record says verified
+        let owner = self.addresses.get(&address).map_err(|e| e.to_string())?;
+        if let Some(owner) = &owner {
+            if owner != username && self.owns(&String::from_utf8_lossy(owner), &address) {
+                return Err(taken());
+            }
+        }
+        self.addresses
+            .compare_and_swap(&address, owner.as_ref(), Some(username))
+            .map_err(|e| e.to_string())?
+            .map_err(|_| taken())?;
+        let verified = Cell::new(false);
+        let now = get_now_plus(0) as u32;
+        self.users.update(&token.username, &|record| {
+            // The address changed since the mail was sent
+            let current = record
+                .email
+                .as_mut()
+                .filter(|current| current.email == token.email);
+            verified.set(current.is_some());
+            if let Some(current) = current.filter(|current| !current.verified) {
+                current.verified = true;
+                current.verified_date = now;
+            }
+        })?;
+        if !verified.get() {
+            let _res =
+                self.addresses
+                    .compare_and_swap(&address, Some(username), None as Option<&[u8]>);
+            return Err(invalid());
+        }
+        Ok((token.username, token.email))
+    }
+
+    // Username of a valid reset token, the other tokens of the user are dropped
+    pub fn take_reset_token(&self, token: &str) -> Option<String> {
+        let token = self.take_token(token, Purpose::ResetPassword)?;
+        // Sent to an address the user has since changed
+        if self.find_verified(&token.email).as_deref() != Some(token.username.as_str()) {
+            return None;
+        }
+        self.remove_tokens(&token.username);
+        Some(token.username)
+    }
+
+    // Before the record is removed
+    pub fn remove(&self, username: &str) -> Result<(), String> {
+        if let Some(email) = self.get(username).filter(|email| email.verified) {
+            self.addresses
+                .remove(normalize(&email.email))
+                .map_err(|e| e.to_string())?;
+        }
+        self.remove_tokens(username);
+        for purpose in [Purpose::VerifyEmail, Purpose::ResetPassword] {
+            self.sent
+                .remove(sent_key(username, purpose))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let expired: Vec<sled::IVec> = self
+            .tokens
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                EmailToken::decode(value.as_ref()).map_or(true, |token| token.expiration_date < now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        let old_sends: Vec<sled::IVec> = self
+            .sent
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                !matches!(decode_date(value), Some(date) if date.saturating_add(RESEND_DELAY) > now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in old_sends {
+            let _res = self.sent.remove(key);
+        }
+        expired
+            .into_iter()
+            .filter(|key| matches!(self.tokens.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(RESET_DURATION as u64));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
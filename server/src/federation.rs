@@ -0,0 +1,579 @@
+use crate::config::FederationProviderConfig;
+use crate::get_now_plus;
+use crate::storage::CreateError;
+use crate::users::Users;
+use hyper::client::HttpConnector;
+use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use proto::prost::Message;
+use proto::server::auth::FederationProvider;
+use proto::server::user::{FederatedIdentity, FederatedLoginState, UserRecord};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::iter;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tonic::{Code, Status};
+
+// links tree: "[provider]:[subject]" -> FederatedIdentity protobuf
+// states tree: "[state]" -> FederatedLoginState protobuf
+
+/*
+    Login with an OpenID Connect provider, authorization code flow with PKCE:
+    - start: a state, a nonce and a PKCE verifier are stored, the app opens the
+      provider's authorization url in a browser
+    - finish: the app sends the code and state given to its redirect_uri, the
+      code is exchanged with the provider and its ID token checked against the
+      provider's JWKS: signature, iss, aud, exp and nonce
+    The identity (provider, sub) is then linked to a local user, see Auth.FederatedLogin.
+    Discovery documents and JWKS are cached, the JWKS is fetched again for an unknown kid.
+    The provider is reached over https, checked against the webpki roots. Plain
+    http is only for a provider on the loopback, a local relay or a test.
+*/
+
+const STATE_DURATION: u32 = 60 * 10; /* seconds */
+const METADATA_TTL: u32 = 60 * 60; /* seconds */
+const JWKS_MIN_REFRESH: u32 = 60; /* seconds, on an unknown kid */
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_USERNAME_LEN: usize = 9; /* bytes, invite keys are "username:random" on 16 */
+const ALGORITHMS: [Algorithm; 8] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::EdDSA,
+];
+
+pub enum FederationError {
+    Invalid(String),     // Bad request, state or ID token
+    Unavailable(String), // The provider cannot be reached or answered nonsense
+}
+
+impl FederationError {
+    pub fn into_status(self) -> Status {
+        match self {
+            FederationError::Invalid(e) => Status::new(Code::InvalidArgument, e),
+            FederationError::Unavailable(e) => Status::new(Code::Unavailable, e),
+        }
+    }
+}
+
+// Claims of the provider's ID token, the standard ones are checked by jsonwebtoken
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    #[serde(default)]
+    nonce: String,
+    #[serde(default)]
+    preferred_username: String,
+    #[serde(default)]
+    email: String,
+}
+
+// Identity proven by a finished login
+pub struct Identity {
+    pub provider: String,
+    pub subject: String,
+    pub username: String, // preferred_username or email of the provider
+}
+
+struct Metadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    jwks: Vec<Jwk>,
+    fetched: u32,
+    jwks_fetched: u32,
+}
+
+#[derive(Clone)]
+pub struct Federation {
+    providers: Arc<Vec<FederationProviderConfig>>,
+    links: sled::Tree,
+    states: sled::Tree,
+    metadata: Arc<RwLock<HashMap<String, Arc<Metadata>>>>,
+    http: Client<HttpsConnector<HttpConnector>>,
+}
+
+fn random_string(len: usize) -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(len)
+        .collect()
+}
+
+fn unavailable<E: std::fmt::Display>(provider: &str) -> impl Fn(E) -> FederationError + '_ {
+    move |e| FederationError::Unavailable(format!("Identity provider \"{}\": {}", provider, e))
+}
+
+// https, or http on the loopback
+pub fn is_secure_url(url: &str) -> bool {
+    if url.starts_with("https://") {
+        return true;
+    }
+    let authority = match url.strip_prefix("http://") {
+        Some(rest) => rest.split(['/', '?', '#']).next().unwrap_or_default(),
+        None => return false,
+    };
+    if authority.contains('@') {
+        return false;
+    }
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+    host == "localhost" || matches!(host.parse::<std::net::IpAddr>(), Ok(ip) if ip.is_loopback())
+}
+
+// Keys of a JWKS, the ones jsonwebtoken does not understand are left out
+fn parse_jwks(jwks: &Value) -> Vec<Jwk> {
+    jwks["keys"]
+        .as_array()
+        .map(|keys| {
+            keys.iter()
+                .filter_map(|key| serde_json::from_value(key.clone()).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// A username for the user created at signup, None if the provider gave nothing usable
+pub fn local_username(identity: &Identity) -> Option<String> {
+    let username = identity.username.split('@').next().unwrap_or_default();
+    let valid = (3..=MAX_USERNAME_LEN).contains(&username.len())
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    valid.then(|| username.to_string())
+}
+
+impl Federation {
+    pub fn new(
+        providers: Vec<FederationProviderConfig>,
+        links: sled::Tree,
+        states: sled::Tree,
+    ) -> Self {
+        Self {
+            providers: Arc::new(providers),
+            links,
+            states,
+            metadata: Arc::new(RwLock::new(HashMap::new())),
+            http: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+        }
+    }
+
+    pub fn providers(&self) -> Vec<FederationProvider> {
+        self.providers
+            .iter()
+            .map(|provider| FederationProvider {
+                id: provider.id.clone(),
+                name: match provider.name.as_str() {
+                    "" => provider.id.clone(),
+                    name => name.to_string(),
+                },
+            })
+            .collect()
+    }
+
+    pub fn allows_signup(&self, provider: &str) -> bool {
+        matches!(self.provider(provider), Ok(p) if p.signup)
+    }
+
+    fn provider(&self, id: &str) -> Result<&FederationProviderConfig, FederationError> {
+        self.providers
+            .iter()
+            .find(|provider| provider.id == id)
+            .ok_or_else(|| FederationError::Invalid("Unknown identity provider".to_string()))
+    }
+
+    async fn send(&self, request: Request<Body>, provider: &str) -> Result<Value, FederationError> {
+        let response = tokio::time::timeout(HTTP_TIMEOUT, self.http.request(request))
+            .await
+            .map_err(unavailable(provider))?
+            .map_err(unavailable(provider))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(unavailable(provider))?;
+        let value: Value = serde_json::from_slice(&body).map_err(unavailable(provider))?;
+        if !status.is_success() {
+            return Err(FederationError::Invalid(format!(
+                "Identity provider \"{}\" refused the code: {}",
+                provider,
+                value["error"].as_str().unwrap_or_else(|| status.as_str())
+            )));
+        }
+        Ok(value)
+    }
+
+    async fn get(&self, url: &str, provider: &str) -> Result<Value, FederationError> {
+        let request = Request::get(url)
+            .header(ACCEPT, "application/json")
+            .body(Body::empty())
+            .map_err(unavailable(provider))?;
+        self.send(request, provider).await
+    }
+
+    // Discovery document and JWKS, from the cache unless stale or refresh_jwks
+    async fn metadata(
+        &self,
+        provider: &FederationProviderConfig,
+        refresh_jwks: bool,
+    ) -> Result<Arc<Metadata>, FederationError> {
+        let now = get_now_plus(0) as u32;
+        let cached = self.metadata.read().unwrap().get(&provider.id).cloned();
+        if let Some(metadata) = &cached {
+            let fresh = metadata.fetched.saturating_add(METADATA_TTL) > now;
+            let jwks_fresh = metadata.jwks_fetched.saturating_add(JWKS_MIN_REFRESH) > now;
+            if fresh && (!refresh_jwks || jwks_fresh) {
+                return Ok(metadata.clone());
+            }
+        }
+        let metadata = match cached.filter(|m| m.fetched.saturating_add(METADATA_TTL) > now) {
+            Some(metadata) => Metadata {
+                authorization_endpoint: metadata.authorization_endpoint.clone(),
+                token_endpoint: metadata.token_endpoint.clone(),
+                jwks_uri: metadata.jwks_uri.clone(),
+                jwks: Vec::new(),
+                fetched: metadata.fetched,
+                jwks_fetched: now,
+            },
+            None => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    provider.issuer.trim_end_matches('/')
+                );
+                let discovery = self.get(&url, &provider.id).await?;
+                if discovery["issuer"] != provider.issuer.as_str() {
+                    return Err(unavailable(&provider.id)(
+                        "issuer mismatch in the discovery document",
+                    ));
+                }
+                let endpoint = |name: &str| match discovery[name].as_str() {
+                    Some(url) if is_secure_url(url) => Ok(url.to_string()),
+                    Some(_) => Err(unavailable(&provider.id)(format!(
+                        "{} is not an https url",
+                        name
+                    ))),
+                    None => Err(unavailable(&provider.id)(format!("no {}", name))),
+                };
+                Metadata {
+                    authorization_endpoint: endpoint("authorization_endpoint")?,
+                    token_endpoint: endpoint("token_endpoint")?,
+                    jwks_uri: endpoint("jwks_uri")?,
+                    jwks: Vec::new(),
+                    fetched: now,
+                    jwks_fetched: now,
+                }
+            }
+        };
+        let jwks = self.get(&metadata.jwks_uri, &provider.id).await?;
+        let metadata = Arc::new(Metadata {
+            jwks: parse_jwks(&jwks),
+            ..metadata
+        });
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(provider.id.clone(), metadata.clone());
+        Ok(metadata)
+    }
+
+    // Return the provider's authorization url and the state
+    pub async fn start(
+        &self,
+        provider: &str,
+        link_to: &str,
+    ) -> Result<(String, String), FederationError> {
+        let provider = self.provider(provider)?;
+        let metadata = self.metadata(provider, false).await?;
+        let state = random_string(32);
+        let login = FederatedLoginState {
+            provider: provider.id.clone(),
+            nonce: random_string(32),
+            code_verifier: random_string(64),
+            expiration_date: get_now_plus(STATE_DURATION) as u32,
+            link_to: link_to.to_string(),
+        };
+        let challenge = base64::encode_config(
+            Sha256::digest(login.code_verifier.as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &provider.client_id)
+            .append_pair("redirect_uri", &provider.redirect_uri)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256")
+            .finish();
+        self.states
+            .insert(&state, login.encode_to_vec())
+            .map_err(|e| FederationError::Unavailable(e.to_string()))?;
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        Ok((
+            format!("{}{}{}", metadata.authorization_endpoint, separator, query),
+            state,
+        ))
+    }
+
+    // Exchange the code, return the identity and the user linking it if any
+    pub async fn finish(
+        &self,
+        state: &str,
+        code: &str,
+    ) -> Result<(Identity, String), FederationError> {
+        let invalid_state = || FederationError::Invalid("Invalid or expired state".to_string());
+        // Single use
+        let login = match self.states.remove(state) {
+            Ok(Some(value)) => {
+                FederatedLoginState::decode(value.as_ref()).map_err(|_| invalid_state())?
+            }
+            _ => return Err(invalid_state()),
+        };
+        if login.expiration_date < get_now_plus(0) as u32 {
+            return Err(invalid_state());
+        }
+        let provider = self.provider(&login.provider)?;
+        let metadata = self.metadata(provider, false).await?;
+        let form = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &provider.redirect_uri)
+            .append_pair("code_verifier", &login.code_verifier)
+            .append_pair("client_id", &provider.client_id)
+            .finish();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&metadata.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json");
+        if !provider.client_secret.is_empty() {
+            let credentials = format!("{}:{}", provider.client_id, provider.client_secret);
+            request = request.header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        let request = request
+            .body(Body::from(form))
+            .map_err(unavailable(&provider.id))?;
+        let tokens = self.send(request, &provider.id).await?;
+        let id_token = tokens["id_token"]
+            .as_str()
+            .ok_or_else(|| unavailable(&provider.id)("no id_token in the token response"))?;
+        let claims = self.verify(provider, metadata, id_token).await?;
+        if claims.nonce != login.nonce {
+            return Err(FederationError::Invalid(
+                "Invalid ID token nonce".to_string(),
+            ));
+        }
+        let username = match claims.preferred_username.as_str() {
+            "" => claims.email,
+            username => username.to_string(),
+        };
+        Ok((
+            Identity {
+                provider: provider.id.clone(),
+                subject: claims.sub,
+                username,
+            },
+            login.link_to,
+        ))
+    }
+
+    async fn verify(
+        &self,
+        provider: &FederationProviderConfig,
+        metadata: Arc<Metadata>,
+        id_token: &str,
+    ) -> Result<IdTokenClaims, FederationError> {
+        let invalid = |e: String| FederationError::Invalid(format!("Invalid ID token: {}", e));
+        let header = decode_header(id_token).map_err(|e| invalid(e.to_string()))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(invalid(format!("unsupported algorithm {:?}", header.alg)));
+        }
+        let find = |jwks: &[Jwk]| -> Option<DecodingKey> {
+            let jwk = match &header.kid {
+                Some(kid) => jwks
+                    .iter()
+                    .find(|jwk| jwk.common.key_id.as_deref() == Some(kid.as_str()))?,
+                None if jwks.len() == 1 => &jwks[0],
+                None => return None,
+            };
+            DecodingKey::from_jwk(jwk).ok()
+        };
+        let key = match find(&metadata.jwks) {
+            Some(key) => key,
+            // The provider may have rotated its keys
+            None => find(&self.metadata(provider, true).await?.jwks)
+                .ok_or_else(|| invalid("unknown signing key".to_string()))?,
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_issuer(&[&provider.issuer]);
+        decode::<IdTokenClaims>(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn get_link(&self, provider: &str, subject: &str) -> Option<String> {
+        match self.links.get(format!("{}:{}", provider, subject)) {
+            Ok(Some(value)) => FederatedIdentity::decode(value.as_ref())
+                .ok()
+                .map(|identity| identity.username),
+            _ => None,
+        }
+    }
+
+    fn record(identity: &Identity, username: &str) -> FederatedIdentity {
+        FederatedIdentity {
+            provider: identity.provider.clone(),
+            subject: identity.subject.clone(),
+            username: username.to_string(),
+            linked_date: get_now_plus(0) as u32,
+            provider_username: identity.username.clone(),
+        }
+    }
+
+    // Linking twice to the same user is fine, to another one is not
+    pub fn link(&self, identity: &Identity, username: &str) -> Result<(), String> {
+        let key = format!("{}:{}", identity.provider, identity.subject);
+        let value = Self::record(identity, username).encode_to_vec();
+        match self
+            .links
+            .compare_and_swap(&key, None as Option<&[u8]>, Some(value))
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_))
+                if self
+                    .get_link(&identity.provider, &identity.subject)
+                    .as_deref()
+                    == Some(username) =>
+            {
+                Ok(())
+            }
+            Ok(Err(_)) => Err("Identity already linked to another user".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Create the user and its link, the password hash cannot be guessed.
+    // The user is removed again if the identity got linked meanwhile.
+    pub fn signup(
+        &self,
+        users: &Users,
+        identity: &Identity,
+        username: &str,
+        record: &UserRecord,
+    ) -> Result<(), String> {
+        if users.exists(username) {
+            return Err(format!(
+                "Username {} already exist, sign in and link the identity from the settings",
+                username
+            ));
+        }
+        let key = format!("{}:{}", identity.provider, identity.subject);
+        let value = Self::record(identity, username).encode_to_vec();
+        if matches!(self.links.contains_key(&key), Ok(true)) {
+            return Err("Identity already linked to another user".to_string());
+        }
+        match users.create(username, record, None) {
+            Ok(()) => {}
+            Err(CreateError::Rejected(e)) | Err(CreateError::Database(e)) => return Err(e),
+        }
+        match self
+            .links
+            .compare_and_swap(key.as_str(), None as Option<&[u8]>, Some(value))
+        {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => {
+                let _res = users.remove(username);
+                Err("Identity already linked to another user".to_string())
+            }
+            Err(e) => {
+                let _res = users.remove(username);
+                Err(e.to_string())
+            }
+        }
+    }
+
+    pub fn get_links(&self, username: &str) -> Vec<FederatedIdentity> {
+        self.links
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, value)| FederatedIdentity::decode(value.as_ref()).ok())
+            .filter(|identity| identity.username == username)
+            .collect()
+    }
+
+    // Return false if the user has no such link
+    pub fn unlink(&self, username: &str, provider: &str, subject: &str) -> Result<bool, String> {
+        if self.get_link(provider, subject).as_deref() != Some(username) {
+            return Ok(false);
+        }
+        self.links
+            .remove(format!("{}:{}", provider, subject))
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    pub fn unlink_all(&self, username: &str) -> Result<(), String> {
+        for identity in self.get_links(username) {
+            self.links
+                .remove(format!("{}:{}", identity.provider, identity.subject))
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let expired: Vec<sled::IVec> = self
+            .states
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                FederatedLoginState::decode(value.as_ref())
+                    .map_or(true, |state| state.expiration_date < now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        expired
+            .into_iter()
+            .filter(|key| matches!(self.states.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(STATE_DURATION as u64));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
@@ -1,25 +1,70 @@
-use proto::prost::Message;
-use proto::server::user::InviteToken;
+use crate::get_now_plus;
+use crate::storage::InviteRepository;
+use proto::server::user::{Invitation, InviteToken, InviteTreeNode};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use std::collections::HashSet;
 
 // key : username:randomstring
+// value : InviteToken, the token field is not stored
 // out -> base64(key)
 
-pub fn get(db: &sled::Tree, username: &str) -> Result<Vec<InviteToken>, String> {
+// invitations: inviter, invitee -> Invitation
+
+fn output(key: &str, mut invite: InviteToken) -> InviteToken {
+    invite.token = base64::encode(key);
+    invite.used = invite.used_by.len() >= invite.max_uses.max(1) as usize;
+    invite
+}
+
+fn is_expired(invite: &InviteToken) -> bool {
+    invite.expiration_date != 0 && (invite.expiration_date as usize) < get_now_plus(0)
+}
+
+// The key of an invite given by a user
+pub fn key(invite: &str) -> Result<String, String> {
+    base64::decode(invite)
+        .ok()
+        .and_then(|key| String::from_utf8(key).ok())
+        .ok_or_else(|| "Invalid invite".to_string())
+}
+
+fn owner(key: &str) -> &str {
+    key.split(':').next().unwrap_or_default()
+}
+
+pub fn get(db: &dyn InviteRepository, username: &str) -> Result<Vec<InviteToken>, String> {
     Ok(db
-        .scan_prefix(format!("{}:", username))
-        .filter_map(|entry| match entry {
-            Ok((key, value)) => Some(InviteToken {
-                token: base64::encode(key),
-                used: false,
-            }),
-            Err(_) => None,
-        })
+        .get(username)?
+        .into_iter()
+        .map(|(key, invite)| output(&key, invite))
+        .collect())
+}
+
+// Invites of every user, from the one after the `after` token
+pub fn list(
+    db: &dyn InviteRepository,
+    after: &str,
+    limit: usize,
+) -> Result<Vec<(String, InviteToken)>, String> {
+    let after = match after {
+        "" => String::new(),
+        after => key(after).or(Err("Invalid page token".to_string()))?,
+    };
+    Ok(db
+        .list(&after, limit)?
+        .into_iter()
+        .map(|(key, invite)| (owner(&key).to_string(), output(&key, invite)))
         .collect())
 }
 
-pub fn create(db: &sled::Tree, user: &str) -> Result<InviteToken, String> {
+pub fn create(
+    db: &dyn InviteRepository,
+    user: &str,
+    expiration_date: u32,
+    max_uses: u32,
+    note: &str,
+) -> Result<InviteToken, String> {
     // let username = base64::encode(user);
     assert!(user.len() < 10);
     let salt: String = rand::thread_rng()
@@ -28,43 +73,105 @@ pub fn create(db: &sled::Tree, user: &str) -> Result<InviteToken, String> {
         .map(char::from)
         .collect();
     let key = format!("{}:{}", user, salt);
+    let invite = InviteToken {
+        expiration_date,
+        max_uses: max_uses.max(1),
+        note: note.to_string(),
+        ..InviteToken::default()
+    };
 
     // /!\ infinit recursion
-    if db.contains_key(&key).or(Err("Database Error"))? {
-        return create(db, user);
+    if !db.insert(&key, &invite)? {
+        return create(db, user, expiration_date, max_uses, note);
     }
-    db.insert(&key, b"some content")
-        .or(Err("Database error".to_string()))?;
-    Ok(InviteToken {
-        token: base64::encode(key),
-        used: false,
-    })
+    Ok(output(&key, invite))
 }
 
-pub fn delete(db: sled::Tree, username: String, invite: &str) -> Result<(), String> {
-    let bkey = match base64::decode(invite) {
-        Err(_) => return Err("Invalid key".to_string()),
-        Ok(a) => a,
-    };
-    let key = std::str::from_utf8(&bkey).or(Err("Invalid key".to_string()))?;
+pub fn delete_all(db: &dyn InviteRepository, username: &str) -> Result<(), String> {
+    db.remove_all(username)
+}
 
-    if !key.starts_with(&format!("{}:", username)) {
+pub fn delete(db: &dyn InviteRepository, username: &str, invite: &str) -> Result<(), String> {
+    let key = key(invite).or(Err("Invalid key".to_string()))?;
+    if owner(&key) != username {
         return Err("Invalid key".to_string());
     }
+    match db.remove(&key)? {
+        true => Ok(()),
+        false => Err("Invalid token".to_string()),
+    }
+}
 
-    // /!\ infinit recursion
-    db.remove(&key)
-        .or(Err("Database error".to_string()))?
-        .ok_or("Invalid token".to_string())?;
-    Ok(())
+// Called by the backends inside the signup transaction, so the invite is only
+// consumed if the user is actually created. Return the invite to store back
+// and the invitation to record.
+pub fn redeem(
+    key: &str,
+    invite: InviteToken,
+    invitee: &str,
+) -> Result<(InviteToken, Invitation), String> {
+    let mut invite = output(key, invite);
+    if invite.used {
+        return Err("Invite already used".to_string());
+    }
+    if is_expired(&invite) {
+        return Err("Invite expired".to_string());
+    }
+    invite.token = String::new(); // Not stored
+    invite.used_by.push(invitee.to_string());
+    invite.used = invite.used_by.len() >= invite.max_uses.max(1) as usize;
+    let invitation = Invitation {
+        inviter: owner(key).to_string(),
+        invitee: invitee.to_string(),
+        date: get_now_plus(0) as u32,
+        invite: base64::encode(key),
+    };
+    Ok((invite, invitation))
 }
 
-pub fn uze(db: &sled::Tree, invite: &str, _username: &str) -> Result<(), String> {
-    let invite = base64::decode(invite).map_err(|_| "Invalid invite".to_string())?;
-    let res = db
-        .get(invite)
-        .map_err(|_| "database error".to_string())?
-        .ok_or("Invalid invite".to_string())?;
-    // TODO
-    Ok(())
+// A username can be registered again once deleted, its invitations must not
+// be inherited
+pub fn delete_invitations(db: &dyn InviteRepository, username: &str) -> Result<(), String> {
+    db.remove_invitations(username)
+}
+
+pub fn invitees(db: &dyn InviteRepository, username: &str) -> Result<Vec<Invitation>, String> {
+    db.invitees(username)
+}
+
+// Invitation used to signup, None for the bootstrap admin
+pub fn inviter(db: &dyn InviteRepository, invitee: &str) -> Option<Invitation> {
+    db.inviter(invitee).ok().flatten()
+}
+
+// Users already in the tree are skipped: invitations left by a deleted user
+// could close a cycle
+pub fn tree(db: &dyn InviteRepository, root: &str) -> Result<InviteTreeNode, String> {
+    fn walk(
+        db: &dyn InviteRepository,
+        invitation: Invitation,
+        visited: &mut HashSet<String>,
+    ) -> Result<InviteTreeNode, String> {
+        visited.insert(invitation.invitee.clone());
+        let mut nodes = Vec::new();
+        for invitation in invitees(db, &invitation.invitee)? {
+            if !visited.contains(&invitation.invitee) {
+                nodes.push(walk(db, invitation, visited)?);
+            }
+        }
+        Ok(InviteTreeNode {
+            username: invitation.invitee,
+            date: invitation.date,
+            invite: invitation.invite,
+            invitees: nodes,
+        })
+    }
+    walk(
+        db,
+        Invitation {
+            invitee: root.to_string(),
+            ..Invitation::default()
+        },
+        &mut HashSet::new(),
+    )
 }
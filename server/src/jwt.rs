@@ -1,12 +1,31 @@
+use crate::config::{JwtConfig, JwtKeyConfig};
+use crate::deny_list::DenyList;
 use crate::get_now_plus;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use crate::pat::{self, PersonalAccessTokens, Scopes};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::env;
+use simple_asn1::ASN1Block;
+use std::iter;
+use std::sync::Arc;
 use tonic::service::Interceptor;
 /*
-    Access token are used to access api endpoints it live only 10 minutes
+    Access token are used to access api endpoints it live only 10 minutes by default
     sub: username
-    exp: timestamp of the date generated plus 10 minutes
+    exp: timestamp of the date generated plus jwt.access_token_duration
+    roles: roles of the user at creation, see roles.rs
+    jti: random ID of the token
+    sid: family of the refresh token it was created with, the session
+    Both can be revoked before the expiration, see deny_list.rs
+    The interceptor also accepts personal access tokens, see pat.rs
+
+    The sessions of the OAuth clients get access tokens for the client only:
+    aud is the client id and scope the granted scope, roles only with the
+    roles scope. The interceptor refuses them, they are for the /oauth2
+    userinfo and introspection endpoints.
 */
 
 /*
@@ -16,52 +35,365 @@ use tonic::service::Interceptor;
     iss: ID of the token ( can be blacklisted ) if token not in database < ID >
 */
 
-// TODO: use env, need next version of jwt
-const SECRET_KEY: &str = "super secret";
-
-const TOKEN_DURATION: u32 = 60 * 10; /* 10 minutes in seconds */
+/*
+    Signing keys:
+    - no jwt.signing_key: HS256 with jwt.secret, nothing to publish
+    - jwt.signing_key = kid: sign with this key of jwt.keys, verify with any of
+      jwt.keys so old keys can stay until their tokens expire
+    ID tokens are only issued with a signing key (see Config::validate), the
+    relying parties verify them with /.well-known/jwks.json.
+*/
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AccessTokenClaims {
     pub sub: String, /*  Username  */
     pub exp: usize,  /* expiration */
     pub iss: String, /*   access   */
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub jti: String,
+    #[serde(default)]
+    pub sid: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub aud: String, /* OAuth client id, empty for this app */
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scope: String, /* Granted to the OAuth client */
+}
+
+// OpenID Connect ID token, for the clients of oauth2.rs
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String, /* oauth.issuer */
+    pub sub: String, /*  Username   */
+    pub aud: String, /*  client id  */
+    pub exp: usize,
+    pub iat: usize,
+    pub auth_time: usize,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub nonce: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>, /* with the profile scope */
+}
+
+// Public part of a key, RFC 7517 format
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub usage: String,
+    pub alg: String,
+    pub kid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PublicKey {
+    pub pem: String,
+    pub jwk: Jwk,
+}
+
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    public: Option<PublicKey>, // None for the shared secret
 }
 
 #[derive(Clone)]
 pub struct Jwt {
-    decode_key: DecodingKey<'static>, // Will be fix in next version of jwt
+    keys: Arc<Vec<VerifyingKey>>,
     encode_key: EncodingKey,
-    validation: Validation,
     header: Header,
+    duration: u32, /* seconds */
+    deny_list: DenyList,
+    pats: PersonalAccessTokens,
+}
+
+pub fn parse_algorithm(algorithm: &str) -> Result<Algorithm, String> {
+    match algorithm {
+        "EdDSA" => Ok(Algorithm::EdDSA),
+        "RS256" => Ok(Algorithm::RS256),
+        _ => Err(format!(
+            "unsupported algorithm \"{}\", should be EdDSA or RS256",
+            algorithm
+        )),
+    }
+}
+
+fn b64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// SubjectPublicKeyInfo -> subjectPublicKey
+fn spki_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    match simple_asn1::from_der(der).ok()?.as_slice() {
+        [ASN1Block::Sequence(_, spki)] => match spki.as_slice() {
+            [_, ASN1Block::BitString(_, _, key)] => Some(key.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// RSAPublicKey -> (modulus, exponent)
+fn rsa_components(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    match simple_asn1::from_der(der).ok()?.as_slice() {
+        [ASN1Block::Sequence(_, key)] => match key.as_slice() {
+            [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => {
+                Some((n.to_bytes_be().1, e.to_bytes_be().1))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn public_jwk(kid: &str, algorithm: Algorithm, pem: &[u8]) -> Option<Jwk> {
+    let pem = pem::parse(pem).ok()?;
+    let mut jwk = Jwk {
+        kty: String::new(),
+        usage: "sig".to_string(),
+        alg: String::new(),
+        kid: kid.to_string(),
+        crv: None,
+        x: None,
+        n: None,
+        e: None,
+    };
+    match algorithm {
+        Algorithm::EdDSA => {
+            jwk.kty = "OKP".to_string();
+            jwk.alg = "EdDSA".to_string();
+            jwk.crv = Some("Ed25519".to_string());
+            jwk.x = Some(b64url(&spki_public_key(&pem.contents)?));
+        }
+        Algorithm::RS256 => {
+            let (n, e) = if pem.tag == "RSA PUBLIC KEY" {
+                rsa_components(&pem.contents)?
+            } else {
+                rsa_components(&spki_public_key(&pem.contents)?)?
+            };
+            jwk.kty = "RSA".to_string();
+            jwk.alg = "RS256".to_string();
+            jwk.n = Some(b64url(&n));
+            jwk.e = Some(b64url(&e));
+        }
+        _ => return None,
+    }
+    Some(jwk)
+}
+
+fn load_key(
+    config: &JwtKeyConfig,
+    signing: bool,
+) -> Result<(VerifyingKey, Option<EncodingKey>), String> {
+    let algorithm = parse_algorithm(&config.algorithm)?;
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+    };
+    let public = read(&config.public_key)?;
+    let key = match algorithm {
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(&public),
+        _ => DecodingKey::from_rsa_pem(&public),
+    }
+    .map_err(|e| format!("invalid public key {}: {}", config.public_key.display(), e))?;
+    let jwk = public_jwk(&config.kid, algorithm, &public).ok_or(format!(
+        "invalid public key {}",
+        config.public_key.display()
+    ))?;
+    let encode_key = match (&config.private_key, signing) {
+        (Some(path), true) => {
+            let private = read(path)?;
+            let key = match algorithm {
+                Algorithm::EdDSA => EncodingKey::from_ed_pem(&private),
+                _ => EncodingKey::from_rsa_pem(&private),
+            }
+            .map_err(|e| format!("invalid private key {}: {}", path.display(), e))?;
+            Some(key)
+        }
+        (None, true) => return Err(format!("jwt key \"{}\" has no private_key", config.kid)),
+        (_, false) => None,
+    };
+    Ok((
+        VerifyingKey {
+            kid: Some(config.kid.clone()),
+            algorithm,
+            key,
+            public: Some(PublicKey {
+                pem: String::from_utf8_lossy(&public).to_string(),
+                jwk,
+            }),
+        },
+        encode_key,
+    ))
 }
 
 impl Jwt {
-    pub fn new() -> Self {
-        Self {
-            decode_key: DecodingKey::from_secret(SECRET_KEY.as_ref()),
-            encode_key: EncodingKey::from_secret(SECRET_KEY.as_ref()),
-            validation: Validation::default(),
-            header: Header::default(),
+    pub fn new(
+        config: &JwtConfig,
+        deny_list: DenyList,
+        pats: PersonalAccessTokens,
+    ) -> Result<Self, String> {
+        let signing_kid = match &config.signing_key {
+            Some(kid) => kid,
+            None => {
+                return Ok(Self {
+                    keys: Arc::new(vec![VerifyingKey {
+                        kid: None,
+                        algorithm: Algorithm::HS256,
+                        key: DecodingKey::from_secret(config.secret.as_ref()),
+                        public: None,
+                    }]),
+                    encode_key: EncodingKey::from_secret(config.secret.as_ref()),
+                    header: Header::default(),
+                    duration: config.access_token_duration,
+                    deny_list,
+                    pats,
+                })
+            }
+        };
+        let mut keys = Vec::new();
+        let mut signing = None;
+        for key in config.keys.iter() {
+            let (key, encode_key) = load_key(key, &key.kid == signing_kid)?;
+            if let Some(encode_key) = encode_key {
+                signing = Some((key.algorithm, encode_key));
+            }
+            keys.push(key);
         }
+        let (algorithm, encode_key) =
+            signing.ok_or(format!("jwt.signing_key: unknown key \"{}\"", signing_kid))?;
+        let jwt = Self {
+            keys: Arc::new(keys),
+            encode_key,
+            header: Header {
+                kid: Some(signing_kid.clone()),
+                ..Header::new(algorithm)
+            },
+            duration: config.access_token_duration,
+            deny_list,
+            pats,
+        };
+        // Catch a private key not matching its public key before serving
+        jwt.verify(&jwt.create_token("", Vec::new(), ""))
+            .map_err(|_| format!("jwt key \"{}\": key pair mismatch", signing_kid))?;
+        Ok(jwt)
+    }
+
+    pub fn get_exp(&self) -> u32 {
+        get_now_plus(self.duration) as u32
     }
 
-    pub fn get_exp() -> u32 {
-        get_now_plus(TOKEN_DURATION) as u32
+    pub fn create_token(&self, username: &str, roles: Vec<String>, session: &str) -> String {
+        self.create_client_token(username, roles, session, "", "")
     }
 
-    pub fn create_token(&self, username: &str) -> String {
+    // Empty client_id for this app
+    pub fn create_client_token(
+        &self,
+        username: &str,
+        roles: Vec<String>,
+        session: &str,
+        client_id: &str,
+        scope: &str,
+    ) -> String {
+        let mut rng = thread_rng();
+        let jti: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(16)
+            .collect();
         encode(
             &self.header,
             &AccessTokenClaims {
                 sub: username.to_string(),
-                exp: get_now_plus(TOKEN_DURATION),
+                exp: get_now_plus(self.duration),
                 iss: "access".to_string(),
+                roles,
+                jti,
+                sid: session.to_string(),
+                aud: client_id.to_string(),
+                scope: scope.to_string(),
             },
             &self.encode_key,
         )
         .unwrap()
     }
+
+    // Signed with the access token key (jwt.signing_key), valid as long as an
+    // access token
+    pub fn create_id_token(&self, claims: &IdTokenClaims) -> String {
+        encode(&self.header, claims, &self.encode_key).unwrap()
+    }
+
+    // "HS256", "EdDSA" or "RS256"
+    pub fn algorithm(&self) -> String {
+        format!("{:?}", self.header.alg)
+    }
+
+    pub fn public_keys(&self) -> Vec<PublicKey> {
+        self.keys
+            .iter()
+            .filter_map(|key| key.public.clone())
+            .collect()
+    }
+
+    pub fn jwks(&self) -> String {
+        let keys: Vec<Jwk> = self.public_keys().into_iter().map(|key| key.jwk).collect();
+        serde_json::json!({ "keys": keys }).to_string()
+    }
+
+    // Claims of an access token or a personal access token, and the scopes of the latter.
+    // Not the access tokens of the OAuth clients.
+    pub fn authenticate(
+        &self,
+        token: &str,
+    ) -> Result<(AccessTokenClaims, Option<Scopes>), &'static str> {
+        let (claims, scopes) = self.authenticate_any(token)?;
+        if !claims.aud.is_empty() {
+            return Err("Invalid token");
+        }
+        Ok((claims, scopes))
+    }
+
+    // The same, the access tokens of the OAuth clients included
+    pub fn authenticate_any(
+        &self,
+        token: &str,
+    ) -> Result<(AccessTokenClaims, Option<Scopes>), &'static str> {
+        if token.starts_with(pat::PREFIX) {
+            let (claims, scopes) = self.pats.authenticate(token).ok_or("Invalid token")?;
+            return Ok((claims, Some(scopes)));
+        }
+        let claims = self.verify(token).map_err(|_| "Invalid token")?;
+        if claims.exp < get_now_plus(0) {
+            return Err("Expired credentials");
+        }
+        if claims.iss != "access" {
+            return Err("Invalid token");
+        }
+        if self.deny_list.is_denied(&claims.jti) || self.deny_list.is_denied(&claims.sid) {
+            return Err("Revoked token");
+        }
+        Ok((claims, None))
+    }
+
+    fn verify(&self, token: &str) -> Result<AccessTokenClaims, jsonwebtoken::errors::Error> {
+        let kid = decode_header(token)?.kid;
+        let key = match self.keys.iter().find(|key| key.kid == kid) {
+            Some(key) => key,
+            None => return Err(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat.into()),
+        };
+        Ok(decode::<AccessTokenClaims>(token, &key.key, &Validation::new(key.algorithm))?.claims)
+    }
 }
 
 impl Interceptor for Jwt {
@@ -78,28 +410,13 @@ impl Interceptor for Jwt {
                 ));
             }
         };
-        let token = match decode::<AccessTokenClaims>(token, &self.decode_key, &self.validation) {
-            Ok(token) => token,
-            Err(_) => {
-                return Err(tonic::Status::new(
-                    tonic::Code::PermissionDenied,
-                    "Invalid token",
-                ))
-            }
-        };
-        if token.claims.exp < get_now_plus(0) {
-            return Err(tonic::Status::new(
-                tonic::Code::PermissionDenied,
-                "Expired credentials",
-            ));
-        }
-        if token.claims.iss != "access" {
-            return Err(tonic::Status::new(
-                tonic::Code::PermissionDenied,
-                "Invalid token",
-            ));
+        let (claims, scopes) = self
+            .authenticate(token)
+            .map_err(|e| tonic::Status::new(tonic::Code::PermissionDenied, e))?;
+        request.extensions_mut().insert(claims);
+        if let Some(scopes) = scopes {
+            request.extensions_mut().insert(scopes);
         }
-        request.extensions_mut().insert(token.claims);
         Ok(request)
     }
 }
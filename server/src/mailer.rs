@@ -0,0 +1,303 @@
+use crate::config::MailConfig;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+/*
+    Outgoing mails, chosen by mail.backend:
+    - LogMailer prints them, for development
+    - FileMailer appends them to a file as json lines, for the tests
+    - SmtpMailer hands them to a relay, with optional AUTH PLAIN. The relay's
+      certificate is checked against the webpki roots, STARTTLS by default.
+*/
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_ADDRESS_LEN: usize = 254;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[tonic::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), String>;
+}
+
+// Loose check, enough to keep the headers and SMTP commands intact
+pub fn valid_address(address: &str) -> bool {
+    let valid_chars = !address
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || "<>()[],;:\\\"".contains(c));
+    match address.split_once('@') {
+        Some((local, domain)) => {
+            valid_chars
+                && address.len() <= MAX_ADDRESS_LEN
+                && !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+        }
+        None => false,
+    }
+}
+
+// The address of "Name <address>" or "address"
+fn address(mailbox: &str) -> &str {
+    match mailbox.rfind('<') {
+        Some(start) if mailbox.ends_with('>') => &mailbox[start + 1..mailbox.len() - 1],
+        _ => mailbox,
+    }
+}
+
+pub fn valid_mailbox(mailbox: &str) -> bool {
+    !mailbox.chars().any(char::is_control) && valid_address(address(mailbox))
+}
+
+pub fn from_config(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.backend.as_str() {
+        "file" => Arc::new(FileMailer {
+            path: config.file.clone(),
+            from: config.from.clone(),
+            lock: Mutex::new(()),
+        }),
+        "smtp" => Arc::new(SmtpMailer {
+            host: config.smtp_host.clone(),
+            port: config.smtp_port,
+            tls: config.smtp_tls.clone(),
+            connector: tls_connector(),
+            from: config.from.clone(),
+            username: config.smtp_username.clone(),
+            password: config.smtp_password.clone(),
+        }),
+        // Checked in Config::validate
+        _ => Arc::new(LogMailer {
+            from: config.from.clone(),
+        }),
+    }
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+pub struct LogMailer {
+    from: String,
+}
+
+#[tonic::async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), String> {
+        println!(
+            "Mail from {} to {}: {}\n{}",
+            self.from, mail.to, mail.subject, mail.body
+        );
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    path: PathBuf,
+    from: String,
+    lock: Mutex<()>, // One line at a time
+}
+
+#[tonic::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), String> {
+        let line = json!({
+            "from": self.from,
+            "to": mail.to,
+            "subject": mail.subject,
+            "body": mail.body,
+        })
+        .to_string()
+            + "\n";
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        file.write_all(line.as_bytes())
+            .await
+            .map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    tls: String, // "starttls", "tls" or "none", checked in Config::validate
+    connector: TlsConnector,
+    from: String,
+    username: String, // Never set with "none"
+    password: String,
+}
+
+// Over TCP or TLS, the buffered reads never cross the STARTTLS upgrade
+struct SmtpConnection<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpConnection<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    // Nothing can be pending: the server waits for the TLS handshake
+    fn into_inner(self) -> Result<S, String> {
+        if !self.stream.buffer().is_empty() {
+            return Err("unexpected data before the TLS handshake".to_string());
+        }
+        Ok(self.stream.into_inner())
+    }
+
+    // Read a possibly multiline reply, Err unless its code starts with expected
+    async fn reply(&mut self, expected: char) -> Result<(), String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = self
+                .stream
+                .read_line(&mut line)
+                .await
+                .map_err(|e| e.to_string())?;
+            if read == 0 {
+                return Err("connection closed by the server".to_string());
+            }
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        if !line.starts_with(expected) {
+            return Err(format!("unexpected reply \"{}\"", line.trim_end()));
+        }
+        Ok(())
+    }
+
+    async fn command(&mut self, command: &str, expected: char) -> Result<(), String> {
+        self.stream
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        self.reply(expected).await
+    }
+}
+
+impl SmtpMailer {
+    fn ehlo(&self) -> String {
+        let domain = address(&self.from)
+            .split_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        format!("EHLO {}", domain)
+    }
+
+    async fn handshake(
+        &self,
+        stream: TcpStream,
+    ) -> Result<tokio_rustls::client::TlsStream<TcpStream>, String> {
+        let name = ServerName::try_from(self.host.as_str())
+            .map_err(|_| format!("invalid TLS server name \"{}\"", self.host))?;
+        self.connector
+            .connect(name, stream)
+            .await
+            .map_err(|e| format!("TLS: {}", e))
+    }
+
+    async fn session(&self, mail: &Mail) -> Result<(), String> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| e.to_string())?;
+        match self.tls.as_str() {
+            "none" => {
+                let mut smtp = SmtpConnection::new(stream);
+                smtp.reply('2').await?;
+                self.deliver(smtp, mail).await
+            }
+            "tls" => {
+                let mut smtp = SmtpConnection::new(self.handshake(stream).await?);
+                smtp.reply('2').await?;
+                self.deliver(smtp, mail).await
+            }
+            _ => {
+                let mut smtp = SmtpConnection::new(stream);
+                smtp.reply('2').await?;
+                smtp.command(&self.ehlo(), '2').await?;
+                smtp.command("STARTTLS", '2').await?;
+                let stream = self.handshake(smtp.into_inner()?).await?;
+                self.deliver(SmtpConnection::new(stream), mail).await
+            }
+        }
+    }
+
+    // After the greeting, and the TLS handshake if any
+    async fn deliver<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut smtp: SmtpConnection<S>,
+        mail: &Mail,
+    ) -> Result<(), String> {
+        let from = address(&self.from);
+        smtp.command(&self.ehlo(), '2').await?;
+        if !self.username.is_empty() {
+            let credentials = base64::encode(format!("\0{}\0{}", self.username, self.password));
+            smtp.command(&format!("AUTH PLAIN {}", credentials), '2')
+                .await?;
+        }
+        smtp.command(&format!("MAIL FROM:<{}>", from), '2').await?;
+        smtp.command(&format!("RCPT TO:<{}>", mail.to), '2').await?;
+        smtp.command("DATA", '3').await?;
+        // Lines starting with a dot are escaped, the message ends with a lone one
+        let body: String = mail
+            .body
+            .lines()
+            .map(|line| match line.starts_with('.') {
+                true => format!(".{}\r\n", line),
+                false => format!("{}\r\n", line),
+            })
+            .collect();
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+            Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}.",
+            self.from, mail.to, mail.subject, body
+        );
+        smtp.command(&message, '2').await?;
+        let _res = smtp.command("QUIT", '2').await;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), String> {
+        if !valid_address(&mail.to) {
+            return Err(format!("invalid address \"{}\"", mail.to));
+        }
+        match tokio::time::timeout(SMTP_TIMEOUT, self.session(mail)).await {
+            Ok(res) => res.map_err(|e| format!("SMTP {}:{}: {}", self.host, self.port, e)),
+            Err(_) => Err(format!("SMTP {}:{}: timeout", self.host, self.port)),
+        }
+    }
+}
@@ -1,16 +1,42 @@
 // use std::sync::Arc;
-use proto::server::{auth::auth_server::AuthServer, user::user_server::UserServer};
+use proto::server::{
+    admin::admin_server::AdminServer, auth::auth_server::AuthServer, user::user_server::UserServer,
+};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tonic::transport::Server;
 
+mod config;
+use config::Config;
+mod deny_list;
+use deny_list::DenyList;
+mod email;
+use email::Emails;
+mod federation;
+use federation::Federation;
 mod jwt;
+mod mailer;
+mod migrations;
 mod refresh_token;
 use refresh_token::RefreshToken;
+mod roles;
+use roles::Roles;
 mod invite;
+mod oauth2;
+mod oidc;
+use oidc::AuthorizationCodes;
+mod pat;
+use pat::PersonalAccessTokens;
+mod throttle;
+use throttle::Throttle;
+mod totp;
+use totp::Totp;
+mod users;
+use users::Users;
+mod well_known;
 
+mod password;
 mod services;
-
-const SALT: &str = "randomsalt";
+mod storage;
 
 pub fn get_now_plus(exp: u32) -> usize {
     SystemTime::now()
@@ -23,32 +49,133 @@ pub fn get_now_plus(exp: u32) -> usize {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let jwt = jwt::Jwt::new();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if config.jwt.signing_key.is_none() && config.jwt.secret == config::JwtConfig::default().secret
+    {
+        eprintln!("Warning: using the default jwt secret, set jwt.secret or ANAPP_JWT_SECRET");
+    }
+    let hash_config = config.argon2.to_argon2();
+
+    let db: sled::Db = sled::open(&config.db_path).expect("cannot open the database");
+    match migrations::run(&db) {
+        Ok(applied) => applied.iter().for_each(|step| println!("{}", step)),
+        Err(e) => {
+            eprintln!("Cannot migrate the database: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let deny_list_db = db
+        .open_tree("deny_list")
+        .expect("cannot open the deny_list database");
+    let deny_list = DenyList::new(deny_list_db, config.jwt.access_token_duration);
+    tokio::spawn(deny_list.clone().sweep_every());
+
+    let storage = match storage::open(&config.storage, &db) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Cannot open the storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let users = Users::new(storage.users.clone());
+
+    let roles = Roles::new(users.clone(), config.admin.clone());
+
+    let pats_db = db
+        .open_tree("personal_access_tokens")
+        .expect("cannot open the personal_access_tokens database");
+    let pats = PersonalAccessTokens::new(pats_db, roles.clone());
+
+    let jwt = match jwt::Jwt::new(&config.jwt, deny_list.clone(), pats.clone()) {
+        Ok(jwt) => jwt,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let refresh_token = RefreshToken::new(
+        storage.refresh_tokens.clone(),
+        config.jwt.refresh_token_duration,
+        config.jwt.refresh_token_idle_timeout,
+        deny_list.clone(),
+    );
+    tokio::spawn(refresh_token.clone().sweep_every(Duration::from_secs(
+        config.jwt.refresh_token_sweep_interval as u64,
+    )));
 
-    let db: sled::Db = sled::open("my_db").expect("cannot open the database");
+    let login_attempts_db = db
+        .open_tree("login_attempts")
+        .expect("cannot open the login_attempts database");
+    let throttle = Throttle::new(login_attempts_db, config.throttle.clone());
+    tokio::spawn(
+        throttle
+            .clone()
+            .sweep_every(Duration::from_secs(config.throttle.sweep_interval as u64)),
+    );
 
-    let invites_db = db
-        .open_tree("invites")
-        .expect("cannot open the invite database");
+    let mfa_challenges_db = db
+        .open_tree("mfa_challenges")
+        .expect("cannot open the mfa_challenges database");
+    let totp = Totp::new(users.clone(), mfa_challenges_db);
+    tokio::spawn(totp.clone().sweep_every());
 
-    let users_db = db
-        .open_tree("users")
-        .expect("cannot open the users database");
+    let authorization_codes_db = db
+        .open_tree("authorization_codes")
+        .expect("cannot open the authorization_codes database");
+    let authorization_codes = AuthorizationCodes::new(authorization_codes_db);
+    tokio::spawn(authorization_codes.clone().sweep_every());
 
-    let refresh_token_db = db
-        .open_tree("users")
-        .expect("cannot open the refresh_token_db database");
-    let refresh_token = RefreshToken::new(refresh_token_db);
+    let federated_identities_db = db
+        .open_tree("federated_identities")
+        .expect("cannot open the federated_identities database");
+    let federated_logins_db = db
+        .open_tree("federated_logins")
+        .expect("cannot open the federated_logins database");
+    let federation = Federation::new(
+        config.federation.providers.clone(),
+        federated_identities_db,
+        federated_logins_db,
+    );
+    tokio::spawn(federation.clone().sweep_every());
 
-    let tweb_config = tonic_web::config()
-        .allow_all_origins()
-        .allow_credentials(true)
-        .expose_headers(vec![
-            "x-request-id",
-            "content-type",
-            "x-grpc-web",
-            "x-user-agent",
-        ]);
+    let email_addresses_db = db
+        .open_tree("email_addresses")
+        .expect("cannot open the email_addresses database");
+    let email_tokens_db = db
+        .open_tree("email_tokens")
+        .expect("cannot open the email_tokens database");
+    let email_sent_db = db
+        .open_tree("email_sent")
+        .expect("cannot open the email_sent database");
+    let emails = Emails::new(
+        users.clone(),
+        email_addresses_db,
+        email_tokens_db,
+        email_sent_db,
+    );
+    tokio::spawn(emails.clone().sweep_every());
+    let mailer = mailer::from_config(&config.mail);
+
+    let tweb_config = if config.allowed_origins.is_empty() {
+        tonic_web::config().allow_all_origins()
+    } else {
+        tonic_web::config().allow_origins(config.allowed_origins.iter().map(String::as_str))
+    };
+    let tweb_config = tweb_config.allow_credentials(true).expose_headers(vec![
+        "x-request-id",
+        "content-type",
+        "x-grpc-web",
+        "x-user-agent",
+        "retry-after",
+    ]);
 
     // Server::builder()
     //     .accept_http1(true)
@@ -57,13 +184,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     .serve(addr)
     //     .await?;
     let auth_svc = AuthServer::new(services::auth::Service::new(
-        users_db.clone(),
+        users.clone(),
         jwt.clone(),
         refresh_token.clone(),
-        invites_db.clone(),
+        throttle.clone(),
+        totp.clone(),
+        roles.clone(),
+        federation.clone(),
+        emails.clone(),
+        mailer.clone(),
+        hash_config.clone(),
+        config.admin.clone(),
     ));
+    let well_known_svc = well_known::WellKnown::new(jwt.clone(), &config.issuer());
+    let oauth2_svc = oauth2::OAuth2::new(
+        jwt.clone(),
+        refresh_token.clone(),
+        deny_list,
+        throttle.clone(),
+        totp.clone(),
+        roles.clone(),
+        authorization_codes,
+        users.clone(),
+        config.issuer(),
+        config.oauth.clients.clone(),
+    );
+    let admin_svc = AdminServer::with_interceptor(
+        services::admin::Service::new(
+            users.clone(),
+            refresh_token.clone(),
+            pats.clone(),
+            throttle.clone(),
+            totp.clone(),
+            roles.clone(),
+            storage.invites.clone(),
+            federation.clone(),
+            emails.clone(),
+            hash_config.clone(),
+            config.admin.clone(),
+        ),
+        jwt.clone(),
+    );
     let user_svc = UserServer::with_interceptor(
-        services::user::Service::new(refresh_token, users_db, invites_db),
+        services::user::Service::new(
+            refresh_token,
+            pats,
+            throttle,
+            totp,
+            roles,
+            users,
+            storage.invites,
+            federation,
+            emails,
+            mailer,
+            hash_config,
+            config.admin.clone(),
+        ),
         jwt,
     );
     //let users_svc = HelloServer::with_interceptor(users::Service::new(users_db), check_auth);
@@ -72,8 +248,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .accept_http1(true)
         .add_service(tweb_config.enable(auth_svc))
         .add_service(tweb_config.enable(user_svc))
+        .add_service(tweb_config.enable(admin_svc))
+        .add_service(well_known_svc)
+        .add_service(oauth2_svc)
         // .add_service(echo_svc)
-        .serve("127.0.0.1:5051".parse().unwrap())
+        .serve(config.bind)
         .await?;
 
     Ok(())
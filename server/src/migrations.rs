@@ -0,0 +1,256 @@
+use proto::prost::Message;
+use proto::server::user::{TotpSecret, UserEmail, UserRecord, UserRoles};
+use sled::transaction::{TransactionError, Transactional};
+use std::convert::TryInto;
+
+// meta tree
+// key : "schema_version"
+// value : u32 big endian, absent -> 0 (before versioning)
+
+/*
+    The layout of the sled database is versioned. At startup every step above
+    the stored version runs in order, the version is written and flushed after
+    each one so an interrupted upgrade resumes at the step it was in. A step
+    has to be safe to run again on a half migrated database.
+    A database of a newer version is refused: this server would misread it.
+
+    New steps go at the end of MIGRATIONS, SCHEMA_VERSION follows.
+*/
+
+const VERSION_KEY: &str = "schema_version";
+pub const SCHEMA_VERSION: u32 = 4;
+
+struct Migration {
+    version: u32, // Reached once applied
+    description: &'static str,
+    run: fn(&sled::Db) -> Result<usize, String>, // Return how many entries changed
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "users stored as UserRecord instead of a raw argon2 hash",
+        run: user_records,
+    },
+    Migration {
+        version: 2,
+        description: "refresh tokens moved out of the users tree",
+        run: refresh_tokens_tree,
+    },
+    Migration {
+        version: 3,
+        description: "refresh token owners indexed by token",
+        run: refresh_token_owners,
+    },
+    Migration {
+        version: 4,
+        description: "roles, disabled flag, email and TOTP secret moved into the UserRecord",
+        run: user_fields,
+    },
+];
+
+fn db_error(e: sled::Error) -> String {
+    format!("database error {}", e)
+}
+
+pub fn version(db: &sled::Db) -> Result<u32, String> {
+    let meta = db.open_tree("meta").map_err(db_error)?;
+    match meta.get(VERSION_KEY).map_err(db_error)? {
+        Some(value) => {
+            let bytes: [u8; 4] = value
+                .as_ref()
+                .try_into()
+                .map_err(|_| "Malformated schema version".to_string())?;
+            Ok(u32::from_be_bytes(bytes))
+        }
+        None => Ok(0),
+    }
+}
+
+fn set_version(db: &sled::Db, version: u32) -> Result<(), String> {
+    let meta = db.open_tree("meta").map_err(db_error)?;
+    meta.insert(VERSION_KEY, &version.to_be_bytes())
+        .map_err(db_error)?;
+    db.flush().map_err(db_error)?;
+    Ok(())
+}
+
+// Bring the database to SCHEMA_VERSION, return a line per step applied
+pub fn run(db: &sled::Db) -> Result<Vec<String>, String> {
+    let current = version(db)?;
+    if current > SCHEMA_VERSION {
+        return Err(format!(
+            "the database schema is version {}, this server only knows up to {}, upgrade the server",
+            current, SCHEMA_VERSION
+        ));
+    }
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let changed = (migration.run)(db)
+            .map_err(|e| format!("migration to version {}: {}", migration.version, e))?;
+        set_version(db, migration.version)?;
+        applied.push(format!(
+            "Database schema version {}: {} ({} entries)",
+            migration.version, migration.description, changed
+        ));
+    }
+    Ok(applied)
+}
+
+// Before UserRecord the value was the argon2 encoded hash alone
+fn user_records(db: &sled::Db) -> Result<usize, String> {
+    let users = db.open_tree("users").map_err(db_error)?;
+    let mut migrated = 0;
+    for entry in users.iter() {
+        let (key, value) = entry.map_err(db_error)?;
+        // Refresh tokens shared the tree as "username:token"
+        if key.contains(&b':') || !value.starts_with(b"$argon2") {
+            continue;
+        }
+        let record = UserRecord {
+            version: 1,
+            password_hash: String::from_utf8_lossy(&value).to_string(),
+            ..UserRecord::default()
+        };
+        let swapped = users
+            .compare_and_swap(&key, Some(value), Some(record.encode_to_vec()))
+            .map_err(db_error)?;
+        if swapped.is_ok() {
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+// The refresh tokens were opened on the users tree as "username:token",
+// usernames cannot contain ':'
+fn refresh_tokens_tree(db: &sled::Db) -> Result<usize, String> {
+    let users = db.open_tree("users").map_err(db_error)?;
+    let refresh_tokens = db.open_tree("refresh_tokens").map_err(db_error)?;
+    let mut moved = 0;
+    for entry in users.iter() {
+        let (key, value) = entry.map_err(db_error)?;
+        if !key.contains(&b':') {
+            continue;
+        }
+        let res = (&users, &refresh_tokens).transaction(|(users, refresh_tokens)| {
+            refresh_tokens.insert(&key, &value)?;
+            users.remove(&key)?;
+            Ok(())
+        });
+        match res {
+            Ok(()) => moved += 1,
+            Err(TransactionError::Abort(())) => {}
+            Err(TransactionError::Storage(e)) => return Err(db_error(e)),
+        }
+    }
+    Ok(moved)
+}
+
+// Keys are "username:token", the index maps the token back to the username
+fn refresh_token_owners(db: &sled::Db) -> Result<usize, String> {
+    let refresh_tokens = db.open_tree("refresh_tokens").map_err(db_error)?;
+    let owners = db.open_tree("refresh_token_owners").map_err(db_error)?;
+    let mut indexed = 0;
+    for entry in refresh_tokens.iter() {
+        let (key, _) = entry.map_err(db_error)?;
+        let key = String::from_utf8_lossy(&key).to_string();
+        if let Some((username, token)) = key.split_once(':') {
+            owners.insert(token, username).map_err(db_error)?;
+            indexed += 1;
+        }
+    }
+    Ok(indexed)
+}
+
+// Trees of the user fields before UserRecord version 2, all keyed by username
+const USER_FIELD_TREES: [&str; 4] = ["roles", "disabled", "emails", "totp"];
+
+/*
+    The fields are copied into the records of the backend in use: the sled
+    records here, the SQLite rows by its upgrade to version 3. The trees are
+    dropped once the storage is open, see storage::open.
+*/
+pub struct UserFieldTrees {
+    roles: sled::Tree,
+    disabled: sled::Tree,
+    emails: sled::Tree,
+    totp: sled::Tree,
+}
+
+impl UserFieldTrees {
+    // None once dropped
+    pub fn open(db: &sled::Db) -> Result<Option<Self>, String> {
+        let names = db.tree_names();
+        if !USER_FIELD_TREES
+            .iter()
+            .any(|name| names.iter().any(|n| n == name.as_bytes()))
+        {
+            return Ok(None);
+        }
+        let tree = |name: &str| db.open_tree(name).map_err(db_error);
+        Ok(Some(Self {
+            roles: tree("roles")?,
+            disabled: tree("disabled")?,
+            emails: tree("emails")?,
+            totp: tree("totp")?,
+        }))
+    }
+
+    // Copy the fields of the user into the record at version 2, return true
+    // if the trees had any
+    pub fn fold(&self, username: &str, record: &mut UserRecord) -> Result<bool, String> {
+        let mut found = false;
+        if let Some(value) = self.roles.get(username).map_err(db_error)? {
+            record.roles = UserRoles::decode(value.as_ref())
+                .map(|roles| roles.roles)
+                .unwrap_or_default();
+            found = true;
+        }
+        if self.disabled.contains_key(username).map_err(db_error)? {
+            record.disabled = true;
+            found = true;
+        }
+        if let Some(value) = self.emails.get(username).map_err(db_error)? {
+            record.email = UserEmail::decode(value.as_ref()).ok();
+            found = true;
+        }
+        if let Some(value) = self.totp.get(username).map_err(db_error)? {
+            record.totp = TotpSecret::decode(value.as_ref()).ok();
+            found = true;
+        }
+        record.version = 2;
+        Ok(found)
+    }
+}
+
+pub fn drop_user_field_trees(db: &sled::Db) -> Result<(), String> {
+    for name in USER_FIELD_TREES {
+        db.drop_tree(name).map_err(db_error)?;
+    }
+    Ok(())
+}
+
+// Users absent from the sled records (SQLite backend) are left to SQLite
+fn user_fields(db: &sled::Db) -> Result<usize, String> {
+    let trees = match UserFieldTrees::open(db)? {
+        Some(trees) => trees,
+        None => return Ok(0),
+    };
+    let users = db.open_tree("users").map_err(db_error)?;
+    let mut moved = 0;
+    for entry in users.iter() {
+        let (key, value) = entry.map_err(db_error)?;
+        let mut record = match UserRecord::decode(value.as_ref()) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if trees.fold(&String::from_utf8_lossy(&key), &mut record)? {
+            moved += 1;
+        }
+        users
+            .insert(&key, record.encode_to_vec())
+            .map_err(db_error)?;
+    }
+    Ok(moved)
+}
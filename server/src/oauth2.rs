@@ -0,0 +1,649 @@
+use futures::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderValue, AUTHORIZATION, CACHE_CONTROL, LOCATION, USER_AGENT, WWW_AUTHENTICATE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use proto::server::user::AuthorizationCode;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::Service;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::transport::NamedService;
+use tonic::Status;
+
+use crate::config::OAuthClientConfig;
+use crate::deny_list::DenyList;
+use crate::get_now_plus;
+use crate::jwt::{IdTokenClaims, Jwt};
+use crate::oidc::{self, AuthorizationCodes};
+use crate::refresh_token::{Device, RefreshToken};
+use crate::roles::Roles;
+use crate::throttle::Throttle;
+use crate::totp::Totp;
+use crate::users::Users;
+
+/*
+    Plain http endpoints for the other services, routed by tonic on "/oauth2":
+    - POST /oauth2/introspect: RFC 7662, is a token still valid and whose
+    - POST /oauth2/revoke: RFC 7009, end a token issued to the client before
+    its expiration
+    Both take a form with "token" and understand access tokens, refresh tokens
+    and personal access tokens, whatever the token_type_hint.
+    Callers authenticate as one of oauth.clients, with HTTP Basic or the
+    client_id and client_secret form fields.
+
+    OpenID Connect provider, authorization code flow with PKCE (S256) only:
+    - GET /oauth2/authorize: login page of the clients with redirect_uris
+    - POST /oauth2/authorize: the posted login page, redirect with a code
+    - POST /oauth2/token: code or refresh token -> access, refresh and ID tokens
+    - GET|POST /oauth2/userinfo: claims of a Bearer access token
+    Each login opens a session bound to the client, listed with the others.
+    Its access tokens are limited to the client, see jwt.rs: only userinfo and
+    the introspection accept them.
+*/
+
+const MAX_BODY_LEN: usize = 8 * 1024;
+
+#[derive(Clone)]
+pub struct OAuth2 {
+    jwt: Jwt,
+    refresh_token: RefreshToken,
+    deny_list: DenyList,
+    throttle: Throttle,
+    totp: Totp,
+    roles: Roles,
+    codes: AuthorizationCodes,
+    users: Users,
+    issuer: Arc<str>,
+    clients: Arc<Vec<OAuthClientConfig>>,
+}
+
+// A bad client or redirect uri is shown to the user, nowhere safe to redirect
+enum AuthorizeError {
+    Page(&'static str),
+    Redirect(&'static str, &'static str), /* error, error_description */
+}
+
+fn response(status: StatusCode, body: Value) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(
+            Body::from(body.to_string())
+                .map_err(|e| Status::internal(e.to_string()))
+                .boxed(),
+        )
+        .unwrap()
+}
+
+fn html(status: StatusCode, body: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/html; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store")
+        .header("x-frame-options", "DENY")
+        .header(
+            "content-security-policy",
+            "default-src 'none'; style-src 'unsafe-inline'; frame-ancestors 'none'",
+        )
+        .body(
+            Body::from(body)
+                .map_err(|e| Status::internal(e.to_string()))
+                .boxed(),
+        )
+        .unwrap()
+}
+
+fn redirect(uri: &str, params: &[(&str, &str)]) -> Response<BoxBody> {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, format!("{}{}{}", uri, separator, query))
+        .header(CACHE_CONTROL, "no-store")
+        .body(
+            Body::empty()
+                .map_err(|e| Status::internal(e.to_string()))
+                .boxed(),
+        )
+        .unwrap()
+}
+
+// RFC 6749 section 5.2
+fn error(status: StatusCode, error: &str) -> Response<BoxBody> {
+    let mut response = response(status, json!({ "error": error }));
+    if status == StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic"));
+    }
+    response
+}
+
+// None if the body is too long or cannot be read
+async fn read_form(body: &mut Body) -> Option<HashMap<String, String>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.ok()?);
+        if bytes.len() > MAX_BODY_LEN {
+            return None;
+        }
+    }
+    Some(form_urlencoded::parse(&bytes).into_owned().collect())
+}
+
+// "Basic base64(id:secret)"
+fn basic_credentials(header: &HeaderValue) -> Option<(String, String)> {
+    let encoded = header.to_str().ok()?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((id.to_string(), secret.to_string()))
+}
+
+fn param<'a>(params: &'a HashMap<String, String>, name: &str) -> &'a str {
+    params.get(name).map_or("", String::as_str)
+}
+
+impl OAuth2 {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        jwt: Jwt,
+        refresh_token: RefreshToken,
+        deny_list: DenyList,
+        throttle: Throttle,
+        totp: Totp,
+        roles: Roles,
+        codes: AuthorizationCodes,
+        users: Users,
+        issuer: String,
+        clients: Vec<OAuthClientConfig>,
+    ) -> Self {
+        Self {
+            jwt,
+            refresh_token,
+            deny_list,
+            throttle,
+            totp,
+            roles,
+            codes,
+            users,
+            issuer: issuer.into(),
+            clients: Arc::new(clients),
+        }
+    }
+
+    // Public clients have no secret, only accepted by the token endpoint
+    fn authenticate_client(
+        &self,
+        request: &Request<Body>,
+        form: &HashMap<String, String>,
+        allow_public: bool,
+    ) -> Option<&OAuthClientConfig> {
+        let (id, secret) = match request.headers().get(AUTHORIZATION) {
+            Some(header) => basic_credentials(header)?,
+            None => (
+                form.get("client_id")?.clone(),
+                param(form, "client_secret").to_string(),
+            ),
+        };
+        let client = self.clients.iter().find(|client| client.id == id)?;
+        if client.secret.is_empty() {
+            return (allow_public && secret.is_empty()).then_some(client);
+        }
+        // Compare hashes, a timing difference tells nothing about the secret
+        (Sha256::digest(client.secret.as_bytes()) == Sha256::digest(secret.as_bytes()))
+            .then_some(client)
+    }
+
+    fn introspect(&self, token: &str) -> Value {
+        if let Ok((claims, scopes)) = self.jwt.authenticate_any(token) {
+            let mut value = json!({
+                "active": true,
+                "sub": claims.sub,
+                "username": claims.sub,
+                "exp": claims.exp,
+                "jti": claims.jti,
+                "roles": claims.roles,
+            });
+            match scopes {
+                Some(scopes) => {
+                    value["token_type"] = json!("personal_access_token");
+                    value["scope"] = json!(scopes.0.join(" "));
+                    if claims.exp == u32::MAX as usize {
+                        // Never expires
+                        value.as_object_mut().unwrap().remove("exp");
+                    }
+                }
+                None => {
+                    value["token_type"] = json!("access_token");
+                    value["sid"] = json!(claims.sid);
+                    if !claims.aud.is_empty() {
+                        value["client_id"] = json!(claims.aud);
+                        value["aud"] = json!(claims.aud);
+                        value["scope"] = json!(claims.scope);
+                    }
+                }
+            }
+            return value;
+        }
+        match self.refresh_token.find(token) {
+            Some((username, token)) if self.refresh_token.is_active(&token) => {
+                let mut value = json!({
+                    "active": true,
+                    "sub": username,
+                    "username": username,
+                    "exp": token.expiration_date,
+                    "iat": token.creation_date,
+                    "token_type": "refresh_token",
+                    "sid": token.family,
+                });
+                if !token.client_id.is_empty() {
+                    value["client_id"] = json!(token.client_id);
+                    value["scope"] = json!(token.scope);
+                }
+                value
+            }
+            _ => json!({ "active": false }),
+        }
+    }
+
+    /*
+        Only the tokens issued to the client, the others are ignored like the
+        unknown and already revoked ones: not an error (RFC 7009 section 2.2).
+        Personal access tokens and the sessions of this app are issued to no
+        client, they are revoked with the User service.
+    */
+    fn revoke(&self, client: &OAuthClientConfig, token: &str) {
+        if let Ok((claims, None)) = self.jwt.authenticate_any(token) {
+            if claims.aud == client.id {
+                self.deny_list.deny(&claims.jti);
+            }
+        } else if let Some((username, session)) = self.refresh_token.find(token) {
+            if session.client_id == client.id {
+                self.refresh_token.delete(&username, token);
+            }
+        }
+    }
+
+    // Return the client and the granted scope
+    fn check_authorization(
+        &self,
+        params: &HashMap<String, String>,
+    ) -> Result<(&OAuthClientConfig, String), AuthorizeError> {
+        let client = self
+            .clients
+            .iter()
+            .find(|client| client.id == param(params, "client_id"))
+            .filter(|client| !client.redirect_uris.is_empty())
+            .ok_or(AuthorizeError::Page("Unknown client"))?;
+        if !client
+            .redirect_uris
+            .iter()
+            .any(|uri| uri == param(params, "redirect_uri"))
+        {
+            return Err(AuthorizeError::Page("Invalid redirect uri"));
+        }
+        if param(params, "response_type") != "code" {
+            return Err(AuthorizeError::Redirect(
+                "unsupported_response_type",
+                "Only the code response type is supported",
+            ));
+        }
+        let scope = oidc::granted_scope(param(params, "scope")).ok_or(AuthorizeError::Redirect(
+            "invalid_scope",
+            "The openid scope is required",
+        ))?;
+        // BASE64URL of a SHA-256
+        if param(params, "code_challenge_method") != "S256"
+            || param(params, "code_challenge").len() != 43
+        {
+            return Err(AuthorizeError::Redirect(
+                "invalid_request",
+                "PKCE with the S256 method is required",
+            ));
+        }
+        Ok((client, scope))
+    }
+
+    fn authorize_error(
+        &self,
+        params: &HashMap<String, String>,
+        e: AuthorizeError,
+    ) -> Response<BoxBody> {
+        match e {
+            AuthorizeError::Page(message) => {
+                html(StatusCode::BAD_REQUEST, oidc::error_page(message))
+            }
+            AuthorizeError::Redirect(error, description) => {
+                let mut query = vec![("error", error), ("error_description", description)];
+                if let Some(state) = params.get("state") {
+                    query.push(("state", state));
+                }
+                redirect(param(params, "redirect_uri"), &query)
+            }
+        }
+    }
+
+    fn authorize_page(&self, request: &Request<Body>) -> Response<BoxBody> {
+        let params: HashMap<String, String> =
+            form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect();
+        match self.check_authorization(&params) {
+            Ok((client, _)) => html(
+                StatusCode::OK,
+                oidc::login_page(client_name(client), &params, "", ""),
+            ),
+            Err(e) => self.authorize_error(&params, e),
+        }
+    }
+
+    // The same checks as the Auth service login, with the second factor in the same form
+    fn login(
+        &self,
+        form: &HashMap<String, String>,
+        ip: Option<std::net::IpAddr>,
+    ) -> Result<(), String> {
+        let username = param(form, "username");
+        let too_many_attempts =
+            |retry_after| format!("Too many attempts, retry in {} seconds", retry_after);
+        self.throttle
+            .check(username, ip)
+            .map_err(too_many_attempts)?;
+        let valid = username.len() >= 3
+            && self
+                .users
+                .check_password(username, param(form, "password"))
+                .is_some()
+            && (!self.totp.is_enabled(username)
+                || self
+                    .totp
+                    .verify_second_factor(username, param(form, "code")));
+        if !valid {
+            return Err(match self.throttle.failed(username, ip) {
+                Some(retry_after) => too_many_attempts(retry_after),
+                None => "Invalid username, password or code".to_string(),
+            });
+        }
+        if self.users.is_disabled(username) {
+            return Err("Account disabled".to_string());
+        }
+        self.throttle.succeeded(username);
+        self.users.record_login(username);
+        Ok(())
+    }
+
+    async fn authorize(&self, mut request: Request<Body>) -> Response<BoxBody> {
+        let form = match read_form(request.body_mut()).await {
+            Some(form) => form,
+            None => return html(StatusCode::BAD_REQUEST, oidc::error_page("Invalid request")),
+        };
+        let (client, scope) = match self.check_authorization(&form) {
+            Ok(checked) => checked,
+            Err(e) => return self.authorize_error(&form, e),
+        };
+        let redirect_uri = param(&form, "redirect_uri");
+        let code = if param(&form, "action") == "deny" {
+            Err("access_denied")
+        } else {
+            let ip = request
+                .extensions()
+                .get::<TcpConnectInfo>()
+                .and_then(|info| info.remote_addr())
+                .map(|addr| addr.ip());
+            if let Err(e) = self.login(&form, ip) {
+                let username = param(&form, "username");
+                return html(
+                    StatusCode::OK,
+                    oidc::login_page(client_name(client), &form, username, &e),
+                );
+            }
+            let user_agent = request
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .unwrap_or_default();
+            self.codes
+                .create(AuthorizationCode {
+                    client_id: client.id.clone(),
+                    redirect_uri: redirect_uri.to_string(),
+                    username: param(&form, "username").to_string(),
+                    scope,
+                    nonce: param(&form, "nonce").to_string(),
+                    code_challenge: param(&form, "code_challenge").to_string(),
+                    expiration_date: 0,
+                    auth_time: get_now_plus(0) as u32,
+                    from: ip.map_or("unknown".to_string(), |ip| ip.to_string()),
+                    user_agent: user_agent.chars().take(256).collect(),
+                })
+                .map_err(|_| "server_error")
+        };
+        let mut query = match &code {
+            Ok(code) => vec![("code", code.as_str())],
+            Err(error) => vec![("error", *error)],
+        };
+        if let Some(state) = form.get("state") {
+            query.push(("state", state));
+        }
+        query.push(("iss", &self.issuer));
+        redirect(redirect_uri, &query)
+    }
+
+    // Access, refresh and ID tokens of a new session of the client
+    fn exchange_code(
+        &self,
+        client: &OAuthClientConfig,
+        form: &HashMap<String, String>,
+    ) -> Response<BoxBody> {
+        let code = match self.codes.take(param(form, "code")) {
+            Some(code)
+                if code.client_id == client.id
+                    && code.redirect_uri == param(form, "redirect_uri")
+                    && oidc::verify_pkce(param(form, "code_verifier"), &code.code_challenge) =>
+            {
+                code
+            }
+            _ => return error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        };
+        if self.users.is_disabled(&code.username) {
+            return error(StatusCode::BAD_REQUEST, "invalid_grant");
+        }
+        let profile = has_scope(&code.scope, "profile");
+        let preferred_username = profile.then(|| code.username.clone());
+        let device = Device {
+            from: code.from,
+            user_agent: code.user_agent,
+            device_name: client_name(client).to_string(),
+            client_id: client.id.clone(),
+            scope: code.scope.clone(),
+        };
+        let (refresh_token, session) = self.refresh_token.new_token(&code.username, device);
+        let id_token = self.jwt.create_id_token(&IdTokenClaims {
+            iss: self.issuer.to_string(),
+            sub: code.username.clone(),
+            aud: client.id.clone(),
+            exp: self.jwt.get_exp() as usize,
+            iat: get_now_plus(0),
+            auth_time: code.auth_time as usize,
+            nonce: code.nonce,
+            preferred_username,
+        });
+        let mut value = self.tokens(client, &code.username, &session, &code.scope, refresh_token);
+        value["id_token"] = json!(id_token);
+        response(StatusCode::OK, value)
+    }
+
+    // Rotate a refresh token of the client's sessions
+    fn refresh(
+        &self,
+        client: &OAuthClientConfig,
+        form: &HashMap<String, String>,
+    ) -> Response<BoxBody> {
+        let token = param(form, "refresh_token");
+        let (username, scope) = match self.refresh_token.find(token) {
+            Some((username, session)) if session.client_id == client.id => {
+                (username, session.scope)
+            }
+            _ => return error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        };
+        if self.users.is_disabled(&username) {
+            return error(StatusCode::BAD_REQUEST, "invalid_grant");
+        }
+        match self.refresh_token.rotate(&username, token) {
+            Ok((refresh_token, session)) => response(
+                StatusCode::OK,
+                self.tokens(client, &username, &session, &scope, refresh_token),
+            ),
+            Err(_) => error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        }
+    }
+
+    // The access token is only for the client, with the roles if granted
+    fn tokens(
+        &self,
+        client: &OAuthClientConfig,
+        username: &str,
+        session: &str,
+        scope: &str,
+        refresh_token: String,
+    ) -> Value {
+        let roles = if has_scope(scope, "roles") {
+            self.roles.get(username)
+        } else {
+            Vec::new()
+        };
+        json!({
+            "access_token": self.jwt.create_client_token(username, roles, session, &client.id, scope),
+            "token_type": "Bearer",
+            "expires_in": self.jwt.get_exp() - get_now_plus(0) as u32,
+            "refresh_token": refresh_token,
+            "scope": scope,
+        })
+    }
+
+    async fn token(&self, mut request: Request<Body>) -> Response<BoxBody> {
+        let form = match read_form(request.body_mut()).await {
+            Some(form) => form,
+            None => return error(StatusCode::BAD_REQUEST, "invalid_request"),
+        };
+        let client = match self.authenticate_client(&request, &form, true) {
+            Some(client) => client,
+            None => return error(StatusCode::UNAUTHORIZED, "invalid_client"),
+        };
+        match param(&form, "grant_type") {
+            "authorization_code" => self.exchange_code(client, &form),
+            "refresh_token" => self.refresh(client, &form),
+            _ => error(StatusCode::BAD_REQUEST, "unsupported_grant_type"),
+        }
+    }
+
+    // Access tokens only, in the Authorization header (RFC 6750)
+    fn userinfo(&self, request: &Request<Body>) -> Response<BoxBody> {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token.map(|token| self.jwt.authenticate_any(token)) {
+            Some(Ok((claims, None))) => {
+                // The tokens of this app are not limited by a scope
+                let all = claims.aud.is_empty();
+                let mut value = json!({ "sub": claims.sub });
+                if all || has_scope(&claims.scope, "profile") {
+                    value["preferred_username"] = json!(claims.sub);
+                }
+                if all || has_scope(&claims.scope, "roles") {
+                    value["roles"] = json!(claims.roles);
+                }
+                response(StatusCode::OK, value)
+            }
+            _ => {
+                let mut response = error(StatusCode::UNAUTHORIZED, "invalid_token");
+                response.headers_mut().insert(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer error=\"invalid_token\""),
+                );
+                response
+            }
+        }
+    }
+
+    async fn handle(self, mut request: Request<Body>) -> Response<BoxBody> {
+        let path = request.uri().path().to_string();
+        let method = request.method().clone();
+        match (path.as_str(), method) {
+            ("/oauth2/authorize", Method::GET) => self.authorize_page(&request),
+            ("/oauth2/authorize", Method::POST) => self.authorize(request).await,
+            ("/oauth2/token", Method::POST) => self.token(request).await,
+            ("/oauth2/userinfo", Method::GET) | ("/oauth2/userinfo", Method::POST) => {
+                self.userinfo(&request)
+            }
+            ("/oauth2/introspect", Method::POST) | ("/oauth2/revoke", Method::POST) => {
+                let form = match read_form(request.body_mut()).await {
+                    Some(form) => form,
+                    None => return error(StatusCode::BAD_REQUEST, "invalid_request"),
+                };
+                let client = match self.authenticate_client(&request, &form, false) {
+                    Some(client) => client,
+                    None => return error(StatusCode::UNAUTHORIZED, "invalid_client"),
+                };
+                let token = match form.get("token") {
+                    Some(token) => token,
+                    None => return error(StatusCode::BAD_REQUEST, "invalid_request"),
+                };
+                if path == "/oauth2/introspect" {
+                    response(StatusCode::OK, self.introspect(token))
+                } else {
+                    self.revoke(client, token);
+                    response(StatusCode::OK, json!({}))
+                }
+            }
+            (
+                "/oauth2/authorize" | "/oauth2/token" | "/oauth2/userinfo" | "/oauth2/introspect"
+                | "/oauth2/revoke",
+                _,
+            ) => error(StatusCode::METHOD_NOT_ALLOWED, "invalid_request"),
+            _ => error(StatusCode::NOT_FOUND, "not_found"),
+        }
+    }
+}
+
+impl NamedService for OAuth2 {
+    const NAME: &'static str = "oauth2";
+}
+
+impl Service<Request<Body>> for OAuth2 {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let oauth2 = self.clone();
+        Box::pin(async move { Ok(oauth2.handle(request).await) })
+    }
+}
+
+fn has_scope(scope: &str, name: &str) -> bool {
+    scope.split(' ').any(|granted| granted == name)
+}
+
+fn client_name(client: &OAuthClientConfig) -> &str {
+    if client.name.is_empty() {
+        &client.id
+    } else {
+        &client.name
+    }
+}
@@ -0,0 +1,167 @@
+use crate::get_now_plus;
+use proto::prost::Message;
+use proto::server::user::AuthorizationCode;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::iter;
+use std::time::Duration;
+
+// key : "[code]"
+// value : AuthorizationCode protobuf
+
+/*
+    OpenID Connect provider pieces used by the /oauth2 endpoints:
+    authorization codes, PKCE and the login page.
+    A code is given to the client's redirect uri after the login and exchanged
+    once, before CODE_DURATION, by /oauth2/token against a session.
+*/
+
+const CODE_DURATION: u32 = 60; /* seconds */
+const CODE_LEN: usize = 32;
+const LOGIN_PAGE: &str = include_str!("../static/authorize.html");
+
+// Parameters of /oauth2/authorize, kept in the login page until it is posted
+pub const AUTHORIZATION_PARAMS: [&str; 8] = [
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "nonce",
+    "code_challenge",
+    "code_challenge_method",
+];
+
+// roles: the roles of the user in the access token and the userinfo
+pub const SCOPES: [&str; 3] = ["openid", "profile", "roles"];
+
+// The supported scopes of a request, None without openid
+pub fn granted_scope(scope: &str) -> Option<String> {
+    let requested: Vec<&str> = scope.split(' ').collect();
+    if !requested.contains(&"openid") {
+        return None;
+    }
+    let granted: Vec<&str> = SCOPES
+        .iter()
+        .copied()
+        .filter(|s| requested.contains(s))
+        .collect();
+    Some(granted.join(" "))
+}
+
+// RFC 7636, only S256: BASE64URL(SHA256(verifier)) == challenge
+pub fn verify_pkce(verifier: &str, challenge: &str) -> bool {
+    let valid = (43..=128).contains(&verifier.len())
+        && verifier
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"-._~".contains(&c));
+    valid
+        && base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+            == challenge
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+pub fn login_page(
+    client_name: &str,
+    params: &HashMap<String, String>,
+    username: &str,
+    error: &str,
+) -> String {
+    let hidden: String = AUTHORIZATION_PARAMS
+        .iter()
+        .filter_map(|name| params.get(*name).map(|value| (name, value)))
+        .map(|(name, value)| {
+            format!(
+                "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+                name,
+                escape(value)
+            )
+        })
+        .collect();
+    LOGIN_PAGE
+        .replace("{client}", &escape(client_name))
+        .replace("{error}", &escape(error))
+        .replace("{username}", &escape(username))
+        .replace("{params}", &hidden)
+}
+
+pub fn error_page(message: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Error</title></head>\
+        <body><h1>{}</h1></body></html>",
+        escape(message)
+    )
+}
+
+#[derive(Clone)]
+pub struct AuthorizationCodes {
+    db: sled::Tree,
+}
+
+impl AuthorizationCodes {
+    pub fn new(db: sled::Tree) -> Self {
+        Self { db }
+    }
+
+    // expiration_date is set here
+    pub fn create(&self, mut code: AuthorizationCode) -> Result<String, String> {
+        let mut rng = thread_rng();
+        let key: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(CODE_LEN)
+            .collect();
+        code.expiration_date = get_now_plus(CODE_DURATION) as u32;
+        self.db
+            .insert(&key, code.encode_to_vec())
+            .map_err(|e| e.to_string())?;
+        Ok(key)
+    }
+
+    // Single use: removed even if the exchange fails afterward
+    pub fn take(&self, key: &str) -> Option<AuthorizationCode> {
+        let code = match self.db.remove(key) {
+            Ok(Some(value)) => AuthorizationCode::decode(value.as_ref()).ok()?,
+            _ => return None,
+        };
+        if code.expiration_date < get_now_plus(0) as u32 {
+            return None;
+        }
+        Some(code)
+    }
+
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let expired: Vec<sled::IVec> = self
+            .db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                AuthorizationCode::decode(value.as_ref())
+                    .map_or(true, |code| code.expiration_date < now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        expired
+            .into_iter()
+            .filter(|key| matches!(self.db.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(CODE_DURATION as u64 * 10));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
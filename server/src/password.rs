@@ -0,0 +1,45 @@
+use rand::RngCore;
+
+// Encoded hash: $argon2i$v=19$m=4096,t=3,p=1$[salt.base64]$[hash.base64]
+
+const SALT_LEN: usize = 16;
+
+pub fn hash(password: &str, config: &argon2::Config) -> Result<String, String> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    argon2::hash_encoded(password.as_bytes(), &salt, config)
+        .map_err(|_| "Unknown error when hashing your password".to_string())
+}
+
+pub fn verify(encoded: &[u8], password: &str) -> bool {
+    match std::str::from_utf8(encoded) {
+        Ok(encoded) => argon2::verify_encoded(encoded, password.as_bytes()) == Ok(true),
+        Err(_) => false,
+    }
+}
+
+// Hashed with other parameters than the current ones, or with the old global salt
+pub fn needs_rehash(encoded: &[u8], config: &argon2::Config) -> bool {
+    let encoded = match std::str::from_utf8(encoded) {
+        Ok(encoded) => encoded,
+        Err(_) => return true,
+    };
+    let expected_params = format!(
+        "m={},t={},p={}",
+        config.mem_cost, config.time_cost, config.lanes
+    );
+    match encoded.split('$').collect::<Vec<_>>().as_slice() {
+        ["", variant, version, params, salt, hash] => {
+            let salt_len =
+                base64::decode_config(salt, base64::STANDARD_NO_PAD).map_or(0, |s| s.len());
+            let hash_len =
+                base64::decode_config(hash, base64::STANDARD_NO_PAD).map_or(0, |h| h.len());
+            *variant != config.variant.as_lowercase_str()
+                || *version != format!("v={}", config.version.as_u32())
+                || *params != expected_params
+                || salt_len < SALT_LEN
+                || hash_len != config.hash_length as usize
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,232 @@
+use crate::get_now_plus;
+use crate::jwt::AccessTokenClaims;
+use crate::roles::Roles;
+use proto::prost::Message;
+use proto::server::user::{PersonalAccessToken, StoredPersonalAccessToken};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::iter;
+use tonic::{Code, Request, Status};
+
+// key : "[id]"
+// value : StoredPersonalAccessToken protobuf
+
+/*
+    Personal access tokens are long lived credentials for scripts: "pat_[id]_[secret]".
+    The id is public, only a SHA-256 of the random secret is stored.
+    They are accepted by the Jwt interceptor in place of an access token, with
+    the Scopes extension limiting the RPCs they can call. Their roles are only
+    the owner's current ones with the admin scope.
+*/
+
+pub const PREFIX: &str = "pat_";
+const ID_LEN: usize = 16;
+const SECRET_LEN: usize = 32;
+const MAX_NAME_LEN: usize = 64;
+const LAST_USE_PRECISION: u32 = 60; /* seconds, not to write on every call */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    SessionsRead,
+    SessionsWrite,
+    InvitesRead,
+    InvitesWrite,
+    Admin,
+    Account, // Password, 2FA and tokens, never given to a personal access token
+}
+
+impl Scope {
+    fn name(self) -> &'static str {
+        match self {
+            Scope::SessionsRead => "sessions:read",
+            Scope::SessionsWrite => "sessions:write",
+            Scope::InvitesRead => "invites:read",
+            Scope::InvitesWrite => "invites:write",
+            Scope::Admin => "admin",
+            Scope::Account => "account",
+        }
+    }
+}
+
+const GRANTABLE: [Scope; 5] = [
+    Scope::SessionsRead,
+    Scope::SessionsWrite,
+    Scope::InvitesRead,
+    Scope::InvitesWrite,
+    Scope::Admin,
+];
+
+// Scopes of the personal access token of the request, absent for access tokens
+#[derive(Debug, Clone)]
+pub struct Scopes(pub Vec<String>);
+
+// To call first in the RPCs behind the Jwt interceptor
+#[allow(clippy::result_large_err)]
+pub fn require_scope<T>(request: &Request<T>, scope: Scope) -> Result<(), Status> {
+    match request.extensions().get::<Scopes>() {
+        Some(Scopes(scopes)) if !scopes.iter().any(|s| s == scope.name()) => Err(Status::new(
+            Code::PermissionDenied,
+            format!("Missing the \"{}\" scope", scope.name()),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn random_string(len: usize) -> String {
+    let mut rng = thread_rng();
+    iter::repeat(())
+        .map(|()| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(len)
+        .collect()
+}
+
+// "pat_[id]_[secret]" -> (id, secret)
+fn split(token: &str) -> Option<(&str, &str)> {
+    let token = token.strip_prefix(PREFIX)?;
+    let (id, secret) = token.split_once('_')?;
+    if id.len() != ID_LEN || secret.len() != SECRET_LEN {
+        return None;
+    }
+    Some((id, secret))
+}
+
+#[derive(Clone)]
+pub struct PersonalAccessTokens {
+    db: sled::Tree,
+    roles: Roles,
+}
+
+impl PersonalAccessTokens {
+    pub fn new(db: sled::Tree, roles: Roles) -> Self {
+        Self { db, roles }
+    }
+
+    fn get(&self, id: &str) -> Option<StoredPersonalAccessToken> {
+        match self.db.get(id) {
+            Ok(Some(value)) => StoredPersonalAccessToken::decode(value.as_ref()).ok(),
+            _ => None,
+        }
+    }
+
+    // Return the full token, only shown once, and its public part
+    pub fn create(
+        &self,
+        username: &str,
+        name: &str,
+        scopes: Vec<String>,
+        expiration_date: u32,
+    ) -> Result<(String, PersonalAccessToken), String> {
+        let now = get_now_plus(0) as u32;
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(format!(
+                "The name should be 1 to {} characters long",
+                MAX_NAME_LEN
+            ));
+        }
+        if scopes.is_empty() {
+            return Err("At least one scope is needed".to_string());
+        }
+        if let Some(scope) = scopes
+            .iter()
+            .find(|s| !GRANTABLE.iter().any(|g| g.name() == s.as_str()))
+        {
+            return Err(format!("Unknown scope \"{}\"", scope));
+        }
+        if expiration_date != 0 && expiration_date <= now {
+            return Err("Expiration date in the past".to_string());
+        }
+        let id = random_string(ID_LEN);
+        let secret = random_string(SECRET_LEN);
+        let token = PersonalAccessToken {
+            id: id.clone(),
+            name: name.to_string(),
+            scopes,
+            creation_date: now,
+            expiration_date,
+            last_use: 0,
+        };
+        let stored = StoredPersonalAccessToken {
+            username: username.to_string(),
+            hash: Sha256::digest(secret.as_bytes()).to_vec(),
+            token: Some(token.clone()),
+        };
+        self.db
+            .insert(&id, stored.encode_to_vec())
+            .map_err(|e| e.to_string())?;
+        Ok((format!("{}{}_{}", PREFIX, id, secret), token))
+    }
+
+    pub fn get_all(&self, username: &str) -> Vec<PersonalAccessToken> {
+        self.db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(_, value)| StoredPersonalAccessToken::decode(value.as_ref()).ok())
+            .filter(|stored| stored.username == username)
+            .filter_map(|stored| stored.token)
+            .collect()
+    }
+
+    // Return false if the user has no such token
+    pub fn revoke(&self, username: &str, id: &str) -> Result<bool, String> {
+        match self.get(id) {
+            Some(stored) if stored.username == username => {
+                self.db.remove(id).map_err(|e| e.to_string())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn revoke_all(&self, username: &str) -> Result<(), String> {
+        for token in self.get_all(username) {
+            self.db.remove(&token.id).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // Claims and scopes to put in the request in place of an access token
+    pub fn authenticate(&self, token: &str) -> Option<(AccessTokenClaims, Scopes)> {
+        let (id, secret) = split(token)?;
+        let stored = self.get(id)?;
+        // Hashes of random secrets, a timing difference tells nothing about the secret
+        if stored.hash != Sha256::digest(secret.as_bytes()).as_slice() {
+            return None;
+        }
+        let token = stored.token?;
+        let now = get_now_plus(0) as u32;
+        if token.expiration_date != 0 && token.expiration_date <= now {
+            return None;
+        }
+        if token.last_use.saturating_add(LAST_USE_PRECISION) <= now {
+            let _res = self.db.update_and_fetch(id, |value| {
+                let mut stored = StoredPersonalAccessToken::decode(value?).ok()?;
+                if let Some(token) = stored.token.as_mut() {
+                    token.last_use = now;
+                }
+                Some(stored.encode_to_vec())
+            });
+        }
+        let roles = if token.scopes.iter().any(|s| s == Scope::Admin.name()) {
+            self.roles.get(&stored.username)
+        } else {
+            Vec::new()
+        };
+        let exp = match token.expiration_date {
+            0 => u32::MAX,
+            date => date,
+        };
+        let claims = AccessTokenClaims {
+            sub: stored.username,
+            exp: exp as usize,
+            iss: "pat".to_string(),
+            roles,
+            jti: token.id,
+            sid: String::new(),
+            aud: String::new(),
+            scope: String::new(),
+        };
+        Some((claims, Scopes(token.scopes)))
+    }
+}
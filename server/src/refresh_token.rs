@@ -1,85 +1,303 @@
+use crate::deny_list::DenyList;
 use crate::get_now_plus;
-use proto::prost::Message;
+use crate::storage::RefreshTokenRepository;
 use proto::server::user::RefreshToken as RefreshTokenPb;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::iter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::vec::Vec;
 
-// token in the db: "[username]:[token]" // cons: double source of truth, no use of the valu field
+// Stored by a RefreshTokenRepository (see storage), keyed by username and token
 
 // custom token : "[username.base64][randomstring?]" // hard to secure (or remove : in username)
 
+const MAX_DEVICE_LEN: usize = 256;
+
+// Where a session was opened from
+pub struct Device {
+    pub from: String,
+    pub user_agent: String,
+    pub device_name: String,
+    pub client_id: String, // OAuth client, empty for this app
+    pub scope: String,     // Granted to the OAuth client
+}
+
+impl Device {
+    pub fn new<T>(request: &tonic::Request<T>, device_name: &str) -> Self {
+        let metadata = request.metadata();
+        let user_agent = metadata
+            .get("user-agent")
+            .or_else(|| metadata.get("x-user-agent"))
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        Self {
+            from: request
+                .remote_addr()
+                .map_or("unknown".to_string(), |addr| addr.ip().to_string()),
+            user_agent: user_agent.chars().take(MAX_DEVICE_LEN).collect(),
+            device_name: device_name.chars().take(MAX_DEVICE_LEN).collect(),
+            client_id: String::new(),
+            scope: String::new(),
+        }
+    }
+}
+
+pub enum RotateError {
+    Invalid,
+    Reused,
+}
+
 #[derive(Clone)]
 pub struct RefreshToken {
-    db: sled::Tree,
+    db: Arc<dyn RefreshTokenRepository>,
+    duration: u32,          /* seconds */
+    idle_timeout: u32,      /* seconds, 0 -> never */
+    deny_list: DenyList,    // Ended sessions lose their access tokens too
+    purged: Arc<AtomicU64>, // Expired tokens removed since startup, see Admin.GetStats
 }
 
 impl RefreshToken {
-    pub fn new(db: sled::Tree) -> Self {
-        Self { db }
+    pub fn new(
+        db: Arc<dyn RefreshTokenRepository>,
+        duration: u32,
+        idle_timeout: u32,
+        deny_list: DenyList,
+    ) -> Self {
+        Self {
+            db,
+            duration,
+            idle_timeout,
+            deny_list,
+            purged: Arc::new(AtomicU64::new(0)),
+        }
     }
-    pub fn new_token(&self, username: &str) -> String {
+    fn random_string() -> String {
         let mut rng = thread_rng();
-        let token: String = iter::repeat(())
+        iter::repeat(())
             .map(|()| rng.sample(Alphanumeric))
             .map(char::from)
             .take(15)
-            .collect();
-        let entry = format!("{}:{}", username, token);
+            .collect()
+    }
+
+    // Fill the fields not stored in the value
+    fn fill(key: &str, mut token: RefreshTokenPb) -> RefreshTokenPb {
+        if token.family.is_empty() {
+            // Created before rotation, alone in its family
+            token.family = key.to_string();
+        }
+        token.token = key.to_string();
+        token
+    }
+
+    // Rotated tokens are only kept to detect reuse, the idle timeout does not apply
+    fn is_expired(&self, token: &RefreshTokenPb, now: u32) -> bool {
+        let expiration_date = match token.expiration_date {
+            0 => token.creation_date.saturating_add(self.duration), // Created without expiration
+            date => date,
+        };
+        expiration_date <= now
+            || (self.idle_timeout != 0
+                && !token.rotated
+                && token.last_use.saturating_add(self.idle_timeout) <= now)
+    }
+
+    // Return the token and its session id
+    pub fn new_token(&self, username: &str, device: Device) -> (String, String) {
+        let token = Self::random_string();
+        let family = Self::random_string();
         let now = get_now_plus(0);
         let token_pb = RefreshTokenPb {
-            token: "".to_string(),         // Not use again
-            from: "somewhere".to_string(), // TODO
+            token: "".to_string(), // Not use again
+            from: device.from,
             creation_date: now as u32,
-            expiration_date: 0, // TODO
+            expiration_date: get_now_plus(self.duration) as u32,
             last_use: now as u32,
+            family: family.clone(),
+            rotated: false,
+            compromised: false,
+            user_agent: device.user_agent,
+            device_name: device.device_name,
+            client_id: device.client_id,
+            scope: device.scope,
         };
-        let _res = self.db.insert(entry.as_bytes(), token_pb.encode_to_vec());
-        token
+        let _res = self.db.insert(username, &token, &token_pb);
+        (token, family)
     }
-    pub fn verify(&self, username: &str, token: &str) -> bool {
-        let entry = format!("{}:{}", username, token);
-        let now = get_now_plus(0);
-        let _pl = match self.db.update_and_fetch(entry.as_bytes(), |token| {
-            if let Some(token) = token {
-                let mut token =
-                    RefreshTokenPb::decode(token).expect("malformated RefreshToken in the db");
-                token.last_use = now as u32;
-                Some(token.encode_to_vec())
-            } else {
-                None
+
+    /*
+        Every use of a refresh token replace it by a new one of the same family,
+        the old one is kept as rotated. Using a rotated token means it leaked:
+        the whole family get revoked.
+        Return the new token and its session id.
+    */
+    pub fn rotate(&self, username: &str, token: &str) -> Result<(String, String), RotateError> {
+        loop {
+            let stored = match self.db.get(username, token) {
+                Ok(Some(stored)) => stored,
+                Ok(None) => return Err(RotateError::Invalid),
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return Err(RotateError::Invalid); // TODO: handle errros
+                }
+            };
+            let old = Self::fill(token, stored.clone());
+            let now = get_now_plus(0) as u32;
+            if old.compromised || (!old.rotated && self.is_expired(&old, now)) {
+                return Err(RotateError::Invalid);
+            }
+            if old.rotated {
+                self.revoke_family(username, &old.family);
+                return Err(RotateError::Reused);
+            }
+            let new_token = Self::random_string();
+            let new = RefreshTokenPb {
+                token: "".to_string(), // Not use again
+                last_use: now,
+                ..old.clone()
+            };
+            // Insert the new one first so a family revocation seeing the old
+            // token rotated always find it
+            if self.db.insert(username, &new_token, &new).is_err() {
+                return Err(RotateError::Invalid);
+            }
+            let rotated = RefreshTokenPb {
+                token: "".to_string(), // Not use again
+                rotated: true,
+                last_use: now,
+                ..old
+            };
+            match self.db.replace(username, token, &stored, &rotated) {
+                Ok(true) => return Ok((new_token, rotated.family)),
+                // Used concurrently, try again to detect the reuse
+                Ok(false) => {
+                    let _res = self.db.remove(username, &new_token);
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    let _res = self.db.remove(username, &new_token);
+                    return Err(RotateError::Invalid);
+                }
+            }
+        }
+    }
+
+    // Tokens of the user, filled
+    fn tokens(&self, username: &str) -> Vec<RefreshTokenPb> {
+        self.db
+            .list(username)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token, value)| Self::fill(&token, value))
+            .collect()
+    }
+
+    fn family(&self, username: &str, family: &str) -> Vec<String> {
+        self.tokens(username)
+            .into_iter()
+            .filter(|token| token.family == family)
+            .map(|token| token.token)
+            .collect()
+    }
+
+    // Keep the tokens so the session show up as compromised
+    fn revoke_family(&self, username: &str, family: &str) {
+        self.deny_list.deny(family);
+        for token in self.family(username, family) {
+            let _res = self
+                .db
+                .update(username, &token, &|token| token.compromised = true);
+        }
+    }
+
+    // Delete the whole session
+    pub fn delete(&self, username: &str, token: &str) {
+        let family = match self.db.get(username, token) {
+            Ok(Some(value)) => Self::fill(token, value).family,
+            _ => return,
+        };
+        self.deny_list.deny(&family);
+        for token in self.family(username, &family) {
+            let _res = self.db.remove(username, &token);
+        }
+    }
+
+    // Return the number of sessions deleted, the session `except` is kept
+    pub fn delete_all(&self, username: &str, except: &str) -> usize {
+        let mut families = Vec::new();
+        for token in self.tokens(username) {
+            if token.family == except {
+                continue;
             }
-        }) {
-            Ok(Some(pl)) => pl,
-            Ok(None) => return false,
+            if matches!(self.db.remove(username, &token.token), Ok(true))
+                && !families.contains(&token.family)
+            {
+                self.deny_list.deny(&token.family);
+                families.push(token.family);
+            }
+        }
+        families.len()
+    }
+
+    // Look a token up without its username, for the /oauth2 endpoints
+    pub fn find(&self, token: &str) -> Option<(String, RefreshTokenPb)> {
+        if token.len() != 15 || !token.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        match self.db.find(token) {
+            Ok(Some((username, value))) => Some((username, Self::fill(token, value))),
+            _ => None,
+        }
+    }
+
+    // Can still be rotated into a new one
+    pub fn is_active(&self, token: &RefreshTokenPb) -> bool {
+        !token.rotated && !token.compromised && !self.is_expired(token, get_now_plus(0) as u32)
+    }
+
+    // One token per session, the rotated ones are only kept for reuse detection
+    pub fn get_all(&self, username: &str) -> Vec<RefreshTokenPb> {
+        let now = get_now_plus(0) as u32;
+        self.tokens(username)
+            .into_iter()
+            .filter(|token| !token.rotated && !self.is_expired(token, now))
+            .collect()
+    }
+
+    // Remove the expired tokens, return how many were removed
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let purged = match self.db.remove_where(&|token| self.is_expired(token, now)) {
+            Ok(purged) => purged,
             Err(e) => {
                 println!("Error: {}", e);
-                return false; // TODO: handle errros
+                0
             }
         };
-        true
+        self.purged.fetch_add(purged as u64, Ordering::Relaxed);
+        purged
     }
-    pub fn delete(&self, username: &str, token: &str) {
-        let entry = format!("{}:{}", username, token);
-        self.db.remove(entry.as_bytes());
+
+    // Expired tokens removed by the sweeps since startup
+    pub fn purged(&self) -> u64 {
+        self.purged.load(Ordering::Relaxed)
     }
-    pub fn get_all(&self, username: &str) -> Vec<RefreshTokenPb> {
-        let username = format!("{}:", username);
-        let tokens = self
-            .db
-            .scan_prefix(username)
-            .map(|entry| {
-                let entry = entry.unwrap();
-                let key = entry.0.as_ref();
-                let key = std::str::from_utf8(key).unwrap();
-                let key = key[key.find(":").unwrap() + 1..].to_string();
-                let mut token = RefreshTokenPb::decode(entry.1.as_ref())
-                    .expect("malformated RefreshToken inthe db");
-                token.token = key;
-                token
-            })
-            .collect();
-        tokens
+
+    pub async fn sweep_every(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let purged = self.sweep();
+            if purged != 0 {
+                println!(
+                    "Refresh tokens sweep: {} expired purged, {} since startup",
+                    purged,
+                    self.purged()
+                );
+            }
+        }
     }
 }
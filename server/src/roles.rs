@@ -0,0 +1,134 @@
+use crate::jwt::AccessTokenClaims;
+use crate::users::Users;
+use tonic::{Code, Request, Status};
+
+// Stored in the roles of the UserRecord
+
+/*
+    Roles are copied in the access token at creation, a change is seen by the
+    user services after the next token refresh.
+    The bootstrap admin (config admin) always has the admin role.
+*/
+
+pub const ADMIN: &str = "admin";
+pub const SUPPORT: &str = "support";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permission {
+    ViewInviteTree,
+    ClearLockout,
+    ManageRoles,
+    ManageUsers,
+    DeleteUsers,
+}
+
+fn permissions(role: &str) -> &'static [Permission] {
+    match role {
+        ADMIN => &[
+            Permission::ViewInviteTree,
+            Permission::ClearLockout,
+            Permission::ManageRoles,
+            Permission::ManageUsers,
+            Permission::DeleteUsers,
+        ],
+        SUPPORT => &[
+            Permission::ViewInviteTree,
+            Permission::ClearLockout,
+            Permission::ManageUsers,
+        ],
+        _ => &[],
+    }
+}
+
+pub fn is_valid(role: &str) -> bool {
+    !permissions(role).is_empty()
+}
+
+// To call first in the RPCs behind the Jwt interceptor
+#[allow(clippy::result_large_err)]
+pub fn authorize<T>(request: &Request<T>, permission: Permission) -> Result<(), Status> {
+    let claims = request
+        .extensions()
+        .get::<AccessTokenClaims>()
+        .ok_or_else(|| Status::new(Code::PermissionDenied, "Missing credentials"))?;
+    if claims
+        .roles
+        .iter()
+        .any(|role| permissions(role).contains(&permission))
+    {
+        Ok(())
+    } else {
+        Err(Status::new(Code::PermissionDenied, "Permission denied"))
+    }
+}
+
+// The target of an admin action cannot have a permission the caller lacks, so
+// a support user cannot take over an admin account (reset its password...)
+#[allow(clippy::result_large_err)]
+pub fn authorize_over<T>(request: &Request<T>, target_roles: &[String]) -> Result<(), Status> {
+    let claims = request
+        .extensions()
+        .get::<AccessTokenClaims>()
+        .ok_or_else(|| Status::new(Code::PermissionDenied, "Missing credentials"))?;
+    let outranked = target_roles
+        .iter()
+        .flat_map(|role| permissions(role).iter())
+        .any(|permission| {
+            !claims
+                .roles
+                .iter()
+                .any(|role| permissions(role).contains(permission))
+        });
+    if outranked {
+        Err(Status::new(
+            Code::PermissionDenied,
+            "The user has permissions you do not have",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Roles {
+    users: Users,
+    admin: String,
+}
+
+impl Roles {
+    pub fn new(users: Users, admin: String) -> Self {
+        Self { users, admin }
+    }
+
+    pub fn get(&self, username: &str) -> Vec<String> {
+        let mut roles = self
+            .users
+            .get(username)
+            .map(|record| record.roles)
+            .unwrap_or_default();
+        if username == self.admin && !roles.iter().any(|role| role == ADMIN) {
+            roles.push(ADMIN.to_string());
+        }
+        roles
+    }
+
+    // Return the roles after the change
+    pub fn set(&self, username: &str, role: &str, granted: bool) -> Result<Vec<String>, String> {
+        if !is_valid(role) {
+            return Err(format!("Unknown role \"{}\"", role));
+        }
+        if !granted && role == ADMIN && username == self.admin {
+            return Err("The bootstrap admin cannot lose the admin role".to_string());
+        }
+        let found = self.users.update(username, &|record| {
+            record.roles.retain(|r| r != role);
+            if granted {
+                record.roles.push(role.to_string());
+            }
+        })?;
+        if !found {
+            return Err("Unknown user".to_string());
+        }
+        Ok(self.get(username))
+    }
+}
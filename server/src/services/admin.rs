@@ -0,0 +1,328 @@
+use proto::server::admin as adminpb;
+use proto::server::user::RefreshToken as RefreshTokenPb;
+use std::sync::Arc;
+
+use tonic::{Code, Request, Response, Status};
+
+use crate::email::Emails;
+use crate::federation::Federation;
+use crate::invite;
+use crate::jwt::AccessTokenClaims;
+use crate::password;
+use crate::pat::PersonalAccessTokens;
+use crate::refresh_token::RefreshToken;
+use crate::roles::{self, Permission, Roles};
+use crate::storage::InviteRepository;
+use crate::throttle::Throttle;
+use crate::totp::Totp;
+use crate::users::Users;
+
+type TonicResult<T> = Result<Response<T>, Status>;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+pub struct Service {
+    users: Users,
+    refresh_token: RefreshToken,
+    pats: PersonalAccessTokens,
+    throttle: Throttle,
+    totp: Totp,
+    roles: Roles,
+    invites: Arc<dyn InviteRepository>,
+    federation: Federation,
+    emails: Emails,
+    hash_config: argon2::Config<'static>,
+    admin: String,
+}
+
+fn page_size(size: u32) -> usize {
+    let size = match size {
+        0 => DEFAULT_PAGE_SIZE,
+        size => size.min(MAX_PAGE_SIZE),
+    };
+    size as usize
+}
+
+impl Service {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        users: Users,
+        refresh_token: RefreshToken,
+        pats: PersonalAccessTokens,
+        throttle: Throttle,
+        totp: Totp,
+        roles: Roles,
+        invites: Arc<dyn InviteRepository>,
+        federation: Federation,
+        emails: Emails,
+        hash_config: argon2::Config<'static>,
+        admin: String,
+    ) -> Self {
+        Self {
+            users,
+            refresh_token,
+            pats,
+            throttle,
+            totp,
+            roles,
+            invites,
+            federation,
+            emails,
+            hash_config,
+            admin,
+        }
+    }
+
+    fn get_username<T>(request: &Request<T>) -> &str {
+        &request.extensions().get::<AccessTokenClaims>().unwrap().sub
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_user(&self, username: &str) -> Result<(), Status> {
+        match self.users.exists(username) {
+            true => Ok(()),
+            false => Err(Status::new(Code::NotFound, "Unknown user")),
+        }
+    }
+
+    // The user exists and the caller outranks it
+    #[allow(clippy::result_large_err)]
+    fn check_target<T>(&self, request: &Request<T>, username: &str) -> Result<(), Status> {
+        self.check_user(username)?;
+        roles::authorize_over(request, &self.roles.get(username))
+    }
+}
+
+#[tonic::async_trait]
+impl adminpb::admin_server::Admin for Service {
+    async fn list_users(
+        &self,
+        request: Request<adminpb::ListUsersReq>,
+    ) -> TonicResult<adminpb::ListUsersRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        let request = request.into_inner();
+        let limit = page_size(request.page_size);
+        let users: Vec<adminpb::UserSummary> = self
+            .users
+            .list(&request.page_token, limit)
+            .into_iter()
+            .map(|(username, record)| adminpb::UserSummary {
+                roles: self.roles.get(&username),
+                disabled: record.disabled,
+                username,
+            })
+            .collect();
+        let next_page_token = match users.last() {
+            Some(user) if users.len() == limit => user.username.clone(),
+            _ => String::new(),
+        };
+        Ok(Response::new(adminpb::ListUsersRes {
+            payload: Some(adminpb::list_users_res::Payload::Ok(
+                adminpb::list_users_res::Ok {
+                    users,
+                    next_page_token,
+                },
+            )),
+        }))
+    }
+
+    async fn get_user(
+        &self,
+        request: Request<adminpb::GetUserReq>,
+    ) -> TonicResult<adminpb::GetUserRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        self.check_target(&request, &request.get_ref().username)?;
+        let username = request.into_inner().username;
+        let record = self
+            .users
+            .get(&username)
+            .ok_or_else(|| Status::new(Code::NotFound, "Unknown user"))?;
+        // The secret would let the caller use the session, the family names it
+        let sessions = self
+            .refresh_token
+            .get_all(&username)
+            .into_iter()
+            .map(|session| RefreshTokenPb {
+                token: String::new(),
+                ..session
+            })
+            .collect();
+        let invitation = invite::inviter(&*self.invites, &username).unwrap_or_default();
+        let user = adminpb::UserDetails {
+            roles: self.roles.get(&username),
+            disabled: record.disabled,
+            totp_enabled: self.totp.is_enabled(&username),
+            sessions,
+            invited_by: invitation.inviter,
+            // Users from before the records only have the invitation date
+            signup_date: match record.created_date {
+                0 => invitation.date,
+                created_date => created_date,
+            },
+            last_login_date: record.last_login_date,
+            username,
+        };
+        Ok(Response::new(adminpb::GetUserRes {
+            payload: Some(adminpb::get_user_res::Payload::Ok(
+                adminpb::get_user_res::Ok { user: Some(user) },
+            )),
+        }))
+    }
+
+    async fn set_user_disabled(
+        &self,
+        request: Request<adminpb::SetUserDisabledReq>,
+    ) -> TonicResult<adminpb::SetUserDisabledRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        self.check_target(&request, &request.get_ref().username)?;
+        let caller = Self::get_username(&request).to_string();
+        let request = request.into_inner();
+        let username = request.username;
+        if request.disabled && (username == self.admin || username == caller) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Cannot disable the bootstrap admin or yourself",
+            ));
+        }
+        self.users
+            .set_disabled(&username, request.disabled)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        if request.disabled {
+            self.refresh_token.delete_all(&username, "");
+            self.pats
+                .revoke_all(&username)
+                .map_err(|e| Status::new(Code::Unknown, e))?;
+        }
+        Ok(Response::new(adminpb::SetUserDisabledRes {
+            payload: Some(adminpb::set_user_disabled_res::Payload::Ok(
+                adminpb::set_user_disabled_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn revoke_user_sessions(
+        &self,
+        request: Request<adminpb::RevokeUserSessionsReq>,
+    ) -> TonicResult<adminpb::RevokeUserSessionsRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        self.check_target(&request, &request.get_ref().username)?;
+        let username = request.into_inner().username;
+        let revoked = self.refresh_token.delete_all(&username, "") as u32;
+        Ok(Response::new(adminpb::RevokeUserSessionsRes {
+            payload: Some(adminpb::revoke_user_sessions_res::Payload::Ok(
+                adminpb::revoke_user_sessions_res::Ok { revoked },
+            )),
+        }))
+    }
+
+    async fn reset_password(
+        &self,
+        request: Request<adminpb::ResetPasswordReq>,
+    ) -> TonicResult<adminpb::ResetPasswordRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        self.check_target(&request, &request.get_ref().username)?;
+        let request = request.into_inner();
+        let username = request.username;
+        if request.new_password.len() < 3 {
+            return Err(Status::new(Code::InvalidArgument, "Password too short"));
+        }
+        let hash = password::hash(&request.new_password, &self.hash_config)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        self.users
+            .set_password(&username, &hash)
+            .map_err(|_| Status::new(Code::Unknown, "Cannot insert new password"))?;
+        self.refresh_token.delete_all(&username, "");
+        self.throttle.succeeded(&username);
+        Ok(Response::new(adminpb::ResetPasswordRes {
+            payload: Some(adminpb::reset_password_res::Payload::Ok(
+                adminpb::reset_password_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<adminpb::DeleteUserReq>,
+    ) -> TonicResult<adminpb::DeleteUserRes> {
+        roles::authorize(&request, Permission::DeleteUsers)?;
+        self.check_target(&request, &request.get_ref().username)?;
+        let caller = Self::get_username(&request).to_string();
+        let username = request.into_inner().username;
+        if username == self.admin || username == caller {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Cannot delete the bootstrap admin or yourself",
+            ));
+        }
+        // The address index is cleaned from the record
+        self.emails
+            .remove(&username)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        self.users
+            .remove(&username)
+            .map_err(|e| Status::new(Code::Unknown, format!("database error {}", e)))?;
+        self.refresh_token.delete_all(&username, "");
+        invite::delete_all(&*self.invites, &username)
+            .and_then(|_| invite::delete_invitations(&*self.invites, &username))
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        self.pats
+            .revoke_all(&username)
+            .and_then(|_| self.federation.unlink_all(&username))
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        self.throttle.succeeded(&username);
+        Ok(Response::new(adminpb::DeleteUserRes {
+            payload: Some(adminpb::delete_user_res::Payload::Ok(
+                adminpb::delete_user_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn list_invites(
+        &self,
+        request: Request<adminpb::ListInvitesReq>,
+    ) -> TonicResult<adminpb::ListInvitesRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        let request = request.into_inner();
+        let limit = page_size(request.page_size);
+        let invites: Vec<adminpb::AdminInvite> =
+            invite::list(&*self.invites, &request.page_token, limit)
+                .map_err(|e| Status::new(Code::InvalidArgument, e))?
+                .into_iter()
+                .map(|(owner, invite)| adminpb::AdminInvite {
+                    owner,
+                    invite: Some(invite),
+                })
+                .collect();
+        let next_page_token = match invites.last() {
+            Some(last) if invites.len() == limit => last
+                .invite
+                .as_ref()
+                .map(|invite| invite.token.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        Ok(Response::new(adminpb::ListInvitesRes {
+            payload: Some(adminpb::list_invites_res::Payload::Ok(
+                adminpb::list_invites_res::Ok {
+                    invites,
+                    next_page_token,
+                },
+            )),
+        }))
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<adminpb::GetStatsReq>,
+    ) -> TonicResult<adminpb::GetStatsRes> {
+        roles::authorize(&request, Permission::ManageUsers)?;
+        Ok(Response::new(adminpb::GetStatsRes {
+            payload: Some(adminpb::get_stats_res::Payload::Ok(
+                adminpb::get_stats_res::Ok {
+                    refresh_tokens_purged: self.refresh_token.purged(),
+                },
+            )),
+        }))
+    }
+}
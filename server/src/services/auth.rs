@@ -1,36 +1,95 @@
 use proto::server::auth::{
-    auth_server::Auth, get_access_token_res, get_refresh_token_res, signup_res, GetAccessTokenReq,
-    GetAccessTokenRes, GetRefreshTokenReq, GetRefreshTokenRes, SignupReq, SignupRes,
+    auth_server::Auth, confirm_password_reset_res, federated_login_res, get_access_token_res,
+    get_federation_providers_res, get_refresh_token_res, get_signing_keys_res, logout_res,
+    request_password_reset_res, signup_res, start_federated_login_res, verify_email_res,
+    verify_mfa_res, ConfirmPasswordResetReq, ConfirmPasswordResetRes, FederatedLoginReq,
+    FederatedLoginRes, GetAccessTokenReq, GetAccessTokenRes, GetFederationProvidersReq,
+    GetFederationProvidersRes, GetRefreshTokenReq, GetRefreshTokenRes, GetSigningKeysReq,
+    GetSigningKeysRes, LogoutReq, LogoutRes, RequestPasswordResetReq, RequestPasswordResetRes,
+    SigningKey, SignupReq, SignupRes, StartFederatedLoginReq, StartFederatedLoginRes,
+    VerifyEmailReq, VerifyEmailRes, VerifyMfaReq, VerifyMfaRes,
 };
+use proto::server::user::email_token::Purpose;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
 use tonic::{Code, Request, Response, Status};
 
+use crate::email::{self, Emails};
+use crate::federation::{self, Federation, FederationError};
 use crate::invite;
 use crate::jwt::Jwt;
-use crate::refresh_token::RefreshToken;
+use crate::mailer::Mailer;
+use crate::password;
+use crate::refresh_token::{Device, RefreshToken, RotateError};
+use crate::roles::Roles;
+use crate::storage::CreateError;
+use crate::throttle::Throttle;
+use crate::totp::Totp;
+use crate::users::{self, Users};
 
 type TonicResult<T> = Result<Response<T>, Status>;
 
-const FIRST_USERNAME: &str = "tet";
-
 pub struct Service {
-    users: sled::Tree,
+    users: Users,
     jwt: Jwt,
     refresh_token: RefreshToken,
-    invites: sled::Tree,
+    throttle: Throttle,
+    totp: Totp,
+    roles: Roles,
+    federation: Federation,
+    emails: Emails,
+    mailer: Arc<dyn Mailer>,
+    hash_config: argon2::Config<'static>,
+    admin: String,
+}
+
+fn too_many_attempts(retry_after: u32) -> Status {
+    let mut status = Status::new(
+        Code::ResourceExhausted,
+        format!("Too many attempts, retry in {}s", retry_after),
+    );
+    status
+        .metadata_mut()
+        .insert("retry-after", retry_after.into());
+    status
 }
 
 impl Service {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        users: sled::Tree,
+        users: Users,
         jwt: Jwt,
         refresh_token: RefreshToken,
-        invites: sled::Tree,
+        throttle: Throttle,
+        totp: Totp,
+        roles: Roles,
+        federation: Federation,
+        emails: Emails,
+        mailer: Arc<dyn Mailer>,
+        hash_config: argon2::Config<'static>,
+        admin: String,
     ) -> Self {
         Self {
             users,
             jwt,
             refresh_token,
-            invites,
+            throttle,
+            totp,
+            roles,
+            federation,
+            emails,
+            mailer,
+            hash_config,
+            admin,
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn check_disabled(&self, username: &str) -> Result<(), Status> {
+        match self.users.is_disabled(username) {
+            false => Ok(()),
+            true => Err(Status::new(Code::PermissionDenied, "Account disabled")),
         }
     }
 }
@@ -41,6 +100,8 @@ impl Auth for Service {
         &self,
         request: Request<GetRefreshTokenReq>,
     ) -> TonicResult<GetRefreshTokenRes> {
+        let device = Device::new(&request, &request.get_ref().device_name);
+        let ip = request.remote_addr().map(|addr| addr.ip());
         let request = request.into_inner();
         let password = request.password;
         let username = request.username;
@@ -50,33 +111,59 @@ impl Auth for Service {
                 "Username or password invalid.",
             ));
         }
-        let hash = match self.users.get(&username) {
-            Ok(Some(users)) => users,
-            _ => {
+        self.throttle
+            .check(&username, ip)
+            .map_err(too_many_attempts)?;
+        // Unknown usernames count too, not to tell them apart
+        let record = match self.users.check_password(&username, &password) {
+            Some(record) => record,
+            None => {
+                if let Some(retry_after) = self.throttle.failed(&username, ip) {
+                    return Err(too_many_attempts(retry_after));
+                }
                 return Err(Status::new(
                     Code::InvalidArgument,
                     "Username or password invalid.",
-                ))
+                ));
             }
         };
+        if password::needs_rehash(record.password_hash.as_bytes(), &self.hash_config) {
+            if let Ok(new_hash) = password::hash(&password, &self.hash_config) {
+                self.users
+                    .rehash(&username, &record.password_hash, &new_hash);
+            }
+        }
+        self.check_disabled(&username)?;
 
-        if argon2::verify_encoded(&std::str::from_utf8(&hash).unwrap(), password.as_bytes())
-            != Ok(true)
-        {
-            return Err(Status::new(
-                Code::InvalidArgument,
-                "Username or password invalid.",
-            ));
-        };
+        if self.totp.is_enabled(&username) {
+            let (mfa_token, expiration_date) = self
+                .totp
+                .new_challenge(&username, &device.device_name)
+                .map_err(|e| Status::new(Code::Unknown, e))?;
+            return Ok(Response::new(GetRefreshTokenRes {
+                payload: Some(get_refresh_token_res::Payload::Mfa(
+                    get_refresh_token_res::Mfa {
+                        mfa_token,
+                        expiration_date,
+                    },
+                )),
+            }));
+        }
+        self.throttle.succeeded(&username);
+        self.users.record_login(&username);
 
-        let refresh_token = self.refresh_token.new_token(&username);
+        let (refresh_token, session) = self.refresh_token.new_token(&username, device);
 
         Ok(Response::new(GetRefreshTokenRes {
             payload: Some(get_refresh_token_res::Payload::Ok(
                 get_refresh_token_res::Ok {
                     refresh_token,
-                    access_token: self.jwt.create_token(&username),
-                    access_exp: Jwt::get_exp(),
+                    access_token: self.jwt.create_token(
+                        &username,
+                        self.roles.get(&username),
+                        &session,
+                    ),
+                    access_exp: self.jwt.get_exp(),
                 },
             )),
         }))
@@ -89,22 +176,34 @@ impl Auth for Service {
         let request = request.into_inner();
         let refresh_token = request.refresh_token;
         let username = request.username;
+        self.check_disabled(&username)?;
 
-        if !self.refresh_token.verify(&username, &refresh_token) {
-            Err(Status::new(Code::InvalidArgument, "Invalid token"))?;
-        }
+        let (refresh_token, session) = match self.refresh_token.rotate(&username, &refresh_token) {
+            Ok(rotated) => rotated,
+            Err(RotateError::Invalid) => Err(Status::new(Code::InvalidArgument, "Invalid token"))?,
+            Err(RotateError::Reused) => Err(Status::new(
+                Code::PermissionDenied,
+                "Refresh token reused, session revoked",
+            ))?,
+        };
 
         Ok(Response::new(GetAccessTokenRes {
             payload: Some(get_access_token_res::Payload::Ok(
                 get_access_token_res::Ok {
-                    access_token: self.jwt.create_token(&username),
-                    exp: Jwt::get_exp(),
+                    access_token: self.jwt.create_token(
+                        &username,
+                        self.roles.get(&username),
+                        &session,
+                    ),
+                    exp: self.jwt.get_exp(),
+                    refresh_token,
                 },
             )),
         }))
     }
 
     async fn signup(&self, request: Request<SignupReq>) -> TonicResult<SignupRes> {
+        let device = Device::new(&request, &request.get_ref().device_name);
         let request = request.into_inner();
         let password = request.password;
         let username = request.username;
@@ -117,45 +216,303 @@ impl Auth for Service {
             ));
         }
 
-        match self.users.get(username.as_bytes()) {
-            Ok(Some(_)) => {
-                return Err(Status::new(Code::InvalidArgument, "Username already exist"))
-            }
-            Ok(None) => {}
-            Err(e) => {
-                return Err(Status::new(
-                    Code::InvalidArgument,
-                    format!("database error {}", e),
-                ))
-            }
+        if self.users.exists(&username) {
+            return Err(Status::new(Code::InvalidArgument, "Username already exist"));
         }
-        let hash = match argon2::hash_encoded(
-            password.as_bytes(),
-            crate::SALT.as_bytes(),
-            &(argon2::Config::default()),
-        ) {
-            Ok(hash) => hash,
-            _ => {
-                return Err(Status::new(
-                    Code::Unknown,
-                    "Unknown error when hashing your password",
-                ))
+        let hash = password::hash(&password, &self.hash_config)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        let mut record = users::new_record(&hash);
+        record.last_login_date = record.created_date;
+        let invite = match username == self.admin {
+            true => None,
+            false => {
+                Some(invite::key(&user_invite).map_err(|e| Status::new(Code::InvalidArgument, e))?)
             }
         };
-        if username != FIRST_USERNAME {
-            if let Err(e) = invite::uze(&self.invites, &username, &user_invite) {
-                return Err(Status::new(Code::Unknown, e.to_string()));
-            }
+        match self.users.create(&username, &record, invite.as_deref()) {
+            Ok(()) => {}
+            Err(CreateError::Rejected(e)) => return Err(Status::new(Code::InvalidArgument, e)),
+            Err(CreateError::Database(e)) => return Err(Status::new(Code::Unknown, e)),
         }
-        self.users.insert(&username, hash.as_bytes());
-        let refresh_token = self.refresh_token.new_token(&username);
+        let (refresh_token, session) = self.refresh_token.new_token(&username, device);
 
         Ok(Response::new(SignupRes {
             payload: Some(signup_res::Payload::Ok(signup_res::Ok {
                 refresh_token,
-                access_token: self.jwt.create_token(&username),
-                access_exp: Jwt::get_exp(),
+                access_token: self
+                    .jwt
+                    .create_token(&username, self.roles.get(&username), &session),
+                access_exp: self.jwt.get_exp(),
             })),
         }))
     }
+
+    async fn get_signing_keys(
+        &self,
+        _request: Request<GetSigningKeysReq>,
+    ) -> TonicResult<GetSigningKeysRes> {
+        let keys = self
+            .jwt
+            .public_keys()
+            .into_iter()
+            .map(|key| SigningKey {
+                kid: key.jwk.kid.clone(),
+                algorithm: key.jwk.alg.clone(),
+                public_key: key.pem,
+                jwk: serde_json::to_string(&key.jwk).unwrap_or_default(),
+            })
+            .collect();
+        Ok(Response::new(GetSigningKeysRes {
+            payload: Some(get_signing_keys_res::Payload::Ok(
+                get_signing_keys_res::Ok { keys },
+            )),
+        }))
+    }
+
+    async fn verify_mfa(&self, request: Request<VerifyMfaReq>) -> TonicResult<VerifyMfaRes> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        let mfa_token = &request.get_ref().mfa_token;
+        let challenge = self
+            .totp
+            .get_challenge(mfa_token)
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid or expired mfa token"))?;
+        let username = challenge.username;
+        self.check_disabled(&username)?;
+        self.throttle
+            .check(&username, ip)
+            .map_err(too_many_attempts)?;
+        if !self
+            .totp
+            .verify_second_factor(&username, &request.get_ref().code)
+        {
+            if let Some(retry_after) = self.throttle.failed(&username, ip) {
+                return Err(too_many_attempts(retry_after));
+            }
+            return Err(Status::new(Code::InvalidArgument, "Invalid code"));
+        }
+        // Single use, lose against a concurrent verification
+        if !self.totp.remove_challenge(mfa_token) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Invalid or expired mfa token",
+            ));
+        }
+        self.throttle.succeeded(&username);
+        self.users.record_login(&username);
+
+        let device = Device::new(&request, &challenge.device_name);
+        let (refresh_token, session) = self.refresh_token.new_token(&username, device);
+
+        Ok(Response::new(VerifyMfaRes {
+            payload: Some(verify_mfa_res::Payload::Ok(verify_mfa_res::Ok {
+                refresh_token,
+                access_token: self
+                    .jwt
+                    .create_token(&username, self.roles.get(&username), &session),
+                access_exp: self.jwt.get_exp(),
+                username,
+            })),
+        }))
+    }
+
+    // Unknown tokens are not an error, the session is gone either way
+    async fn logout(&self, request: Request<LogoutReq>) -> TonicResult<LogoutRes> {
+        let request = request.into_inner();
+        self.refresh_token
+            .delete(&request.username, &request.refresh_token);
+        Ok(Response::new(LogoutRes {
+            payload: Some(logout_res::Payload::Ok(logout_res::Ok {})),
+        }))
+    }
+
+    async fn get_federation_providers(
+        &self,
+        _request: Request<GetFederationProvidersReq>,
+    ) -> TonicResult<GetFederationProvidersRes> {
+        Ok(Response::new(GetFederationProvidersRes {
+            payload: Some(get_federation_providers_res::Payload::Ok(
+                get_federation_providers_res::Ok {
+                    providers: self.federation.providers(),
+                },
+            )),
+        }))
+    }
+
+    async fn start_federated_login(
+        &self,
+        request: Request<StartFederatedLoginReq>,
+    ) -> TonicResult<StartFederatedLoginRes> {
+        let (authorization_url, state) = self
+            .federation
+            .start(&request.get_ref().provider, "")
+            .await
+            .map_err(FederationError::into_status)?;
+        Ok(Response::new(StartFederatedLoginRes {
+            payload: Some(start_federated_login_res::Payload::Ok(
+                start_federated_login_res::Ok {
+                    authorization_url,
+                    state,
+                },
+            )),
+        }))
+    }
+
+    // Linked user, or one created for the identity if the provider allows signup
+    async fn federated_login(
+        &self,
+        request: Request<FederatedLoginReq>,
+    ) -> TonicResult<FederatedLoginRes> {
+        let device = Device::new(&request, &request.get_ref().device_name);
+        let request = request.into_inner();
+        let (identity, link_to) = self
+            .federation
+            .finish(&request.state, &request.code)
+            .await
+            .map_err(FederationError::into_status)?;
+        let linked = self
+            .federation
+            .get_link(&identity.provider, &identity.subject);
+        let username = if !link_to.is_empty() {
+            // Started by User.StartFederatedLink
+            self.federation
+                .link(&identity, &link_to)
+                .map_err(|e| Status::new(Code::AlreadyExists, e))?;
+            link_to
+        } else if let Some(username) = linked {
+            username
+        } else if self.federation.allows_signup(&identity.provider) {
+            let username = federation::local_username(&identity).ok_or_else(|| {
+                Status::new(
+                    Code::FailedPrecondition,
+                    "The identity provider gave no usable username",
+                )
+            })?;
+            // No password login until the user sets one
+            let password: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect();
+            let hash = password::hash(&password, &self.hash_config)
+                .map_err(|e| Status::new(Code::Unknown, e))?;
+            self.federation
+                .signup(&self.users, &identity, &username, &users::new_record(&hash))
+                .map_err(|e| Status::new(Code::AlreadyExists, e))?;
+            username
+        } else {
+            return Err(Status::new(
+                Code::NotFound,
+                "No user linked to this identity, sign in and link it from the settings",
+            ));
+        };
+        self.check_disabled(&username)?;
+
+        if self.totp.is_enabled(&username) {
+            let (mfa_token, expiration_date) = self
+                .totp
+                .new_challenge(&username, &device.device_name)
+                .map_err(|e| Status::new(Code::Unknown, e))?;
+            return Ok(Response::new(FederatedLoginRes {
+                payload: Some(federated_login_res::Payload::Mfa(
+                    federated_login_res::Mfa {
+                        username,
+                        mfa_token,
+                        expiration_date,
+                    },
+                )),
+            }));
+        }
+        self.users.record_login(&username);
+
+        let (refresh_token, session) = self.refresh_token.new_token(&username, device);
+
+        Ok(Response::new(FederatedLoginRes {
+            payload: Some(federated_login_res::Payload::Ok(federated_login_res::Ok {
+                access_token: self
+                    .jwt
+                    .create_token(&username, self.roles.get(&username), &session),
+                access_exp: self.jwt.get_exp(),
+                refresh_token,
+                username,
+            })),
+        }))
+    }
+
+    async fn verify_email(&self, request: Request<VerifyEmailReq>) -> TonicResult<VerifyEmailRes> {
+        let (username, email) = self
+            .emails
+            .verify(&request.get_ref().token)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(VerifyEmailRes {
+            payload: Some(verify_email_res::Payload::Ok(verify_email_res::Ok {
+                username,
+                email,
+            })),
+        }))
+    }
+
+    // Sent in the background, the answer does not tell if the address is known
+    async fn request_password_reset(
+        &self,
+        request: Request<RequestPasswordResetReq>,
+    ) -> TonicResult<RequestPasswordResetRes> {
+        let username = self.emails.find_verified(&request.get_ref().email);
+        let email = username
+            .as_ref()
+            .and_then(|username| self.emails.get(username));
+        if let (Some(username), Some(email)) = (username, email) {
+            if let Ok(Some(token)) =
+                self.emails
+                    .new_token(Purpose::ResetPassword, &username, &email.email)
+            {
+                let mail = email::reset_mail(&email.email, &username, &token);
+                let mailer = self.mailer.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = mailer.send(&mail).await {
+                        eprintln!("Cannot send the password reset mail: {}", e);
+                    }
+                });
+            }
+        }
+        Ok(Response::new(RequestPasswordResetRes {
+            payload: Some(request_password_reset_res::Payload::Ok(
+                request_password_reset_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn confirm_password_reset(
+        &self,
+        request: Request<ConfirmPasswordResetReq>,
+    ) -> TonicResult<ConfirmPasswordResetRes> {
+        let request = request.into_inner();
+        if request.new_password.len() < 3 {
+            return Err(Status::new(Code::InvalidArgument, "Password invalid."));
+        }
+        let username = self
+            .emails
+            .take_reset_token(&request.token)
+            .filter(|username| self.users.exists(username))
+            .ok_or_else(|| Status::new(Code::InvalidArgument, "Invalid or expired token"))?;
+        let hash = password::hash(&request.new_password, &self.hash_config)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        match self.users.set_password(&username, &hash) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Invalid or expired token",
+                ))
+            }
+            Err(_) => return Err(Status::new(Code::Unknown, "Cannot insert new password")),
+        }
+        // Whoever knew the old password is signed out, and the lockout lifted
+        let revoked = self.refresh_token.delete_all(&username, "") as u32;
+        self.throttle.succeeded(&username);
+        Ok(Response::new(ConfirmPasswordResetRes {
+            payload: Some(confirm_password_reset_res::Payload::Ok(
+                confirm_password_reset_res::Ok { username, revoked },
+            )),
+        }))
+    }
 }
@@ -1,29 +1,73 @@
 use proto::server::user as userpb;
+use std::sync::Arc;
 
 use tonic::{Code, Request, Response, Status};
 
+use crate::email::{self, Emails};
+use crate::federation::{Federation, FederationError};
+use crate::get_now_plus;
 use crate::invite;
 use crate::jwt::AccessTokenClaims;
+use crate::mailer::{self, Mailer};
+use crate::password;
+use crate::pat::{require_scope, PersonalAccessTokens, Scope};
 use crate::refresh_token::RefreshToken;
+use crate::roles::{self, Permission, Roles};
+use crate::storage::InviteRepository;
+use crate::throttle::Throttle;
+use crate::totp::Totp;
+use crate::users::Users;
 
 type TonicResult<T> = Result<Response<T>, Status>;
 
 pub struct Service {
     refresh_token: RefreshToken,
-    users: sled::Tree,
-    invites: sled::Tree,
+    pats: PersonalAccessTokens,
+    throttle: Throttle,
+    totp: Totp,
+    roles: Roles,
+    users: Users,
+    invites: Arc<dyn InviteRepository>,
+    federation: Federation,
+    emails: Emails,
+    mailer: Arc<dyn Mailer>,
+    hash_config: argon2::Config<'static>,
+    admin: String,
 }
 
 impl Service {
-    pub fn new(refresh_token: RefreshToken, users: sled::Tree, invites: sled::Tree) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        refresh_token: RefreshToken,
+        pats: PersonalAccessTokens,
+        throttle: Throttle,
+        totp: Totp,
+        roles: Roles,
+        users: Users,
+        invites: Arc<dyn InviteRepository>,
+        federation: Federation,
+        emails: Emails,
+        mailer: Arc<dyn Mailer>,
+        hash_config: argon2::Config<'static>,
+        admin: String,
+    ) -> Self {
         Self {
             refresh_token,
+            pats,
+            throttle,
+            totp,
+            roles,
             users,
             invites,
+            federation,
+            emails,
+            mailer,
+            hash_config,
+            admin,
         }
     }
 
-    fn get_username<'a, T>(request: &'a Request<T>) -> &'a str {
+    fn get_username<T>(request: &Request<T>) -> &str {
         &request.extensions().get::<AccessTokenClaims>().unwrap().sub
     }
 }
@@ -34,6 +78,7 @@ impl userpb::user_server::User for Service {
         &self,
         request: Request<userpb::GetRefreshTokensReq>,
     ) -> TonicResult<userpb::GetRefreshTokensRes> {
+        require_scope(&request, Scope::SessionsRead)?;
         let claims = request.extensions().get::<AccessTokenClaims>().unwrap();
         let tokens = self.refresh_token.get_all(&claims.sub);
         Ok(Response::new(userpb::GetRefreshTokensRes {
@@ -49,10 +94,11 @@ impl userpb::user_server::User for Service {
         &self,
         request: Request<userpb::DeleteRefreshTokenReq>,
     ) -> TonicResult<userpb::DeleteRefreshTokenRes> {
+        require_scope(&request, Scope::SessionsWrite)?;
         let username = Self::get_username(&request);
         let request = request.get_ref();
         let token = &request.refresh_token;
-        self.refresh_token.delete(&username, token);
+        self.refresh_token.delete(username, token);
         Ok(Response::new(userpb::DeleteRefreshTokenRes {
             payload: Some(userpb::delete_refresh_token_res::Payload::Ok(
                 userpb::delete_refresh_token_res::Ok {},
@@ -64,43 +110,55 @@ impl userpb::user_server::User for Service {
         &self,
         request: Request<userpb::ChangePasswordReq>,
     ) -> TonicResult<userpb::ChangePasswordRes> {
-        let username = Self::get_username(&request);
+        require_scope(&request, Scope::Account)?;
+        let claims = request.extensions().get::<AccessTokenClaims>().unwrap();
+        let username = &claims.sub;
         let request = request.get_ref();
         let old_password = &request.old_password;
         let new_password = &request.new_password;
         if new_password.len() < 3 {
             return Err(Status::new(Code::InvalidArgument, "Username invalid."));
         }
-        let hash = match self.users.get(&username) {
-            Ok(Some(users)) => users,
-            _ => Err(Status::new(Code::InvalidArgument, "User does not exist"))?,
-        };
-        if argon2::verify_encoded(
-            &std::str::from_utf8(&hash).unwrap(),
-            old_password.as_bytes(),
-        ) != Ok(true)
-        {
+        if !self.users.exists(username) {
+            Err(Status::new(Code::InvalidArgument, "User does not exist"))?;
+        }
+        if self.users.check_password(username, old_password).is_none() {
             Err(Status::new(Code::InvalidArgument, "Invalid new password"))?;
         };
-        let new_hash = match argon2::hash_encoded(
-            new_password.as_bytes(),
-            crate::SALT.as_bytes(),
-            &(argon2::Config::default()),
-        ) {
-            Ok(hash) => hash,
-            _ => {
-                return Err(Status::new(
-                    Code::Unknown,
-                    "Unknown error when hashing your password",
-                ))
-            }
-        };
+        let new_hash = password::hash(new_password, &self.hash_config)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
         self.users
-            .insert(&username, new_hash.as_bytes())
+            .set_password(username, &new_hash)
             .map_err(|_| Status::new(Code::Unknown, "Cannot insert new password"))?;
+        // A session opened with the old password should not survive it
+        let except = if request.keep_current_session {
+            claims.sid.as_str()
+        } else {
+            ""
+        };
+        let revoked = self.refresh_token.delete_all(username, except) as u32;
         Ok(Response::new(userpb::ChangePasswordRes {
             payload: Some(userpb::change_password_res::Payload::Ok(
-                userpb::change_password_res::Ok {},
+                userpb::change_password_res::Ok { revoked },
+            )),
+        }))
+    }
+
+    async fn revoke_all_sessions(
+        &self,
+        request: Request<userpb::RevokeAllSessionsReq>,
+    ) -> TonicResult<userpb::RevokeAllSessionsRes> {
+        require_scope(&request, Scope::SessionsWrite)?;
+        let claims = request.extensions().get::<AccessTokenClaims>().unwrap();
+        let except = if request.get_ref().keep_current_session {
+            claims.sid.as_str()
+        } else {
+            ""
+        };
+        let revoked = self.refresh_token.delete_all(&claims.sub, except) as u32;
+        Ok(Response::new(userpb::RevokeAllSessionsRes {
+            payload: Some(userpb::revoke_all_sessions_res::Payload::Ok(
+                userpb::revoke_all_sessions_res::Ok { revoked },
             )),
         }))
     }
@@ -109,8 +167,10 @@ impl userpb::user_server::User for Service {
         &self,
         request: Request<userpb::GetInviteTokensReq>,
     ) -> TonicResult<userpb::GetInviteTokensRes> {
+        require_scope(&request, Scope::InvitesRead)?;
         let username = Self::get_username(&request);
-        let tokens = invite::get(&self.invites, username).unwrap();
+        let tokens =
+            invite::get(&*self.invites, username).map_err(|e| Status::new(Code::Unknown, e))?;
         Ok(Response::new(userpb::GetInviteTokensRes {
             payload: Some(userpb::get_invite_tokens_res::Payload::Ok(
                 userpb::get_invite_tokens_res::Ok { tokens },
@@ -122,12 +182,365 @@ impl userpb::user_server::User for Service {
         &self,
         request: Request<userpb::CreateInviteTokenReq>,
     ) -> TonicResult<userpb::CreateInviteTokenRes> {
+        require_scope(&request, Scope::InvitesWrite)?;
         let username = Self::get_username(&request);
-        let token = invite::create(&self.invites, username).unwrap();
+        let req = request.get_ref();
+        if req.expiration_date != 0 && (req.expiration_date as usize) <= get_now_plus(0) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Expiration date is in the past",
+            ));
+        }
+        let token = invite::create(
+            &*self.invites,
+            username,
+            req.expiration_date,
+            req.max_uses,
+            &req.note,
+        )
+        .map_err(|e| Status::new(Code::Unknown, e))?;
         Ok(Response::new(userpb::CreateInviteTokenRes {
             payload: Some(userpb::create_invite_token_res::Payload::Ok(
                 userpb::create_invite_token_res::Ok { token: Some(token) },
             )),
         }))
     }
+
+    async fn revoke_invite_token(
+        &self,
+        request: Request<userpb::RevokeInviteTokenReq>,
+    ) -> TonicResult<userpb::RevokeInviteTokenRes> {
+        require_scope(&request, Scope::InvitesWrite)?;
+        let username = Self::get_username(&request);
+        invite::delete(&*self.invites, username, &request.get_ref().token)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::RevokeInviteTokenRes {
+            payload: Some(userpb::revoke_invite_token_res::Payload::Ok(
+                userpb::revoke_invite_token_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn get_invitees(
+        &self,
+        request: Request<userpb::GetInviteesReq>,
+    ) -> TonicResult<userpb::GetInviteesRes> {
+        require_scope(&request, Scope::InvitesRead)?;
+        let username = Self::get_username(&request);
+        let invitations = invite::invitees(&*self.invites, username)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        Ok(Response::new(userpb::GetInviteesRes {
+            payload: Some(userpb::get_invitees_res::Payload::Ok(
+                userpb::get_invitees_res::Ok { invitations },
+            )),
+        }))
+    }
+
+    async fn get_invite_tree(
+        &self,
+        request: Request<userpb::GetInviteTreeReq>,
+    ) -> TonicResult<userpb::GetInviteTreeRes> {
+        roles::authorize(&request, Permission::ViewInviteTree)?;
+        let root = match request.get_ref().root.as_str() {
+            "" => self.admin.as_str(),
+            root => root,
+        };
+        let root = invite::tree(&*self.invites, root).map_err(|e| Status::new(Code::Unknown, e))?;
+        Ok(Response::new(userpb::GetInviteTreeRes {
+            payload: Some(userpb::get_invite_tree_res::Payload::Ok(
+                userpb::get_invite_tree_res::Ok { root: Some(root) },
+            )),
+        }))
+    }
+
+    async fn clear_lockout(
+        &self,
+        request: Request<userpb::ClearLockoutReq>,
+    ) -> TonicResult<userpb::ClearLockoutRes> {
+        roles::authorize(&request, Permission::ClearLockout)?;
+        let request = request.into_inner();
+        let ip = match request.ip.as_str() {
+            "" => None,
+            ip => Some(
+                ip.parse()
+                    .map_err(|_| Status::new(Code::InvalidArgument, "Invalid ip"))?,
+            ),
+        };
+        if request.username.is_empty() && ip.is_none() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Username or ip required",
+            ));
+        }
+        let cleared = self.throttle.clear(&request.username, ip);
+        Ok(Response::new(userpb::ClearLockoutRes {
+            payload: Some(userpb::clear_lockout_res::Payload::Ok(
+                userpb::clear_lockout_res::Ok { cleared },
+            )),
+        }))
+    }
+
+    async fn get_totp(
+        &self,
+        request: Request<userpb::GetTotpReq>,
+    ) -> TonicResult<userpb::GetTotpRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        Ok(Response::new(userpb::GetTotpRes {
+            payload: Some(userpb::get_totp_res::Payload::Ok(
+                userpb::get_totp_res::Ok {
+                    enabled: self.totp.is_enabled(username),
+                    recovery_codes_left: self.totp.recovery_codes_left(username),
+                },
+            )),
+        }))
+    }
+
+    async fn enroll_totp(
+        &self,
+        request: Request<userpb::EnrollTotpReq>,
+    ) -> TonicResult<userpb::EnrollTotpRes> {
+        require_scope(&request, Scope::Account)?;
+        let (secret, uri) = self
+            .totp
+            .enroll(Self::get_username(&request))
+            .map_err(|e| Status::new(Code::FailedPrecondition, e))?;
+        Ok(Response::new(userpb::EnrollTotpRes {
+            payload: Some(userpb::enroll_totp_res::Payload::Ok(
+                userpb::enroll_totp_res::Ok { secret, uri },
+            )),
+        }))
+    }
+
+    async fn confirm_totp(
+        &self,
+        request: Request<userpb::ConfirmTotpReq>,
+    ) -> TonicResult<userpb::ConfirmTotpRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        let recovery_codes = self
+            .totp
+            .confirm(username, &request.get_ref().code)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::ConfirmTotpRes {
+            payload: Some(userpb::confirm_totp_res::Payload::Ok(
+                userpb::confirm_totp_res::Ok { recovery_codes },
+            )),
+        }))
+    }
+
+    async fn disable_totp(
+        &self,
+        request: Request<userpb::DisableTotpReq>,
+    ) -> TonicResult<userpb::DisableTotpRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        self.totp
+            .disable(username, &request.get_ref().code)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::DisableTotpRes {
+            payload: Some(userpb::disable_totp_res::Payload::Ok(
+                userpb::disable_totp_res::Ok {},
+            )),
+        }))
+    }
+
+    async fn regenerate_recovery_codes(
+        &self,
+        request: Request<userpb::RegenerateRecoveryCodesReq>,
+    ) -> TonicResult<userpb::RegenerateRecoveryCodesRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        let recovery_codes = self
+            .totp
+            .regenerate_recovery_codes(username, &request.get_ref().code)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::RegenerateRecoveryCodesRes {
+            payload: Some(userpb::regenerate_recovery_codes_res::Payload::Ok(
+                userpb::regenerate_recovery_codes_res::Ok { recovery_codes },
+            )),
+        }))
+    }
+
+    async fn set_role(
+        &self,
+        request: Request<userpb::SetRoleReq>,
+    ) -> TonicResult<userpb::SetRoleRes> {
+        roles::authorize(&request, Permission::ManageRoles)?;
+        let request = request.into_inner();
+        if !self.users.exists(&request.username) {
+            return Err(Status::new(Code::NotFound, "Unknown user"));
+        }
+        let roles = self
+            .roles
+            .set(&request.username, &request.role, request.granted)
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::SetRoleRes {
+            payload: Some(userpb::set_role_res::Payload::Ok(
+                userpb::set_role_res::Ok { roles },
+            )),
+        }))
+    }
+
+    async fn create_personal_access_token(
+        &self,
+        request: Request<userpb::CreatePersonalAccessTokenReq>,
+    ) -> TonicResult<userpb::CreatePersonalAccessTokenRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request).to_string();
+        let request = request.into_inner();
+        let (secret, token) = self
+            .pats
+            .create(
+                &username,
+                &request.name,
+                request.scopes,
+                request.expiration_date,
+            )
+            .map_err(|e| Status::new(Code::InvalidArgument, e))?;
+        Ok(Response::new(userpb::CreatePersonalAccessTokenRes {
+            payload: Some(userpb::create_personal_access_token_res::Payload::Ok(
+                userpb::create_personal_access_token_res::Ok {
+                    secret,
+                    token: Some(token),
+                },
+            )),
+        }))
+    }
+
+    async fn get_personal_access_tokens(
+        &self,
+        request: Request<userpb::GetPersonalAccessTokensReq>,
+    ) -> TonicResult<userpb::GetPersonalAccessTokensRes> {
+        require_scope(&request, Scope::Account)?;
+        let tokens = self.pats.get_all(Self::get_username(&request));
+        Ok(Response::new(userpb::GetPersonalAccessTokensRes {
+            payload: Some(userpb::get_personal_access_tokens_res::Payload::Ok(
+                userpb::get_personal_access_tokens_res::Ok { tokens },
+            )),
+        }))
+    }
+
+    async fn revoke_personal_access_token(
+        &self,
+        request: Request<userpb::RevokePersonalAccessTokenReq>,
+    ) -> TonicResult<userpb::RevokePersonalAccessTokenRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        match self.pats.revoke(username, &request.get_ref().id) {
+            Ok(true) => Ok(Response::new(userpb::RevokePersonalAccessTokenRes {
+                payload: Some(userpb::revoke_personal_access_token_res::Payload::Ok(
+                    userpb::revoke_personal_access_token_res::Ok {},
+                )),
+            })),
+            Ok(false) => Err(Status::new(Code::NotFound, "Unknown token")),
+            Err(e) => Err(Status::new(Code::Unknown, e)),
+        }
+    }
+
+    async fn start_federated_link(
+        &self,
+        request: Request<userpb::StartFederatedLinkReq>,
+    ) -> TonicResult<userpb::StartFederatedLinkRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        let (authorization_url, state) = self
+            .federation
+            .start(&request.get_ref().provider, username)
+            .await
+            .map_err(FederationError::into_status)?;
+        Ok(Response::new(userpb::StartFederatedLinkRes {
+            payload: Some(userpb::start_federated_link_res::Payload::Ok(
+                userpb::start_federated_link_res::Ok {
+                    authorization_url,
+                    state,
+                },
+            )),
+        }))
+    }
+
+    async fn get_federated_identities(
+        &self,
+        request: Request<userpb::GetFederatedIdentitiesReq>,
+    ) -> TonicResult<userpb::GetFederatedIdentitiesRes> {
+        require_scope(&request, Scope::Account)?;
+        let identities = self.federation.get_links(Self::get_username(&request));
+        Ok(Response::new(userpb::GetFederatedIdentitiesRes {
+            payload: Some(userpb::get_federated_identities_res::Payload::Ok(
+                userpb::get_federated_identities_res::Ok { identities },
+            )),
+        }))
+    }
+
+    async fn unlink_federated_identity(
+        &self,
+        request: Request<userpb::UnlinkFederatedIdentityReq>,
+    ) -> TonicResult<userpb::UnlinkFederatedIdentityRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        let request = request.get_ref();
+        match self
+            .federation
+            .unlink(username, &request.provider, &request.subject)
+        {
+            Ok(true) => Ok(Response::new(userpb::UnlinkFederatedIdentityRes {
+                payload: Some(userpb::unlink_federated_identity_res::Payload::Ok(
+                    userpb::unlink_federated_identity_res::Ok {},
+                )),
+            })),
+            Ok(false) => Err(Status::new(Code::NotFound, "Unknown identity")),
+            Err(e) => Err(Status::new(Code::Unknown, e)),
+        }
+    }
+
+    async fn set_email(
+        &self,
+        request: Request<userpb::SetEmailReq>,
+    ) -> TonicResult<userpb::SetEmailRes> {
+        require_scope(&request, Scope::Account)?;
+        let username = Self::get_username(&request);
+        let email = request.get_ref().email.trim();
+        if !mailer::valid_address(email) {
+            return Err(Status::new(Code::InvalidArgument, "Invalid email address"));
+        }
+        let changed = self
+            .emails
+            .set(username, email)
+            .map_err(|e| Status::new(Code::Unknown, e))?;
+        let token = match changed {
+            true => self
+                .emails
+                .new_token(userpb::email_token::Purpose::VerifyEmail, username, email)
+                .map_err(|e| Status::new(Code::Unknown, e))?,
+            false => None,
+        };
+        if let Some(token) = &token {
+            self.mailer
+                .send(&email::verification_mail(email, username, token))
+                .await
+                .map_err(|e| {
+                    Status::new(Code::Unavailable, format!("Cannot send the mail: {}", e))
+                })?;
+        }
+        Ok(Response::new(userpb::SetEmailRes {
+            payload: Some(userpb::set_email_res::Payload::Ok(
+                userpb::set_email_res::Ok {
+                    verification_sent: token.is_some(),
+                },
+            )),
+        }))
+    }
+
+    async fn get_email(
+        &self,
+        request: Request<userpb::GetEmailReq>,
+    ) -> TonicResult<userpb::GetEmailRes> {
+        require_scope(&request, Scope::Account)?;
+        let email = self.emails.get(Self::get_username(&request));
+        Ok(Response::new(userpb::GetEmailRes {
+            payload: Some(userpb::get_email_res::Payload::Ok(
+                userpb::get_email_res::Ok { email },
+            )),
+        }))
+    }
 }
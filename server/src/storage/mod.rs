@@ -0,0 +1,127 @@
+use crate::config::StorageConfig;
+use crate::migrations;
+use proto::server::user::{Invitation, InviteToken, RefreshToken, UserRecord};
+use std::sync::Arc;
+
+mod sled_backend;
+mod sqlite_backend;
+pub use sled_backend::SledStorage;
+pub use sqlite_backend::SqliteStorage;
+
+/*
+    Users, refresh tokens and invites are stored through these traits, in sled
+    or in SQLite depending on storage.backend. A backend only stores: the rules
+    (rotation, expiration, invite uses) are in users.rs, refresh_token.rs and
+    invite.rs. Everything else stays in the sled database. A new SQLite file
+    starts with what the sled backend held.
+
+    Values are handed over as stored: RefreshToken without its token, family
+    empty for the tokens created before rotation, InviteToken without its token
+    and used flag.
+*/
+
+pub enum CreateError {
+    Rejected(String), // Username taken or invite refused, for the user
+    Database(String),
+}
+
+pub trait UserRepository: Send + Sync {
+    fn get(&self, username: &str) -> Result<Option<UserRecord>, String>;
+    // The invite (its key) is redeemed in the same transaction, None for the
+    // bootstrap admin and the federated signups
+    fn create(
+        &self,
+        username: &str,
+        record: &UserRecord,
+        invite: Option<&str>,
+    ) -> Result<(), CreateError>;
+    // Atomic, f can run more than once. false if there is no such user.
+    fn update(&self, username: &str, f: &dyn Fn(&mut UserRecord)) -> Result<bool, String>;
+    fn remove(&self, username: &str) -> Result<(), String>;
+    // In username order, from the one after `after`
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, UserRecord)>, String>;
+}
+
+pub trait RefreshTokenRepository: Send + Sync {
+    fn insert(&self, username: &str, token: &str, value: &RefreshToken) -> Result<(), String>;
+    fn get(&self, username: &str, token: &str) -> Result<Option<RefreshToken>, String>;
+    // Only if the token is still `current`, false otherwise
+    fn replace(
+        &self,
+        username: &str,
+        token: &str,
+        current: &RefreshToken,
+        new: &RefreshToken,
+    ) -> Result<bool, String>;
+    // false if there is no such token
+    fn update(
+        &self,
+        username: &str,
+        token: &str,
+        f: &dyn Fn(&mut RefreshToken),
+    ) -> Result<bool, String>;
+    fn remove(&self, username: &str, token: &str) -> Result<bool, String>;
+    // Tokens of a user in token order, with their token
+    fn list(&self, username: &str) -> Result<Vec<(String, RefreshToken)>, String>;
+    // Owner of a token
+    fn find(&self, token: &str) -> Result<Option<(String, RefreshToken)>, String>;
+    // Return how many were removed
+    fn remove_where(&self, f: &dyn Fn(&RefreshToken) -> bool) -> Result<usize, String>;
+}
+
+// Invites are keyed "[owner]:[random]", invitations by inviter and invitee
+pub trait InviteRepository: Send + Sync {
+    // Invites of a user in key order, with their key
+    fn get(&self, owner: &str) -> Result<Vec<(String, InviteToken)>, String>;
+    // Invites of every user in key order, from the one after `after`
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, InviteToken)>, String>;
+    // false if the key is taken
+    fn insert(&self, key: &str, invite: &InviteToken) -> Result<bool, String>;
+    fn remove(&self, key: &str) -> Result<bool, String>;
+    fn remove_all(&self, owner: &str) -> Result<(), String>;
+    // In invitee order
+    fn invitees(&self, inviter: &str) -> Result<Vec<Invitation>, String>;
+    fn inviter(&self, invitee: &str) -> Result<Option<Invitation>, String>;
+    // Both ways, when the user is deleted
+    fn remove_invitations(&self, username: &str) -> Result<(), String>;
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    pub users: Arc<dyn UserRepository>,
+    pub refresh_tokens: Arc<dyn RefreshTokenRepository>,
+    pub invites: Arc<dyn InviteRepository>,
+}
+
+pub fn open(config: &StorageConfig, db: &sled::Db) -> Result<Storage, String> {
+    let user_fields = migrations::UserFieldTrees::open(db)?;
+    let storage = match config.backend.as_str() {
+        "sqlite" => {
+            let sled = SledStorage::open(db)?;
+            let sqlite = Arc::new(SqliteStorage::open(
+                &config.sqlite_path,
+                &sled,
+                user_fields.as_ref(),
+            )?);
+            Storage {
+                users: sqlite.clone(),
+                refresh_tokens: sqlite.clone(),
+                invites: sqlite,
+            }
+        }
+        // Checked in Config::validate
+        _ => {
+            let sled = Arc::new(SledStorage::open(db)?);
+            Storage {
+                users: sled.clone(),
+                refresh_tokens: sled.clone(),
+                invites: sled,
+            }
+        }
+    };
+    // Copied by the sled migrations or the SQLite upgrade
+    if user_fields.is_some() {
+        migrations::drop_user_field_trees(db)?;
+    }
+    Ok(storage)
+}
@@ -0,0 +1,382 @@
+use super::{CreateError, InviteRepository, RefreshTokenRepository, UserRepository};
+use crate::invite;
+use proto::prost::Message;
+use proto::server::user::{Invitation, InviteToken, RefreshToken, UserRecord};
+use sled::transaction::{abort, TransactionError, Transactional};
+use std::ops::Bound;
+
+// users tree
+// key : "[username]"
+// value : UserRecord protobuf
+
+// refresh_tokens tree
+// key : "[username]:[token]"
+// value : RefreshToken protobuf, without its token
+
+// refresh_token_owners tree, to find a token without its username
+// key : "[token]"
+// value : "[username]"
+
+// invites tree
+// key : "[username]:[random]"
+// value : InviteToken protobuf, without its token
+
+// invitations tree
+// key : "[inviter]:[invitee]"
+// value : Invitation protobuf
+
+pub struct SledStorage {
+    users: sled::Tree,
+    refresh_tokens: sled::Tree,
+    refresh_token_owners: sled::Tree,
+    invites: sled::Tree,
+    invitations: sled::Tree,
+}
+
+fn token_key(username: &str, token: &str) -> String {
+    format!("{}:{}", username, token)
+}
+
+// The part after the first ':'
+fn suffix(key: &[u8]) -> String {
+    let key = String::from_utf8_lossy(key);
+    key.split_once(':')
+        .map_or(String::new(), |(_, suffix)| suffix.to_string())
+}
+
+fn decode_token(value: &[u8]) -> Result<RefreshToken, String> {
+    RefreshToken::decode(value).map_err(|_| "Malformated refresh token".to_string())
+}
+
+fn decode_invite(value: &[u8]) -> InviteToken {
+    // Invites created before redemption tracking only hold a placeholder value
+    InviteToken::decode(value).unwrap_or_default()
+}
+
+fn db_error(e: sled::Error) -> String {
+    format!("database error {}", e)
+}
+
+// For the transactions that never abort
+fn transaction_error(e: TransactionError<()>) -> String {
+    match e {
+        TransactionError::Abort(()) => "database transaction aborted".to_string(),
+        TransactionError::Storage(e) => db_error(e),
+    }
+}
+
+impl SledStorage {
+    pub fn open(db: &sled::Db) -> Result<Self, String> {
+        let tree = |name: &str| db.open_tree(name).map_err(db_error);
+        Ok(Self {
+            users: tree("users")?,
+            refresh_tokens: tree("refresh_tokens")?,
+            refresh_token_owners: tree("refresh_token_owners")?,
+            invites: tree("invites")?,
+            invitations: tree("invitations")?,
+        })
+    }
+}
+
+impl UserRepository for SledStorage {
+    fn get(&self, username: &str) -> Result<Option<UserRecord>, String> {
+        match self.users.get(username).map_err(db_error)? {
+            Some(value) => UserRecord::decode(value.as_ref())
+                .map(Some)
+                .map_err(|_| "Malformated user record".to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn create(
+        &self,
+        username: &str,
+        record: &UserRecord,
+        invite: Option<&str>,
+    ) -> Result<(), CreateError> {
+        let trees = (&self.users, &self.invites, &self.invitations);
+        let res = trees.transaction(|(users, invites, invitations)| {
+            if users.get(username.as_bytes())?.is_some() {
+                return abort("Username already exist".to_string());
+            }
+            if let Some(key) = invite {
+                let token = match invites.get(key.as_bytes())? {
+                    Some(value) => decode_invite(&value),
+                    None => return abort("Invalid invite".to_string()),
+                };
+                let (token, invitation) = match invite::redeem(key, token, username) {
+                    Ok(redeemed) => redeemed,
+                    Err(e) => return abort(e),
+                };
+                invites.insert(key.as_bytes(), token.encode_to_vec())?;
+                invitations.insert(
+                    format!("{}:{}", invitation.inviter, invitation.invitee).as_bytes(),
+                    invitation.encode_to_vec(),
+                )?;
+            }
+            users.insert(username.as_bytes(), record.encode_to_vec())?;
+            Ok(())
+        });
+        match res {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(CreateError::Rejected(e)),
+            Err(TransactionError::Storage(e)) => Err(CreateError::Database(db_error(e))),
+        }
+    }
+
+    fn update(&self, username: &str, f: &dyn Fn(&mut UserRecord)) -> Result<bool, String> {
+        let mut found = false;
+        self.users
+            .fetch_and_update(username, |value| {
+                let value = value?;
+                // None would remove the entry, an undecodable one is kept as is
+                match UserRecord::decode(value) {
+                    Ok(mut record) => {
+                        f(&mut record);
+                        found = true;
+                        Some(record.encode_to_vec())
+                    }
+                    Err(_) => Some(value.to_vec()),
+                }
+            })
+            .map_err(db_error)?;
+        Ok(found)
+    }
+
+    fn remove(&self, username: &str) -> Result<(), String> {
+        self.users.remove(username).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, UserRecord)>, String> {
+        let iter = match after {
+            "" => self.users.iter(),
+            after => self
+                .users
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+        };
+        let mut users = Vec::new();
+        for entry in iter {
+            let (key, value) = entry.map_err(db_error)?;
+            if let Ok(record) = UserRecord::decode(value.as_ref()) {
+                users.push((String::from_utf8_lossy(&key).to_string(), record));
+            }
+            if users.len() == limit {
+                break;
+            }
+        }
+        Ok(users)
+    }
+}
+
+impl RefreshTokenRepository for SledStorage {
+    fn insert(&self, username: &str, token: &str, value: &RefreshToken) -> Result<(), String> {
+        let key = token_key(username, token);
+        let value = value.encode_to_vec();
+        (&self.refresh_tokens, &self.refresh_token_owners)
+            .transaction(|(tokens, owners)| {
+                tokens.insert(key.as_bytes(), value.as_slice())?;
+                owners.insert(token.as_bytes(), username.as_bytes())?;
+                Ok(())
+            })
+            .map_err(transaction_error)
+    }
+
+    fn get(&self, username: &str, token: &str) -> Result<Option<RefreshToken>, String> {
+        match self
+            .refresh_tokens
+            .get(token_key(username, token))
+            .map_err(db_error)?
+        {
+            Some(value) => decode_token(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn replace(
+        &self,
+        username: &str,
+        token: &str,
+        current: &RefreshToken,
+        new: &RefreshToken,
+    ) -> Result<bool, String> {
+        let key = token_key(username, token);
+        let res = self.refresh_tokens.transaction(|tokens| {
+            match tokens.get(key.as_bytes())? {
+                Some(value) if RefreshToken::decode(value.as_ref()).as_ref() == Ok(current) => {}
+                _ => return Ok(false),
+            }
+            tokens.insert(key.as_bytes(), new.encode_to_vec())?;
+            Ok(true)
+        });
+        match res {
+            Ok(replaced) => Ok(replaced),
+            Err(TransactionError::Abort(())) => Ok(false),
+            Err(TransactionError::Storage(e)) => Err(db_error(e)),
+        }
+    }
+
+    fn update(
+        &self,
+        username: &str,
+        token: &str,
+        f: &dyn Fn(&mut RefreshToken),
+    ) -> Result<bool, String> {
+        let mut found = false;
+        self.refresh_tokens
+            .fetch_and_update(token_key(username, token), |value| {
+                let value = value?;
+                match RefreshToken::decode(value) {
+                    Ok(mut token) => {
+                        f(&mut token);
+                        found = true;
+                        Some(token.encode_to_vec())
+                    }
+                    Err(_) => Some(value.to_vec()),
+                }
+            })
+            .map_err(db_error)?;
+        Ok(found)
+    }
+
+    fn remove(&self, username: &str, token: &str) -> Result<bool, String> {
+        let key = token_key(username, token);
+        (&self.refresh_tokens, &self.refresh_token_owners)
+            .transaction(|(tokens, owners)| {
+                let removed = tokens.remove(key.as_bytes())?;
+                if removed.is_some() {
+                    owners.remove(token.as_bytes())?;
+                }
+                Ok(removed.is_some())
+            })
+            .map_err(transaction_error)
+    }
+
+    fn list(&self, username: &str) -> Result<Vec<(String, RefreshToken)>, String> {
+        self.refresh_tokens
+            .scan_prefix(format!("{}:", username))
+            .map(|entry| {
+                let (key, value) = entry.map_err(db_error)?;
+                Ok((suffix(&key), decode_token(&value)?))
+            })
+            .collect()
+    }
+
+    fn find(&self, token: &str) -> Result<Option<(String, RefreshToken)>, String> {
+        let username = match self.refresh_token_owners.get(token).map_err(db_error)? {
+            Some(username) => String::from_utf8_lossy(&username).to_string(),
+            None => return Ok(None),
+        };
+        match RefreshTokenRepository::get(self, &username, token)? {
+            Some(value) => Ok(Some((username, value))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_where(&self, f: &dyn Fn(&RefreshToken) -> bool) -> Result<usize, String> {
+        let matching: Vec<sled::IVec> = self
+            .refresh_tokens
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| matches!(decode_token(value), Ok(token) if f(&token)))
+            .map(|(key, _)| key)
+            .collect();
+        let mut removed = 0;
+        for key in matching {
+            if let Some((username, token)) = String::from_utf8_lossy(&key).split_once(':') {
+                if RefreshTokenRepository::remove(self, username, token)? {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl InviteRepository for SledStorage {
+    fn get(&self, owner: &str) -> Result<Vec<(String, InviteToken)>, String> {
+        self.invites
+            .scan_prefix(format!("{}:", owner))
+            .map(|entry| {
+                let (key, value) = entry.map_err(db_error)?;
+                Ok((
+                    String::from_utf8_lossy(&key).to_string(),
+                    decode_invite(&value),
+                ))
+            })
+            .collect()
+    }
+
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, InviteToken)>, String> {
+        let iter = match after {
+            "" => self.invites.iter(),
+            after => self
+                .invites
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+        };
+        iter.take(limit)
+            .map(|entry| {
+                let (key, value) = entry.map_err(db_error)?;
+                Ok((
+                    String::from_utf8_lossy(&key).to_string(),
+                    decode_invite(&value),
+                ))
+            })
+            .collect()
+    }
+
+    fn insert(&self, key: &str, invite: &InviteToken) -> Result<bool, String> {
+        let inserted = self
+            .invites
+            .compare_and_swap(key, None as Option<&[u8]>, Some(invite.encode_to_vec()))
+            .map_err(db_error)?;
+        Ok(inserted.is_ok())
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, String> {
+        Ok(self.invites.remove(key).map_err(db_error)?.is_some())
+    }
+
+    fn remove_all(&self, owner: &str) -> Result<(), String> {
+        for entry in self.invites.scan_prefix(format!("{}:", owner)) {
+            let (key, _) = entry.map_err(db_error)?;
+            self.invites.remove(key).map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    fn invitees(&self, inviter: &str) -> Result<Vec<Invitation>, String> {
+        self.invitations
+            .scan_prefix(format!("{}:", inviter))
+            .map(|entry| {
+                let (_, value) = entry.map_err(db_error)?;
+                Invitation::decode(value.as_ref()).map_err(|_| "Malformated invitation".to_string())
+            })
+            .collect()
+    }
+
+    fn inviter(&self, invitee: &str) -> Result<Option<Invitation>, String> {
+        let key_suffix = format!(":{}", invitee);
+        for entry in self.invitations.iter() {
+            let (key, value) = entry.map_err(db_error)?;
+            if key.ends_with(key_suffix.as_bytes()) {
+                return Invitation::decode(value.as_ref())
+                    .map(Some)
+                    .map_err(|_| "Malformated invitation".to_string());
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove_invitations(&self, username: &str) -> Result<(), String> {
+        let prefix = format!("{}:", username);
+        let key_suffix = format!(":{}", username);
+        for entry in self.invitations.iter() {
+            let (key, _) = entry.map_err(db_error)?;
+            if key.starts_with(prefix.as_bytes()) || key.ends_with(key_suffix.as_bytes()) {
+                self.invitations.remove(key).map_err(db_error)?;
+            }
+        }
+        Ok(())
+    }
+}
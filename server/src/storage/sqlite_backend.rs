@@ -0,0 +1,775 @@
+use super::{CreateError, InviteRepository, RefreshTokenRepository, SledStorage, UserRepository};
+use crate::invite;
+use crate::migrations::UserFieldTrees;
+use proto::server::user::{
+    Invitation, InviteToken, RefreshToken, TotpSecret, UserEmail, UserRecord,
+};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/*
+    One table per repository, a column per field so the database can be read
+    with the sqlite3 shell. A single connection: the queries are short and
+    sled did not run them in parallel either.
+
+    The schema is versioned with PRAGMA user_version. A new file gets SCHEMA,
+    the latest version, and everything the sled backend held so switching
+    backends keeps the users. An older file runs the UPGRADES above its
+    version, in the same transaction. Files created before versioning are at 0
+    with the version 1 tables. A newer file is refused.
+
+    Version 3 moved the user fields of the sled trees into the users table,
+    the upgrade copies them.
+*/
+
+const SCHEMA_VERSION: u32 = 3;
+
+// (version reached, SQL from the previous version)
+const UPGRADES: &[(u32, &str)] = &[
+    (
+        2,
+        "ALTER TABLE refresh_tokens ADD COLUMN scope TEXT NOT NULL DEFAULT '';",
+    ),
+    (
+        3,
+        "ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN roles TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE users ADD COLUMN email TEXT NOT NULL DEFAULT '';
+        ALTER TABLE users ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN email_verified_date INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN totp_secret BLOB NOT NULL DEFAULT x'';
+        ALTER TABLE users ADD COLUMN totp_confirmed INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE users ADD COLUMN totp_recovery_codes BLOB NOT NULL DEFAULT x'';",
+    ),
+];
+
+const IMPORT_PAGE: usize = 500;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    version INTEGER NOT NULL,
+    password_hash TEXT NOT NULL,
+    created_date INTEGER NOT NULL,
+    last_login_date INTEGER NOT NULL,
+    disabled INTEGER NOT NULL,
+    roles TEXT NOT NULL, -- json array
+    email TEXT NOT NULL, -- empty -> none
+    email_verified INTEGER NOT NULL,
+    email_verified_date INTEGER NOT NULL,
+    totp_secret BLOB NOT NULL, -- empty -> not enrolled
+    totp_confirmed INTEGER NOT NULL,
+    totp_last_step INTEGER NOT NULL,
+    totp_recovery_codes BLOB NOT NULL -- SHA-256 hashes, concatenated
+);
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    username TEXT NOT NULL,
+    token TEXT NOT NULL,
+    family TEXT NOT NULL,
+    from_address TEXT NOT NULL,
+    creation_date INTEGER NOT NULL,
+    expiration_date INTEGER NOT NULL,
+    last_use INTEGER NOT NULL,
+    rotated INTEGER NOT NULL,
+    compromised INTEGER NOT NULL,
+    user_agent TEXT NOT NULL,
+    device_name TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    PRIMARY KEY (username, token)
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_token ON refresh_tokens (token);
+CREATE TABLE IF NOT EXISTS invites (
+    key TEXT PRIMARY KEY NOT NULL,
+    owner TEXT NOT NULL,
+    expiration_date INTEGER NOT NULL,
+    max_uses INTEGER NOT NULL,
+    note TEXT NOT NULL,
+    used_by TEXT NOT NULL -- json array of usernames
+);
+CREATE INDEX IF NOT EXISTS invites_owner ON invites (owner);
+CREATE TABLE IF NOT EXISTS invitations (
+    inviter TEXT NOT NULL,
+    invitee TEXT NOT NULL,
+    date INTEGER NOT NULL,
+    invite TEXT NOT NULL,
+    PRIMARY KEY (inviter, invitee)
+);
+CREATE INDEX IF NOT EXISTS invitations_invitee ON invitations (invitee);
+";
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const USER_COLUMNS: &str = "username, version, password_hash, created_date, last_login_date, \
+    disabled, roles, email, email_verified, email_verified_date, totp_secret, totp_confirmed, \
+    totp_last_step, totp_recovery_codes";
+const RECOVERY_CODE_LEN: usize = 32; // SHA-256
+const TOKEN_COLUMNS: &str = "username, token, family, from_address, creation_date, \
+    expiration_date, last_use, rotated, compromised, user_agent, device_name, client_id, scope";
+const INVITE_COLUMNS: &str = "key, expiration_date, max_uses, note, used_by";
+const INVITATION_COLUMNS: &str = "inviter, invitee, date, invite";
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+fn db_error(e: rusqlite::Error) -> String {
+    format!("database error {}", e)
+}
+
+fn user_row(row: &Row) -> rusqlite::Result<(String, UserRecord)> {
+    let roles: String = row.get(6)?;
+    let email: String = row.get(7)?;
+    let totp_secret: Vec<u8> = row.get(10)?;
+    let recovery_codes: Vec<u8> = row.get(13)?;
+    Ok((
+        row.get(0)?,
+        UserRecord {
+            version: row.get(1)?,
+            password_hash: row.get(2)?,
+            created_date: row.get(3)?,
+            last_login_date: row.get(4)?,
+            disabled: row.get(5)?,
+            roles: serde_json::from_str(&roles).unwrap_or_default(),
+            email: match email.is_empty() {
+                true => None,
+                false => Some(UserEmail {
+                    email,
+                    verified: row.get(8)?,
+                    verified_date: row.get(9)?,
+                }),
+            },
+            totp: match totp_secret.is_empty() {
+                true => None,
+                false => Some(TotpSecret {
+                    secret: totp_secret,
+                    confirmed: row.get(11)?,
+                    last_step: row.get(12)?,
+                    recovery_codes: recovery_codes
+                        .chunks(RECOVERY_CODE_LEN)
+                        .map(<[u8]>::to_vec)
+                        .collect(),
+                }),
+            },
+        },
+    ))
+}
+
+// (username, token, value)
+fn token_row(row: &Row) -> rusqlite::Result<(String, String, RefreshToken)> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        RefreshToken {
+            token: String::new(), // Not stored
+            family: row.get(2)?,
+            from: row.get(3)?,
+            creation_date: row.get(4)?,
+            expiration_date: row.get(5)?,
+            last_use: row.get(6)?,
+            rotated: row.get(7)?,
+            compromised: row.get(8)?,
+            user_agent: row.get(9)?,
+            device_name: row.get(10)?,
+            client_id: row.get(11)?,
+            scope: row.get(12)?,
+        },
+    ))
+}
+
+fn invite_row(row: &Row) -> rusqlite::Result<(String, InviteToken)> {
+    let used_by: String = row.get(4)?;
+    Ok((
+        row.get(0)?,
+        InviteToken {
+            expiration_date: row.get(1)?,
+            max_uses: row.get(2)?,
+            note: row.get(3)?,
+            used_by: serde_json::from_str(&used_by).unwrap_or_default(),
+            ..InviteToken::default()
+        },
+    ))
+}
+
+fn invitation_row(row: &Row) -> rusqlite::Result<Invitation> {
+    Ok(Invitation {
+        inviter: row.get(0)?,
+        invitee: row.get(1)?,
+        date: row.get(2)?,
+        invite: row.get(3)?,
+    })
+}
+
+fn insert_token(
+    conn: &Connection,
+    username: &str,
+    token: &str,
+    value: &RefreshToken,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO refresh_tokens ({}) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            TOKEN_COLUMNS
+        ),
+        params![
+            username,
+            token,
+            value.family,
+            value.from,
+            value.creation_date,
+            value.expiration_date,
+            value.last_use,
+            value.rotated,
+            value.compromised,
+            value.user_agent,
+            value.device_name,
+            value.client_id,
+            value.scope,
+        ],
+    )?;
+    Ok(())
+}
+
+// Replace the user if it exists
+fn insert_user(conn: &Connection, username: &str, record: &UserRecord) -> rusqlite::Result<()> {
+    let email = record.email.clone().unwrap_or_default();
+    let totp = record.totp.clone().unwrap_or_default();
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO users ({}) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+            USER_COLUMNS
+        ),
+        params![
+            username,
+            record.version,
+            record.password_hash,
+            record.created_date,
+            record.last_login_date,
+            record.disabled,
+            serde_json::to_string(&record.roles).unwrap_or_default(),
+            email.email,
+            email.verified,
+            email.verified_date,
+            totp.secret,
+            totp.confirmed,
+            totp.last_step,
+            totp.recovery_codes.concat(),
+        ],
+    )?;
+    Ok(())
+}
+
+fn get_user(conn: &Connection, username: &str) -> rusqlite::Result<Option<UserRecord>> {
+    conn.query_row(
+        &format!("SELECT {} FROM users WHERE username = ?1", USER_COLUMNS),
+        params![username],
+        user_row,
+    )
+    .optional()
+    .map(|found| found.map(|(_, record)| record))
+}
+
+// The user fields still in the sled trees, from before version 3
+fn fold_user_fields(conn: &Connection, trees: &UserFieldTrees) -> Result<usize, String> {
+    let usernames: Vec<String> = {
+        let mut statement = conn
+            .prepare("SELECT username FROM users")
+            .map_err(db_error)?;
+        let usernames = statement
+            .query_map([], |row| row.get(0))
+            .map_err(db_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error)?;
+        usernames
+    };
+    let mut folded = 0;
+    for username in usernames.iter() {
+        let mut record = match get_user(conn, username).map_err(db_error)? {
+            Some(record) => record,
+            None => continue,
+        };
+        if trees.fold(username, &mut record)? {
+            folded += 1;
+        }
+        insert_user(conn, username, &record).map_err(db_error)?;
+    }
+    Ok(folded)
+}
+
+// false if the key is taken
+fn insert_invite(conn: &Connection, key: &str, invite: &InviteToken) -> rusqlite::Result<bool> {
+    let owner = key.split(':').next().unwrap_or_default();
+    let used_by = serde_json::to_string(&invite.used_by).unwrap_or_default();
+    let inserted = conn.execute(
+        &format!(
+            "INSERT OR IGNORE INTO invites (owner, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            INVITE_COLUMNS
+        ),
+        params![
+            owner,
+            key,
+            invite.expiration_date,
+            invite.max_uses,
+            invite.note,
+            used_by
+        ],
+    )?;
+    Ok(inserted != 0)
+}
+
+fn insert_invitation(conn: &Connection, invitation: &Invitation) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT OR REPLACE INTO invitations ({}) VALUES (?1, ?2, ?3, ?4)",
+            INVITATION_COLUMNS
+        ),
+        params![
+            invitation.inviter,
+            invitation.invitee,
+            invitation.date,
+            invitation.invite
+        ],
+    )?;
+    Ok(())
+}
+
+// Users with their refresh tokens and invitations, then the invites, return
+// how many users
+fn import(conn: &Connection, sled: &SledStorage) -> Result<usize, String> {
+    let mut imported = 0;
+    let mut after = String::new();
+    loop {
+        let users = UserRepository::list(sled, &after, IMPORT_PAGE)?;
+        for (username, record) in users.iter() {
+            insert_user(conn, username, record).map_err(db_error)?;
+            for (token, value) in RefreshTokenRepository::list(sled, username)? {
+                insert_token(conn, username, &token, &value).map_err(db_error)?;
+            }
+            for invitation in sled.invitees(username)? {
+                insert_invitation(conn, &invitation).map_err(db_error)?;
+            }
+        }
+        imported += users.len();
+        match users.last() {
+            Some((username, _)) if users.len() == IMPORT_PAGE => after = username.clone(),
+            _ => break,
+        }
+    }
+    let mut after = String::new();
+    loop {
+        let invites = InviteRepository::list(sled, &after, IMPORT_PAGE)?;
+        for (key, invite) in invites.iter() {
+            insert_invite(conn, key, invite).map_err(db_error)?;
+        }
+        match invites.last() {
+            Some((key, _)) if invites.len() == IMPORT_PAGE => after = key.clone(),
+            _ => break,
+        }
+    }
+    Ok(imported)
+}
+
+fn get_token(
+    conn: &Connection,
+    username: &str,
+    token: &str,
+) -> rusqlite::Result<Option<RefreshToken>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM refresh_tokens WHERE username = ?1 AND token = ?2",
+            TOKEN_COLUMNS
+        ),
+        params![username, token],
+        token_row,
+    )
+    .optional()
+    .map(|found| found.map(|(_, _, value)| value))
+}
+
+impl SqliteStorage {
+    // sled is imported into a new file, the user field trees are copied by
+    // the upgrade to version 3
+    pub fn open(
+        path: &Path,
+        sled: &SledStorage,
+        user_fields: Option<&UserFieldTrees>,
+    ) -> Result<Self, String> {
+        let error = |e: rusqlite::Error| format!("{}: {}", path.display(), e);
+        let mut conn = Connection::open(path).map_err(error)?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(error)?;
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(error)?;
+        let mut version: u32 = tx
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(error)?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "{}: the schema is version {}, this server only knows up to {}, upgrade the server",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            ));
+        }
+        if version == 0 {
+            let users: u32 = tx
+                .query_row(
+                    "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'users'",
+                    [],
+                    |row| row.get(0),
+                )
+                .map_err(error)?;
+            if users == 0 {
+                tx.execute_batch(SCHEMA).map_err(error)?;
+                let imported = import(&tx, sled).map_err(|e| format!("import from sled: {}", e))?;
+                if imported != 0 {
+                    println!(
+                        "{}: {} users imported from the sled database",
+                        path.display(),
+                        imported
+                    );
+                }
+                version = SCHEMA_VERSION;
+            } else {
+                version = 1;
+            }
+        }
+        for (_, upgrade) in UPGRADES.iter().filter(|(to, _)| *to > version) {
+            tx.execute_batch(upgrade).map_err(error)?;
+        }
+        if let Some(trees) = user_fields.filter(|_| version < 3) {
+            let folded = fold_user_fields(&tx, trees)
+                .map_err(|e| format!("{}: user fields from sled: {}", path.display(), e))?;
+            if folded != 0 {
+                println!(
+                    "{}: roles, email and TOTP of {} users moved from the sled database",
+                    path.display(),
+                    folded
+                );
+            }
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+            .map_err(error)?;
+        tx.commit().map_err(error)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        // A panic cannot leave a half written transaction, it rolls back on drop
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl UserRepository for SqliteStorage {
+    fn get(&self, username: &str) -> Result<Option<UserRecord>, String> {
+        get_user(&self.conn(), username).map_err(db_error)
+    }
+
+    fn create(
+        &self,
+        username: &str,
+        record: &UserRecord,
+        invite: Option<&str>,
+    ) -> Result<(), CreateError> {
+        let database = |e: rusqlite::Error| CreateError::Database(db_error(e));
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(database)?;
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM users WHERE username = ?1",
+                params![username],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(database)?;
+        if exists.is_some() {
+            return Err(CreateError::Rejected("Username already exist".to_string()));
+        }
+        if let Some(key) = invite {
+            let token = tx
+                .query_row(
+                    &format!("SELECT {} FROM invites WHERE key = ?1", INVITE_COLUMNS),
+                    params![key],
+                    invite_row,
+                )
+                .optional()
+                .map_err(database)?
+                .ok_or_else(|| CreateError::Rejected("Invalid invite".to_string()))?
+                .1;
+            let (token, invitation) =
+                invite::redeem(key, token, username).map_err(CreateError::Rejected)?;
+            let used_by = serde_json::to_string(&token.used_by).unwrap_or_default();
+            tx.execute(
+                "UPDATE invites SET used_by = ?2 WHERE key = ?1",
+                params![key, used_by],
+            )
+            .map_err(database)?;
+            insert_invitation(&tx, &invitation).map_err(database)?;
+        }
+        insert_user(&tx, username, record).map_err(database)?;
+        tx.commit().map_err(database)
+    }
+
+    fn update(&self, username: &str, f: &dyn Fn(&mut UserRecord)) -> Result<bool, String> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        let mut record = match get_user(&tx, username).map_err(db_error)? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        f(&mut record);
+        insert_user(&tx, username, &record).map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(true)
+    }
+
+    fn remove(&self, username: &str) -> Result<(), String> {
+        self.conn()
+            .execute("DELETE FROM users WHERE username = ?1", params![username])
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, UserRecord)>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM users WHERE username > ?1 ORDER BY username LIMIT ?2",
+                USER_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let users = statement
+            .query_map(params![after, limit as i64], user_row)
+            .map_err(db_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error);
+        users
+    }
+}
+
+impl RefreshTokenRepository for SqliteStorage {
+    fn insert(&self, username: &str, token: &str, value: &RefreshToken) -> Result<(), String> {
+        insert_token(&self.conn(), username, token, value).map_err(db_error)
+    }
+
+    fn get(&self, username: &str, token: &str) -> Result<Option<RefreshToken>, String> {
+        get_token(&self.conn(), username, token).map_err(db_error)
+    }
+
+    fn replace(
+        &self,
+        username: &str,
+        token: &str,
+        current: &RefreshToken,
+        new: &RefreshToken,
+    ) -> Result<bool, String> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        if get_token(&tx, username, token).map_err(db_error)?.as_ref() != Some(current) {
+            return Ok(false);
+        }
+        insert_token(&tx, username, token, new).map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(true)
+    }
+
+    fn update(
+        &self,
+        username: &str,
+        token: &str,
+        f: &dyn Fn(&mut RefreshToken),
+    ) -> Result<bool, String> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        let mut value = match get_token(&tx, username, token).map_err(db_error)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        f(&mut value);
+        insert_token(&tx, username, token, &value).map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(true)
+    }
+
+    fn remove(&self, username: &str, token: &str) -> Result<bool, String> {
+        let removed = self
+            .conn()
+            .execute(
+                "DELETE FROM refresh_tokens WHERE username = ?1 AND token = ?2",
+                params![username, token],
+            )
+            .map_err(db_error)?;
+        Ok(removed != 0)
+    }
+
+    fn list(&self, username: &str) -> Result<Vec<(String, RefreshToken)>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM refresh_tokens WHERE username = ?1 ORDER BY token",
+                TOKEN_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let tokens = statement
+            .query_map(params![username], token_row)
+            .map_err(db_error)?
+            .map(|row| row.map(|(_, token, value)| (token, value)))
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error);
+        tokens
+    }
+
+    fn find(&self, token: &str) -> Result<Option<(String, RefreshToken)>, String> {
+        self.conn()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM refresh_tokens WHERE token = ?1",
+                    TOKEN_COLUMNS
+                ),
+                params![token],
+                token_row,
+            )
+            .optional()
+            .map(|found| found.map(|(username, _, value)| (username, value)))
+            .map_err(db_error)
+    }
+
+    fn remove_where(&self, f: &dyn Fn(&RefreshToken) -> bool) -> Result<usize, String> {
+        let mut conn = self.conn();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(db_error)?;
+        let matching: Vec<(String, String)> = {
+            let mut statement = tx
+                .prepare(&format!("SELECT {} FROM refresh_tokens", TOKEN_COLUMNS))
+                .map_err(db_error)?;
+            let tokens = statement
+                .query_map([], token_row)
+                .map_err(db_error)?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(db_error)?;
+            tokens
+                .into_iter()
+                .filter(|(_, _, value)| f(value))
+                .map(|(username, token, _)| (username, token))
+                .collect()
+        };
+        for (username, token) in matching.iter() {
+            tx.execute(
+                "DELETE FROM refresh_tokens WHERE username = ?1 AND token = ?2",
+                params![username, token],
+            )
+            .map_err(db_error)?;
+        }
+        tx.commit().map_err(db_error)?;
+        Ok(matching.len())
+    }
+}
+
+impl InviteRepository for SqliteStorage {
+    fn get(&self, owner: &str) -> Result<Vec<(String, InviteToken)>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM invites WHERE owner = ?1 ORDER BY key",
+                INVITE_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let invites = statement
+            .query_map(params![owner], invite_row)
+            .map_err(db_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error);
+        invites
+    }
+
+    fn list(&self, after: &str, limit: usize) -> Result<Vec<(String, InviteToken)>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM invites WHERE key > ?1 ORDER BY key LIMIT ?2",
+                INVITE_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let invites = statement
+            .query_map(params![after, limit as i64], invite_row)
+            .map_err(db_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error);
+        invites
+    }
+
+    fn insert(&self, key: &str, invite: &InviteToken) -> Result<bool, String> {
+        insert_invite(&self.conn(), key, invite).map_err(db_error)
+    }
+
+    fn remove(&self, key: &str) -> Result<bool, String> {
+        let removed = self
+            .conn()
+            .execute("DELETE FROM invites WHERE key = ?1", params![key])
+            .map_err(db_error)?;
+        Ok(removed != 0)
+    }
+
+    fn remove_all(&self, owner: &str) -> Result<(), String> {
+        self.conn()
+            .execute("DELETE FROM invites WHERE owner = ?1", params![owner])
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    fn invitees(&self, inviter: &str) -> Result<Vec<Invitation>, String> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT {} FROM invitations WHERE inviter = ?1 ORDER BY invitee",
+                INVITATION_COLUMNS
+            ))
+            .map_err(db_error)?;
+        let invitations = statement
+            .query_map(params![inviter], invitation_row)
+            .map_err(db_error)?
+            .collect::<rusqlite::Result<_>>()
+            .map_err(db_error);
+        invitations
+    }
+
+    fn inviter(&self, invitee: &str) -> Result<Option<Invitation>, String> {
+        self.conn()
+            .query_row(
+                &format!(
+                    "SELECT {} FROM invitations WHERE invitee = ?1 ORDER BY inviter",
+                    INVITATION_COLUMNS
+                ),
+                params![invitee],
+                invitation_row,
+            )
+            .optional()
+            .map_err(db_error)
+    }
+
+    fn remove_invitations(&self, username: &str) -> Result<(), String> {
+        self.conn()
+            .execute(
+                "DELETE FROM invitations WHERE inviter = ?1 OR invitee = ?1",
+                params![username],
+            )
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
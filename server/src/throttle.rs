@@ -0,0 +1,161 @@
+use crate::config::ThrottleConfig;
+use crate::get_now_plus;
+use proto::prost::Message;
+use proto::server::user::LoginAttempts;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// key : "user:[username]" or "ip:[address]"
+// value : LoginAttempts protobuf
+
+/*
+    Failed logins in the last `window` seconds are counted per username and per
+    peer address, reaching the limit lock the key. Each consecutive lockout last
+    twice as long as the previous one, up to `max_lockout`.
+*/
+
+#[derive(Clone)]
+pub struct Throttle {
+    db: sled::Tree,
+    config: ThrottleConfig,
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+impl Throttle {
+    pub fn new(db: sled::Tree, config: ThrottleConfig) -> Self {
+        Self { db, config }
+    }
+
+    fn keys(&self, username: &str, ip: Option<IpAddr>) -> Vec<(String, u32)> {
+        let mut keys = vec![(user_key(username), self.config.max_user_attempts)];
+        if let Some(ip) = ip {
+            keys.push((ip_key(ip), self.config.max_ip_attempts));
+        }
+        keys
+    }
+
+    fn get(&self, key: &str) -> Option<LoginAttempts> {
+        match self.db.get(key) {
+            Ok(Some(value)) => LoginAttempts::decode(value.as_ref()).ok(),
+            _ => None,
+        }
+    }
+
+    // Seconds before the next allowed attempt
+    pub fn check(&self, username: &str, ip: Option<IpAddr>) -> Result<(), u32> {
+        let now = get_now_plus(0) as u32;
+        let locked_until = self
+            .keys(username, ip)
+            .iter()
+            .filter_map(|(key, _)| self.get(key))
+            .map(|attempts| attempts.locked_until)
+            .max()
+            .unwrap_or(0);
+        if locked_until > now {
+            Err(locked_until - now)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Return the lockout duration if this failure locked a key
+    pub fn failed(&self, username: &str, ip: Option<IpAddr>) -> Option<u32> {
+        let now = get_now_plus(0) as u32;
+        let window_start = now.saturating_sub(self.config.window);
+        let config = &self.config;
+        let mut lockout = None;
+        for (key, max_attempts) in self.keys(username, ip) {
+            let res = self.db.update_and_fetch(key.as_bytes(), |value| {
+                let mut attempts = value
+                    .and_then(|value| LoginAttempts::decode(value).ok())
+                    .unwrap_or_default();
+                // Quiet for long enough, start the backoff over
+                if attempts.locked_until.saturating_add(config.max_lockout) < now {
+                    attempts.lockouts = 0;
+                }
+                attempts.failures.retain(|date| *date > window_start);
+                attempts.failures.push(now);
+                if attempts.failures.len() as u32 >= max_attempts {
+                    let duration = config
+                        .lockout
+                        .checked_shl(attempts.lockouts)
+                        .unwrap_or(u32::MAX)
+                        .min(config.max_lockout);
+                    attempts.failures.clear();
+                    attempts.lockouts += 1;
+                    attempts.locked_until = now + duration;
+                }
+                Some(attempts.encode_to_vec())
+            });
+            if let Ok(Some(value)) = res {
+                if let Ok(attempts) = LoginAttempts::decode(value.as_ref()) {
+                    if attempts.locked_until > now {
+                        lockout = lockout.max(Some(attempts.locked_until - now));
+                    }
+                }
+            }
+        }
+        lockout
+    }
+
+    // The address is kept, a valid account should not reset a stuffing attempt
+    pub fn succeeded(&self, username: &str) {
+        let _res = self.db.remove(user_key(username));
+    }
+
+    // Return false if there was nothing to clear
+    pub fn clear(&self, username: &str, ip: Option<IpAddr>) -> bool {
+        let mut keys = Vec::new();
+        if !username.is_empty() {
+            keys.push(user_key(username));
+        }
+        if let Some(ip) = ip {
+            keys.push(ip_key(ip));
+        }
+        keys.into_iter()
+            .filter(|key| matches!(self.db.remove(key), Ok(Some(_))))
+            .count()
+            != 0
+    }
+
+    // Remove the entries with nothing left to remember
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let window_start = now.saturating_sub(self.config.window);
+        let stale: Vec<sled::IVec> = self
+            .db
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| match LoginAttempts::decode(value.as_ref()) {
+                Ok(attempts) => {
+                    attempts.failures.iter().all(|date| *date <= window_start)
+                        && attempts
+                            .locked_until
+                            .saturating_add(self.config.max_lockout)
+                            < now
+                }
+                Err(_) => true,
+            })
+            .map(|(key, _)| key)
+            .collect();
+        stale
+            .into_iter()
+            .filter(|key| matches!(self.db.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
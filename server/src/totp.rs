@@ -0,0 +1,301 @@
+use crate::get_now_plus;
+use crate::users::Users;
+use hmac::{Hmac, Mac};
+use proto::prost::Message;
+use proto::server::user::{MfaChallenge, TotpSecret};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::iter;
+use std::time::Duration;
+
+// The secret is the totp of the UserRecord
+// challenges tree: "[random]" -> MfaChallenge protobuf
+
+/*
+    RFC 6238 with the authenticator apps defaults: SHA1, 6 digits, 30 seconds.
+    A code is accepted one step before or after the current one for clock drift.
+
+    Recovery codes replace a TOTP code once each, in case the device is lost.
+    They are random enough for a plain SHA-256 to be stored instead of argon2.
+*/
+
+const ISSUER: &str = "AnApp";
+const SECRET_LEN: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD: u64 = 30; /* seconds */
+const SKEW: u64 = 1; /* steps */
+const CHALLENGE_DURATION: u32 = 60 * 5; /* seconds */
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10; /* base32 characters, 50 bits */
+
+#[derive(Clone)]
+pub struct Totp {
+    users: Users,
+    challenges: sled::Tree,
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10u32.pow(DIGITS)
+}
+
+// Step matching the code, if any
+fn matching_step(secret: &[u8], code: &str) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = get_now_plus(0) as u64 / PERIOD;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| hotp(secret, *step) == code)
+}
+
+fn hash_recovery_code(code: &str) -> Vec<u8> {
+    let code: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(code.as_bytes()).to_vec()
+}
+
+// Return the codes as shown to the user and their hashes
+fn generate_recovery_codes() -> (Vec<String>, Vec<Vec<u8>>) {
+    let mut rng = thread_rng();
+    let codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 8];
+            rng.fill_bytes(&mut bytes);
+            let code =
+                base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+            let half = RECOVERY_CODE_LEN / 2;
+            format!("{}-{}", &code[..half], &code[half..RECOVERY_CODE_LEN])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    (codes, hashes)
+}
+
+fn uri(username: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = ISSUER,
+        username = username,
+        secret = secret,
+        digits = DIGITS,
+        period = PERIOD
+    )
+}
+
+impl Totp {
+    pub fn new(users: Users, challenges: sled::Tree) -> Self {
+        Self { users, challenges }
+    }
+
+    fn get(&self, username: &str) -> Option<TotpSecret> {
+        self.users.get(username).and_then(|record| record.totp)
+    }
+
+    // Apply f to the secret of the user in a single update, return its result,
+    // false without a secret
+    fn update(&self, username: &str, f: &dyn Fn(&mut TotpSecret) -> bool) -> bool {
+        let done = Cell::new(false);
+        let res = self.users.update(username, &|record| {
+            done.set(record.totp.as_mut().is_some_and(f));
+        });
+        matches!(res, Ok(true)) && done.get()
+    }
+
+    pub fn is_enabled(&self, username: &str) -> bool {
+        self.get(username).is_some_and(|totp| totp.confirmed)
+    }
+
+    // Return the base32 secret and its otpauth uri, replace a pending enrollment
+    pub fn enroll(&self, username: &str) -> Result<(String, String), String> {
+        let mut secret = vec![0u8; SECRET_LEN];
+        thread_rng().fill_bytes(&mut secret);
+        let encoded = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret);
+        let totp = TotpSecret {
+            secret,
+            confirmed: false,
+            last_step: 0,
+            recovery_codes: Vec::new(),
+        };
+        let enabled = Cell::new(false);
+        self.users.update(username, &|record| {
+            enabled.set(record.totp.as_ref().is_some_and(|totp| totp.confirmed));
+            if !enabled.get() {
+                record.totp = Some(totp.clone());
+            }
+        })?;
+        if enabled.get() {
+            return Err("TOTP already enabled".to_string());
+        }
+        Ok((encoded.clone(), uri(username, &encoded)))
+    }
+
+    pub fn recovery_codes_left(&self, username: &str) -> u32 {
+        self.get(username)
+            .map_or(0, |totp| totp.recovery_codes.len() as u32)
+    }
+
+    // Return the first recovery codes
+    pub fn confirm(&self, username: &str, code: &str) -> Result<Vec<String>, String> {
+        match self.get(username) {
+            Some(totp) if totp.confirmed => Err("TOTP already enabled".to_string()),
+            Some(_) if self.verify(username, code) => self.new_recovery_codes(username),
+            Some(_) => Err("Invalid code".to_string()),
+            None => Err("No TOTP enrollment".to_string()),
+        }
+    }
+
+    pub fn disable(&self, username: &str, code: &str) -> Result<(), String> {
+        if !self.is_enabled(username) {
+            return Err("TOTP not enabled".to_string());
+        }
+        if !self.verify_second_factor(username, code) {
+            return Err("Invalid code".to_string());
+        }
+        self.users.update(username, &|record| record.totp = None)?;
+        Ok(())
+    }
+
+    pub fn regenerate_recovery_codes(
+        &self,
+        username: &str,
+        code: &str,
+    ) -> Result<Vec<String>, String> {
+        if !self.is_enabled(username) {
+            return Err("TOTP not enabled".to_string());
+        }
+        if !self.verify_second_factor(username, code) {
+            return Err("Invalid code".to_string());
+        }
+        self.new_recovery_codes(username)
+    }
+
+    fn new_recovery_codes(&self, username: &str) -> Result<Vec<String>, String> {
+        let (codes, hashes) = generate_recovery_codes();
+        let replaced = self.update(username, &|totp| {
+            totp.recovery_codes = hashes.clone();
+            true
+        });
+        match replaced {
+            true => Ok(codes),
+            false => Err("TOTP not enabled".to_string()),
+        }
+    }
+
+    // TOTP code or one of the recovery codes, consumed
+    pub fn verify_second_factor(&self, username: &str, code: &str) -> bool {
+        if code.len() == DIGITS as usize && code.bytes().all(|c| c.is_ascii_digit()) {
+            self.verify(username, code)
+        } else {
+            self.use_recovery_code(username, code)
+        }
+    }
+
+    fn use_recovery_code(&self, username: &str, code: &str) -> bool {
+        let hash = hash_recovery_code(code);
+        // A concurrent use of the same code finds it removed
+        self.update(
+            username,
+            &|totp| match totp.recovery_codes.iter().position(|h| *h == hash) {
+                Some(index) if totp.confirmed => {
+                    totp.recovery_codes.remove(index);
+                    true
+                }
+                _ => false,
+            },
+        )
+    }
+
+    // Check the code and burn its step, confirm a pending enrollment
+    pub fn verify(&self, username: &str, code: &str) -> bool {
+        // A concurrent use of the same code finds its step burnt
+        self.update(username, &|totp| match matching_step(&totp.secret, code) {
+            Some(step) if step > totp.last_step => {
+                totp.last_step = step;
+                totp.confirmed = true;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    pub fn new_challenge(
+        &self,
+        username: &str,
+        device_name: &str,
+    ) -> Result<(String, u32), String> {
+        let mut rng = thread_rng();
+        let token: String = iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(32)
+            .collect();
+        let expiration_date = get_now_plus(CHALLENGE_DURATION) as u32;
+        let challenge = MfaChallenge {
+            username: username.to_string(),
+            expiration_date,
+            device_name: device_name.to_string(),
+        };
+        self.challenges
+            .insert(&token, challenge.encode_to_vec())
+            .map_err(|e| e.to_string())?;
+        Ok((token, expiration_date))
+    }
+
+    pub fn get_challenge(&self, token: &str) -> Option<MfaChallenge> {
+        let challenge = match self.challenges.get(token) {
+            Ok(Some(value)) => MfaChallenge::decode(value.as_ref()).ok()?,
+            _ => return None,
+        };
+        if challenge.expiration_date < get_now_plus(0) as u32 {
+            return None;
+        }
+        Some(challenge)
+    }
+
+    // Return false if the challenge was already used
+    pub fn remove_challenge(&self, token: &str) -> bool {
+        matches!(self.challenges.remove(token), Ok(Some(_)))
+    }
+
+    pub fn sweep(&self) -> usize {
+        let now = get_now_plus(0) as u32;
+        let expired: Vec<sled::IVec> = self
+            .challenges
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|(_, value)| {
+                MfaChallenge::decode(value.as_ref())
+                    .map_or(true, |challenge| challenge.expiration_date < now)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        expired
+            .into_iter()
+            .filter(|key| matches!(self.challenges.remove(key), Ok(Some(_))))
+            .count()
+    }
+
+    pub async fn sweep_every(self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(CHALLENGE_DURATION as u64));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+}
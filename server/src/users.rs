@@ -0,0 +1,110 @@
+use crate::get_now_plus;
+use crate::password;
+use crate::storage::{CreateError, UserRepository};
+use proto::server::user::UserRecord;
+use std::sync::Arc;
+
+/*
+    Every read and write of the users goes through this module, stored by a
+    UserRepository (see storage). A change of the record layout that needs
+    more than new optional fields bumps RECORD_VERSION.
+
+    The roles, TOTP and email modules keep their rules but store their part
+    of the record through update.
+*/
+
+pub const RECORD_VERSION: u32 = 2;
+
+pub fn new_record(password_hash: &str) -> UserRecord {
+    UserRecord {
+        version: RECORD_VERSION,
+        password_hash: password_hash.to_string(),
+        created_date: get_now_plus(0) as u32,
+        ..UserRecord::default()
+    }
+}
+
+#[derive(Clone)]
+pub struct Users {
+    db: Arc<dyn UserRepository>,
+}
+
+impl Users {
+    pub fn new(db: Arc<dyn UserRepository>) -> Self {
+        Self { db }
+    }
+
+    pub fn get(&self, username: &str) -> Option<UserRecord> {
+        self.db.get(username).ok().flatten()
+    }
+
+    pub fn exists(&self, username: &str) -> bool {
+        self.get(username).is_some()
+    }
+
+    // A database error counts as disabled
+    pub fn is_disabled(&self, username: &str) -> bool {
+        matches!(
+            self.db.get(username),
+            Err(_) | Ok(Some(UserRecord { disabled: true, .. }))
+        )
+    }
+
+    // false if there is no such user
+    pub fn set_disabled(&self, username: &str, disabled: bool) -> Result<bool, String> {
+        self.db
+            .update(username, &|record| record.disabled = disabled)
+    }
+
+    // Atomic, f can run more than once. false if there is no such user.
+    pub fn update(&self, username: &str, f: &dyn Fn(&mut UserRecord)) -> Result<bool, String> {
+        self.db.update(username, f)
+    }
+
+    // With the invite (its key) redeemed at once
+    pub fn create(
+        &self,
+        username: &str,
+        record: &UserRecord,
+        invite: Option<&str>,
+    ) -> Result<(), CreateError> {
+        self.db.create(username, record, invite)
+    }
+
+    // The record of the user if the password is the right one
+    pub fn check_password(&self, username: &str, password: &str) -> Option<UserRecord> {
+        self.get(username)
+            .filter(|record| password::verify(record.password_hash.as_bytes(), password))
+    }
+
+    // false if there is no such user
+    pub fn set_password(&self, username: &str, password_hash: &str) -> Result<bool, String> {
+        self.db.update(username, &|record| {
+            record.password_hash = password_hash.to_string()
+        })
+    }
+
+    // Only replace the hash that was checked, not a concurrent password change
+    pub fn rehash(&self, username: &str, checked: &str, password_hash: &str) {
+        let _res = self.db.update(username, &|record| {
+            if record.password_hash == checked {
+                record.password_hash = password_hash.to_string();
+            }
+        });
+    }
+
+    pub fn record_login(&self, username: &str) {
+        let _res = self.db.update(username, &|record| {
+            record.last_login_date = get_now_plus(0) as u32;
+        });
+    }
+
+    pub fn remove(&self, username: &str) -> Result<(), String> {
+        self.db.remove(username)
+    }
+
+    // Usernames in order, from the one after `after`
+    pub fn list(&self, after: &str, limit: usize) -> Vec<(String, UserRecord)> {
+        self.db.list(after, limit).unwrap_or_default()
+    }
+}
@@ -0,0 +1,100 @@
+use futures::future::{ready, Ready};
+use hyper::body::HttpBody;
+use hyper::{Body, Request, Response, StatusCode};
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::Service;
+use tonic::transport::NamedService;
+use tonic::Status;
+
+use crate::jwt::Jwt;
+use crate::oidc;
+
+// Plain http endpoints living next to the grpc services, routed by tonic
+// on the "/.well-known" prefix
+
+#[derive(Clone)]
+pub struct WellKnown {
+    jwt: Jwt,
+    openid_configuration: Arc<String>,
+}
+
+// OpenID Connect discovery and RFC 8414 metadata of the endpoints in oauth2.rs
+fn openid_configuration(jwt: &Jwt, issuer: &str) -> String {
+    json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth2/authorize", issuer),
+        "token_endpoint": format!("{}/oauth2/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth2/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "introspection_endpoint": format!("{}/oauth2/introspect", issuer),
+        "revocation_endpoint": format!("{}/oauth2/revoke", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": [jwt.algorithm()],
+        "scopes_supported": oidc::SCOPES,
+        "claims_supported": [
+            "iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "preferred_username",
+            "roles"
+        ],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "authorization_response_iss_parameter_supported": true,
+    })
+    .to_string()
+}
+
+impl WellKnown {
+    pub fn new(jwt: Jwt, issuer: &str) -> Self {
+        Self {
+            openid_configuration: Arc::new(openid_configuration(&jwt, issuer)),
+            jwt,
+        }
+    }
+}
+
+fn response(status: StatusCode, content_type: &str, body: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", content_type)
+        .body(
+            Body::from(body)
+                .map_err(|e| Status::internal(e.to_string()))
+                .boxed(),
+        )
+        .unwrap()
+}
+
+impl NamedService for WellKnown {
+    const NAME: &'static str = ".well-known";
+}
+
+impl Service<Request<Body>> for WellKnown {
+    type Response = Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        ready(Ok(match request.uri().path() {
+            "/.well-known/jwks.json" => {
+                response(StatusCode::OK, "application/json", self.jwt.jwks())
+            }
+            "/.well-known/openid-configuration" | "/.well-known/oauth-authorization-server" => {
+                response(
+                    StatusCode::OK,
+                    "application/json",
+                    self.openid_configuration.to_string(),
+                )
+            }
+            _ => response(StatusCode::NOT_FOUND, "text/plain", "Not found".to_string()),
+        }))
+    }
+}
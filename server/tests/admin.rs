@@ -0,0 +1,135 @@
+// Admin service permissions: support users manage the accounts, not the admins
+
+mod common;
+
+use common::TestServer;
+use proto::client::admin::{
+    get_user_res, GetUserReq, ResetPasswordReq, RevokeUserSessionsReq, SetUserDisabledReq,
+};
+use proto::client::auth::{auth_client::AuthClient, GetAccessTokenReq, SignupReq};
+use proto::client::user::{create_invite_token_res, CreateInviteTokenReq, SetRoleReq};
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// Signed up with an invite of tet, with the role if not empty
+async fn add_user(server: &TestServer, username: &str, role: &str) {
+    let access_token = common::login(server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let res = tet
+        .create_invite_token(CreateInviteTokenReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let invite_code = match res.payload {
+        Some(create_invite_token_res::Payload::Ok(create_invite_token_res::Ok {
+            token: Some(token),
+        })) => token.token,
+        _ => panic!("no invite"),
+    };
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    auth.signup(SignupReq {
+        username: username.to_string(),
+        password: "password".to_string(),
+        invite_code,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    if !role.is_empty() {
+        tet.set_role(SetRoleReq {
+            username: username.to_string(),
+            role: role.to_string(),
+            granted: true,
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn support_cannot_take_over_admins() {
+    let server = common::start("admin", CONFIG).await;
+    add_user(&server, "bob", "support").await;
+    add_user(&server, "carol", "admin").await;
+    add_user(&server, "dave", "").await;
+    let access_token = common::login(&server, "bob", "password").await;
+    let mut bob = common::admin_client(&server.url, access_token).await;
+    let reset = |username: &str| ResetPasswordReq {
+        username: username.to_string(),
+        new_password: "taken over".to_string(),
+    };
+
+    for admin in ["tet", "carol"] {
+        let err = bob.reset_password(reset(admin)).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        let err = bob
+            .set_user_disabled(SetUserDisabledReq {
+                username: admin.to_string(),
+                disabled: true,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        let err = bob
+            .revoke_user_sessions(RevokeUserSessionsReq {
+                username: admin.to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+    }
+    common::login(&server, "tet", "password").await;
+    common::login(&server, "carol", "password").await;
+
+    // Users without more permissions than support are fine
+    bob.reset_password(reset("dave")).await.unwrap();
+    common::login(&server, "dave", "taken over").await;
+    add_user(&server, "erin", "support").await;
+    bob.reset_password(reset("erin")).await.unwrap();
+
+    // And admins manage everyone
+    let access_token = common::login(&server, "carol", "password").await;
+    let mut carol = common::admin_client(&server.url, access_token).await;
+    carol.reset_password(reset("bob")).await.unwrap();
+}
+
+#[tokio::test]
+async fn user_details_do_not_leak_sessions() {
+    let server = common::start("admin-details", CONFIG).await;
+    add_user(&server, "bob", "support").await;
+    add_user(&server, "dave", "").await;
+    let access_token = common::login(&server, "bob", "password").await;
+    let mut bob = common::admin_client(&server.url, access_token).await;
+    let details = |username: &str| GetUserReq {
+        username: username.to_string(),
+    };
+
+    let err = bob.get_user(details("tet")).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+
+    // Sessions of the users below support are listed, without their token
+    common::login(&server, "dave", "password").await;
+    let res = bob.get_user(details("dave")).await.unwrap().into_inner();
+    let sessions = match res.payload {
+        Some(get_user_res::Payload::Ok(get_user_res::Ok { user: Some(user) })) => user.sessions,
+        _ => panic!("no user"),
+    };
+    assert!(!sessions.is_empty());
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    for session in sessions {
+        assert!(session.token.is_empty());
+        assert!(!session.family.is_empty());
+        for token in [session.token, session.family] {
+            let res = auth
+                .get_access_token(GetAccessTokenReq {
+                    username: "dave".to_string(),
+                    refresh_token: token,
+                })
+                .await;
+            assert!(res.is_err());
+        }
+    }
+}
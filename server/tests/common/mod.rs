@@ -0,0 +1,233 @@
+// Server binary and plain http helpers shared by the integration tests
+#![allow(dead_code)]
+
+use hyper::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use proto::client::admin::admin_client::AdminClient;
+use proto::client::auth::{
+    auth_client::AuthClient, get_refresh_token_res, GetRefreshTokenReq, SignupReq,
+};
+use proto::client::user::user_client::UserClient;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
+use tonic::codegen::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+
+pub struct TestServer {
+    child: Child,
+    pub dir: PathBuf,
+    pub url: String,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _res = self.child.kill();
+        let _res = self.child.wait();
+        let _res = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// Run the server with this configuration, "tet" is signed up with "password".
+// "{dir}" in the configuration is replaced by the server's temporary directory.
+pub async fn start(name: &str, config: &str) -> TestServer {
+    start_with(name, config, |_| {}).await
+}
+
+// The same, seed gets the database path before the server opens it
+pub async fn start_with(name: &str, config: &str, seed: impl FnOnce(&Path)) -> TestServer {
+    let port = free_port();
+    let addr = format!("127.0.0.1:{}", port);
+    let dir = std::env::temp_dir().join(format!("anapp-{}-{}-{}", name, std::process::id(), port));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = config.replace("{dir}", dir.to_str().unwrap());
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    seed(&dir.join("db"));
+    let child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg("--config")
+        .arg(dir.join("config.toml"))
+        .arg("--db-path")
+        .arg(dir.join("db"))
+        .arg("--bind")
+        .arg(&addr)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = TestServer {
+        child,
+        dir,
+        url: format!("http://{}", addr),
+    };
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    auth.signup(SignupReq {
+        username: "tet".to_string(),
+        password: "password".to_string(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    server
+}
+
+// Run the server on a seeded database until it exits by itself, return its stderr
+pub fn run_until_exit(name: &str, seed: impl FnOnce(&Path)) -> (ExitStatus, String) {
+    run_until_exit_with(name, "", seed)
+}
+
+// The same with this configuration, "{dir}" replaced like in start
+pub fn run_until_exit_with(
+    name: &str,
+    config: &str,
+    seed: impl FnOnce(&Path),
+) -> (ExitStatus, String) {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("anapp-{}-{}-{}", name, std::process::id(), port));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = config.replace("{dir}", dir.to_str().unwrap());
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+    seed(&dir.join("db"));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg("--config")
+        .arg(dir.join("config.toml"))
+        .arg("--db-path")
+        .arg(dir.join("db"))
+        .arg("--bind")
+        .arg(format!("127.0.0.1:{}", port))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut status = None;
+    for _ in 0..100 {
+        status = child.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let _res = child.kill();
+    let output = child.wait_with_output().unwrap();
+    let _res = std::fs::remove_dir_all(&dir);
+    let status = status.expect("the server did not exit");
+    (status, String::from_utf8_lossy(&output.stderr).to_string())
+}
+
+// Password login, return the access token
+pub async fn login(server: &TestServer, username: &str, password: &str) -> String {
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: username.to_string(),
+            password: password.to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_refresh_token_res::Payload::Ok(ok)) => ok.access_token,
+        _ => panic!("no tokens"),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+pub async fn user_client(
+    url: &str,
+    access_token: String,
+) -> UserClient<InterceptedService<Channel, impl Interceptor>> {
+    let channel = Channel::from_shared(url.to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    UserClient::with_interceptor(channel, move |mut request: tonic::Request<()>| {
+        request
+            .metadata_mut()
+            .insert("authorization", access_token.parse().unwrap());
+        Ok(request)
+    })
+}
+
+#[allow(clippy::result_large_err)]
+pub async fn admin_client(
+    url: &str,
+    access_token: String,
+) -> AdminClient<InterceptedService<Channel, impl Interceptor>> {
+    let channel = Channel::from_shared(url.to_string())
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    AdminClient::with_interceptor(channel, move |mut request: tonic::Request<()>| {
+        request
+            .metadata_mut()
+            .insert("authorization", access_token.parse().unwrap());
+        Ok(request)
+    })
+}
+
+pub async fn send(request: Request<Body>) -> (StatusCode, HeaderMap, String) {
+    let response = Client::new().request(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, headers, String::from_utf8_lossy(&body).to_string())
+}
+
+pub async fn get(url: &str, bearer: Option<&str>) -> (StatusCode, HeaderMap, String) {
+    let mut request = Request::get(url);
+    if let Some(token) = bearer {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    send(request.body(Body::empty()).unwrap()).await
+}
+
+pub async fn post(url: &str, form: &[(&str, &str)]) -> (StatusCode, HeaderMap, String) {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(body))
+        .unwrap();
+    send(request).await
+}
+
+pub fn challenge(verifier: &str) -> String {
+    base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+// The uri and query parameters of a url
+pub fn split_url(url: &str) -> (String, HashMap<String, String>) {
+    let (uri, query) = url.split_once('?').unwrap();
+    let params = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    (uri.to_string(), params)
+}
+
+// The redirect uri and its query parameters
+pub fn redirected(headers: &HeaderMap) -> (String, HashMap<String, String>) {
+    split_url(headers.get(LOCATION).unwrap().to_str().unwrap())
+}
@@ -0,0 +1,259 @@
+// Email verification and password reset, the mails are read from the file
+// of the "file" mail backend
+
+mod common;
+
+use common::TestServer;
+use proto::client::auth::{
+    auth_client::AuthClient, confirm_password_reset_res, verify_email_res, ConfirmPasswordResetReq,
+    GetRefreshTokenReq, RequestPasswordResetReq, VerifyEmailReq,
+};
+use proto::client::user::{get_email_res, set_email_res, GetEmailReq, SetEmailReq};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tonic::transport::Channel;
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+
+[mail]
+backend = "file"
+from = "AnApp <noreply@example.com>"
+file = "{dir}/mails.jsonl"
+"#;
+
+fn mails(server: &TestServer) -> Vec<Value> {
+    std::fs::read_to_string(server.dir.join("mails.jsonl"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+// Wait for the mail number `count`, return its recipient, subject and token
+async fn mail(server: &TestServer, count: usize) -> (String, String, String) {
+    for _ in 0..50 {
+        if let Some(mail) = mails(server).get(count - 1) {
+            let token = mail["body"]
+                .as_str()
+                .unwrap()
+                .lines()
+                .find(|line| line.len() == 32 && line.chars().all(|c| c.is_ascii_alphanumeric()))
+                .unwrap()
+                .to_string();
+            return (
+                mail["to"].as_str().unwrap().to_string(),
+                mail["subject"].as_str().unwrap().to_string(),
+                token,
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("mail {} not sent", count);
+}
+
+async fn request_reset(auth: &mut AuthClient<Channel>, email: &str) {
+    auth.request_password_reset(RequestPasswordResetReq {
+        email: email.to_string(),
+    })
+    .await
+    .unwrap();
+}
+
+async fn confirm_reset(
+    auth: &mut AuthClient<Channel>,
+    token: &str,
+    new_password: &str,
+) -> Result<confirm_password_reset_res::Ok, tonic::Status> {
+    let res = auth
+        .confirm_password_reset(ConfirmPasswordResetReq {
+            token: token.to_string(),
+            new_password: new_password.to_string(),
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(confirm_password_reset_res::Payload::Ok(ok)) => Ok(ok),
+        None => panic!("no payload"),
+    }
+}
+
+#[tokio::test]
+async fn email_verification_and_password_reset() {
+    let server = common::start("email", CONFIG).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut user = common::user_client(&server.url, access_token).await;
+
+    let email = user.get_email(GetEmailReq {}).await.unwrap().into_inner();
+    assert!(matches!(
+        email.payload,
+        Some(get_email_res::Payload::Ok(get_email_res::Ok {
+            email: None
+        }))
+    ));
+    let err = user
+        .set_email(SetEmailReq {
+            email: "tet@example.com\r\nBcc: everyone@example.com".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let set_email = || SetEmailReq {
+        email: "Tet@Example.com".to_string(),
+    };
+    let res = user.set_email(set_email()).await.unwrap().into_inner();
+    assert!(matches!(
+        res.payload,
+        Some(set_email_res::Payload::Ok(set_email_res::Ok {
+            verification_sent: true
+        }))
+    ));
+    let (to, subject, verification_token) = mail(&server, 1).await;
+    assert_eq!(to, "Tet@Example.com");
+    assert_eq!(subject, "Verify your email address");
+    // Not twice in a minute
+    let res = user.set_email(set_email()).await.unwrap().into_inner();
+    assert!(matches!(
+        res.payload,
+        Some(set_email_res::Payload::Ok(set_email_res::Ok {
+            verification_sent: false
+        }))
+    ));
+
+    // No reset to an unverified address
+    request_reset(&mut auth, "tet@example.com").await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mails(&server).len(), 1);
+
+    let verify = VerifyEmailReq {
+        token: verification_token,
+    };
+    let res = auth
+        .verify_email(verify.clone())
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(verify_email_res::Payload::Ok(ok)) => {
+            assert_eq!(ok.username, "tet");
+            assert_eq!(ok.email, "Tet@Example.com");
+        }
+        None => panic!("no payload"),
+    }
+    let err = auth.verify_email(verify).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let email = user.get_email(GetEmailReq {}).await.unwrap().into_inner();
+    match email.payload {
+        Some(get_email_res::Payload::Ok(get_email_res::Ok { email: Some(email) })) => {
+            assert!(email.verified)
+        }
+        _ => panic!("no email"),
+    }
+
+    // Unknown addresses get the same answer and no mail
+    request_reset(&mut auth, "nobody@example.com").await;
+    request_reset(&mut auth, "TET@example.com").await;
+    let (to, subject, reset_token) = mail(&server, 2).await;
+    assert_eq!(to, "Tet@Example.com");
+    assert_eq!(subject, "Reset your password");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(mails(&server).len(), 2);
+
+    let err = confirm_reset(&mut auth, "not a token", "new password")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let err = confirm_reset(&mut auth, &reset_token, "pw")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let reset = confirm_reset(&mut auth, &reset_token, "new password")
+        .await
+        .unwrap();
+    assert_eq!(reset.username, "tet");
+    // The signup and login sessions
+    assert_eq!(reset.revoked, 2);
+    let err = confirm_reset(&mut auth, &reset_token, "another password")
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let login = |password: &str| GetRefreshTokenReq {
+        username: "tet".to_string(),
+        password: password.to_string(),
+        ..Default::default()
+    };
+    let err = auth.get_refresh_token(login("password")).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    auth.get_refresh_token(login("new password")).await.unwrap();
+}
+
+// A relay without STARTTLS, return its port and the commands it received
+async fn plain_relay() -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let commands = received.clone();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 relay\r\n").await.unwrap();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let reply: &[u8] = match line.as_str() {
+                "STARTTLS" => b"454 TLS not available\r\n",
+                _ => b"250 ok\r\n",
+            };
+            commands.lock().unwrap().push(line);
+            if write.write_all(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+    (port, received)
+}
+
+fn smtp_config(port: u16, tls: &str) -> String {
+    format!(
+        r#"
+admin = "tet"
+
+[mail]
+backend = "smtp"
+smtp_host = "127.0.0.1"
+smtp_port = {}
+smtp_tls = "{}"
+smtp_username = "relay"
+smtp_password = "relay password"
+"#,
+        port, tls
+    )
+}
+
+#[tokio::test]
+async fn smtp_credentials_need_tls() {
+    let (port, received) = plain_relay().await;
+    let server = common::start("email-smtp", &smtp_config(port, "starttls")).await;
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut user = common::user_client(&server.url, access_token).await;
+    let err = user
+        .set_email(SetEmailReq {
+            email: "tet@example.com".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::Unavailable);
+    assert!(err.message().contains("454"), "{}", err.message());
+    assert_eq!(*received.lock().unwrap(), ["EHLO localhost", "STARTTLS"]);
+
+    let (status, stderr) =
+        common::run_until_exit_with("email-plain", &smtp_config(port, "none"), |_| {});
+    assert!(!status.success());
+    assert!(stderr.contains("not sent without TLS"), "{}", stderr);
+}
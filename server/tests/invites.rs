@@ -0,0 +1,133 @@
+// Invite tree: deleted users leave it, and a cycle in the stored invitations
+// does not hang it
+
+mod common;
+
+use common::TestServer;
+use proto::client::admin::DeleteUserReq;
+use proto::client::auth::{auth_client::AuthClient, SignupReq};
+use proto::client::user::{
+    create_invite_token_res, get_invite_tree_res, get_invitees_res, CreateInviteTokenReq,
+    GetInviteTreeReq, GetInviteesReq, InviteTreeNode,
+};
+use proto::prost::Message;
+use proto::server::user::Invitation;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// Signed up with an invite of the inviter
+async fn invite(server: &TestServer, inviter: &str, username: &str) {
+    let access_token = common::login(server, inviter, "password").await;
+    let mut user = common::user_client(&server.url, access_token).await;
+    let res = user
+        .create_invite_token(CreateInviteTokenReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    let invite_code = match res.payload {
+        Some(create_invite_token_res::Payload::Ok(create_invite_token_res::Ok {
+            token: Some(token),
+        })) => token.token,
+        _ => panic!("no invite"),
+    };
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    auth.signup(SignupReq {
+        username: username.to_string(),
+        password: "password".to_string(),
+        invite_code,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+}
+
+async fn tree(server: &TestServer, root: &str) -> InviteTreeNode {
+    let access_token = common::login(server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let res = tet
+        .get_invite_tree(GetInviteTreeReq {
+            root: root.to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_invite_tree_res::Payload::Ok(get_invite_tree_res::Ok { root: Some(root) })) => {
+            root
+        }
+        _ => panic!("no tree"),
+    }
+}
+
+fn usernames(node: &InviteTreeNode) -> Vec<&str> {
+    node.invitees
+        .iter()
+        .map(|node| node.username.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn registering_a_deleted_username_again() {
+    let server = common::start("invites", CONFIG).await;
+    invite(&server, "tet", "bob").await;
+    invite(&server, "bob", "carol").await;
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut admin = common::admin_client(&server.url, access_token).await;
+    admin
+        .delete_user(DeleteUserReq {
+            username: "bob".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(usernames(&tree(&server, "tet").await).is_empty());
+
+    // The new bob starts without the invitees of the old one
+    invite(&server, "carol", "bob").await;
+    let access_token = common::login(&server, "bob", "password").await;
+    let mut bob = common::user_client(&server.url, access_token).await;
+    let res = bob
+        .get_invitees(GetInviteesReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_invitees_res::Payload::Ok(ok)) => assert!(ok.invitations.is_empty()),
+        None => panic!("no payload"),
+    }
+    let carol = tree(&server, "carol").await;
+    assert_eq!(usernames(&carol), ["bob"]);
+    assert!(carol.invitees[0].invitees.is_empty());
+}
+
+// Left by a server that kept the invitations of the deleted users
+fn seed_cycle(db_path: &std::path::Path) {
+    let db = sled::open(db_path).unwrap();
+    let invitations = db.open_tree("invitations").unwrap();
+    for (inviter, invitee) in [("tet", "bob"), ("bob", "carol"), ("carol", "bob")] {
+        let invitation = Invitation {
+            inviter: inviter.to_string(),
+            invitee: invitee.to_string(),
+            date: 1,
+            invite: String::new(),
+        };
+        invitations
+            .insert(
+                format!("{}:{}", inviter, invitee),
+                invitation.encode_to_vec(),
+            )
+            .unwrap();
+    }
+    db.flush().unwrap();
+}
+
+#[tokio::test]
+async fn cycles_end_the_walk() {
+    let server = common::start_with("invites-cycle", CONFIG, seed_cycle).await;
+    let tet = tree(&server, "tet").await;
+    assert_eq!(usernames(&tet), ["bob"]);
+    let bob = &tet.invitees[0];
+    assert_eq!(usernames(bob), ["carol"]);
+    assert!(bob.invitees[0].invitees.is_empty());
+}
@@ -0,0 +1,108 @@
+// Schema versioning of the sled database, run on databases left by older servers
+
+mod common;
+
+use proto::client::auth::{auth_client::AuthClient, get_access_token_res, GetAccessTokenReq};
+use proto::prost::Message;
+use proto::server::user::RefreshToken;
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CONFIG: &str = r#"
+admin = "tet"
+
+[[oauth.clients]]
+id = "api"
+secret = "api secret 0123456789"
+"#;
+
+const TOKEN: &str = "aaaaabbbbbccccc";
+
+// Before versioning: raw argon2 hashes, and the refresh tokens in the users tree
+fn seed_unversioned(db_path: &std::path::Path) {
+    let db = sled::open(db_path).unwrap();
+    let users = db.open_tree("users").unwrap();
+    let hash = argon2::hash_encoded(
+        b"legacy password",
+        b"some salt bytes!",
+        &argon2::Config::default(),
+    )
+    .unwrap();
+    users.insert("alice", hash.as_bytes()).unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let token = RefreshToken {
+        creation_date: now,
+        expiration_date: now + 3600,
+        last_use: now,
+        ..Default::default()
+    };
+    users
+        .insert(format!("alice:{}", TOKEN), token.encode_to_vec())
+        .unwrap();
+    db.flush().unwrap();
+}
+
+// Return the new refresh token
+async fn refresh(server: &common::TestServer, refresh_token: &str) -> String {
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let res = auth
+        .get_access_token(GetAccessTokenReq {
+            username: "alice".to_string(),
+            refresh_token: refresh_token.to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_access_token_res::Payload::Ok(ok)) => ok.refresh_token,
+        _ => panic!("no tokens"),
+    }
+}
+
+// The username of a token found without it, None if inactive
+async fn introspect(server: &common::TestServer, token: &str) -> Option<String> {
+    let (_, _, body) = common::post(
+        &format!("{}/oauth2/introspect", server.url),
+        &[
+            ("client_id", "api"),
+            ("client_secret", "api secret 0123456789"),
+            ("token", token),
+        ],
+    )
+    .await;
+    let introspection: Value = serde_json::from_str(&body).unwrap();
+    introspection["username"].as_str().map(String::from)
+}
+
+#[tokio::test]
+async fn unversioned_database_is_migrated() {
+    let server = common::start_with("migrations", CONFIG, seed_unversioned).await;
+    assert_eq!(introspect(&server, TOKEN).await.as_deref(), Some("alice"));
+    // The session opened before the move is still usable, and so is its rotation
+    let refresh_token = refresh(&server, TOKEN).await;
+    assert_eq!(introspect(&server, TOKEN).await, None);
+    assert_eq!(
+        introspect(&server, &refresh_token).await.as_deref(),
+        Some("alice")
+    );
+    refresh(&server, &refresh_token).await;
+    common::login(&server, "alice", "legacy password").await;
+}
+
+fn seed_newer(db_path: &std::path::Path) {
+    let db = sled::open(db_path).unwrap();
+    let meta = db.open_tree("meta").unwrap();
+    meta.insert("schema_version", &999u32.to_be_bytes())
+        .unwrap();
+    db.flush().unwrap();
+}
+
+#[test]
+fn newer_database_is_refused() {
+    let (status, stderr) = common::run_until_exit("migrations-newer", seed_newer);
+    assert!(!status.success());
+    assert!(stderr.contains("version 999"), "{}", stderr);
+}
@@ -0,0 +1,96 @@
+// Personal access tokens: only the RPCs of their scopes, never the account ones
+
+mod common;
+
+use common::TestServer;
+use proto::client::admin::ListUsersReq;
+use proto::client::user::{
+    create_personal_access_token_res, ChangePasswordReq, CreateInviteTokenReq,
+    CreatePersonalAccessTokenReq, GetRefreshTokensReq, GetTotpReq,
+};
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+fn request(scopes: &[&str]) -> CreatePersonalAccessTokenReq {
+    CreatePersonalAccessTokenReq {
+        name: "script".to_string(),
+        scopes: scopes.iter().map(|s| s.to_string()).collect(),
+        expiration_date: 0,
+    }
+}
+
+// Created by tet, return the full token
+async fn create(server: &TestServer, scopes: &[&str]) -> String {
+    let access_token = common::login(server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let res = tet
+        .create_personal_access_token(request(scopes))
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(create_personal_access_token_res::Payload::Ok(ok)) => ok.secret,
+        None => panic!("no payload"),
+    }
+}
+
+#[tokio::test]
+async fn tokens_are_limited_to_their_scopes() {
+    let server = common::start("pats", CONFIG).await;
+    let pat = create(&server, &["sessions:read"]).await;
+    let mut user = common::user_client(&server.url, pat.clone()).await;
+    user.get_refresh_tokens(GetRefreshTokensReq {})
+        .await
+        .unwrap();
+
+    let err = user
+        .create_invite_token(CreateInviteTokenReq::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+    assert_eq!(err.message(), "Missing the \"invites:write\" scope");
+    // The roles of tet need the admin scope
+    let mut admin = common::admin_client(&server.url, pat).await;
+    let err = admin.list_users(ListUsersReq::default()).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn account_rpcs_need_a_login() {
+    let server = common::start("pats-account", CONFIG).await;
+    let pat = create(&server, &["sessions:read", "sessions:write", "admin"]).await;
+    let mut user = common::user_client(&server.url, pat).await;
+    let missing_account = |err: tonic::Status| {
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert_eq!(err.message(), "Missing the \"account\" scope");
+    };
+
+    missing_account(user.get_totp(GetTotpReq {}).await.unwrap_err());
+    missing_account(
+        user.change_password(ChangePasswordReq {
+            old_password: "password".to_string(),
+            new_password: "taken over".to_string(),
+            keep_current_session: false,
+        })
+        .await
+        .unwrap_err(),
+    );
+    // Not even a token with more scopes
+    missing_account(
+        user.create_personal_access_token(request(&["sessions:read"]))
+            .await
+            .unwrap_err(),
+    );
+
+    // The password did not change, and the account scope cannot be granted
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let err = tet
+        .create_personal_access_token(request(&["account"]))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
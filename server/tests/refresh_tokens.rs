@@ -0,0 +1,103 @@
+// Rotation of the refresh tokens, revocation of a family on reuse and the purge
+// of the expired ones
+
+mod common;
+
+use proto::client::admin::{get_stats_res, GetStatsReq};
+use proto::client::auth::{
+    auth_client::AuthClient, get_access_token_res, get_refresh_token_res, GetAccessTokenReq,
+    GetRefreshTokenReq,
+};
+use tonic::transport::Channel;
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// Return the refresh token
+async fn login(auth: &mut AuthClient<Channel>) -> String {
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: "tet".to_string(),
+            password: "password".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_refresh_token_res::Payload::Ok(ok)) => ok.refresh_token,
+        _ => panic!("no tokens"),
+    }
+}
+
+// Return the new refresh token
+async fn refresh(
+    auth: &mut AuthClient<Channel>,
+    refresh_token: &str,
+) -> Result<String, tonic::Status> {
+    let res = auth
+        .get_access_token(GetAccessTokenReq {
+            username: "tet".to_string(),
+            refresh_token: refresh_token.to_string(),
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(get_access_token_res::Payload::Ok(ok)) => Ok(ok.refresh_token),
+        _ => panic!("no tokens"),
+    }
+}
+
+#[tokio::test]
+async fn reuse_revokes_the_family() {
+    let server = common::start("refresh-tokens", CONFIG).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let first = login(&mut auth).await;
+    let other_session = login(&mut auth).await;
+
+    let second = refresh(&mut auth, &first).await.unwrap();
+    assert_ne!(second, first);
+    let third = refresh(&mut auth, &second).await.unwrap();
+
+    // first was rotated: whoever holds it is not the legitimate client
+    let err = refresh(&mut auth, &first).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+    // The whole family is gone, the latest token included
+    let err = refresh(&mut auth, &third).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let err = refresh(&mut auth, &first).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    // The other sessions are left alone
+    refresh(&mut auth, &other_session).await.unwrap();
+}
+
+const SHORT_CONFIG: &str = r#"
+admin = "tet"
+
+[jwt]
+access_token_duration = 1
+refresh_token_duration = 2
+refresh_token_sweep_interval = 1
+"#;
+
+#[tokio::test]
+async fn expired_tokens_are_purged_and_counted() {
+    let server = common::start("refresh-tokens-sweep", SHORT_CONFIG).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let expired = login(&mut auth).await;
+    tokio::time::sleep(std::time::Duration::from_millis(3500)).await;
+    let err = refresh(&mut auth, &expired).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut admin = common::admin_client(&server.url, access_token).await;
+    let res = admin.get_stats(GetStatsReq {}).await.unwrap().into_inner();
+    match res.payload {
+        // The signup token and the login one
+        Some(get_stats_res::Payload::Ok(ok)) => assert!(ok.refresh_tokens_purged >= 2),
+        None => panic!("no payload"),
+    }
+}
@@ -0,0 +1,97 @@
+// Ended sessions lose their access tokens before they expire (deny list)
+
+mod common;
+
+use proto::client::auth::{
+    auth_client::AuthClient, get_access_token_res, get_refresh_token_res, GetAccessTokenReq,
+    GetRefreshTokenReq, LogoutReq,
+};
+use proto::client::user::GetRefreshTokensReq;
+use tonic::transport::Channel;
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// Return the refresh token and the access token
+async fn login(auth: &mut AuthClient<Channel>) -> (String, String) {
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: "tet".to_string(),
+            password: "password".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_refresh_token_res::Payload::Ok(ok)) => (ok.refresh_token, ok.access_token),
+        _ => panic!("no tokens"),
+    }
+}
+
+// Return the new refresh token and access token
+async fn refresh(
+    auth: &mut AuthClient<Channel>,
+    refresh_token: &str,
+) -> Result<(String, String), tonic::Status> {
+    let res = auth
+        .get_access_token(GetAccessTokenReq {
+            username: "tet".to_string(),
+            refresh_token: refresh_token.to_string(),
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(get_access_token_res::Payload::Ok(ok)) => Ok((ok.refresh_token, ok.access_token)),
+        _ => panic!("no tokens"),
+    }
+}
+
+// Return the status of a call with the access token
+async fn call(url: &str, access_token: &str) -> Result<(), tonic::Status> {
+    let mut user = common::user_client(url, access_token.to_string()).await;
+    user.get_refresh_tokens(GetRefreshTokensReq {})
+        .await
+        .map(|_| ())
+}
+
+#[tokio::test]
+async fn logout_denies_the_access_tokens() {
+    let server = common::start("sessions", CONFIG).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let (refresh_token, first_access) = login(&mut auth).await;
+    let (refresh_token, second_access) = refresh(&mut auth, &refresh_token).await.unwrap();
+    let (_, other_access) = login(&mut auth).await;
+    call(&server.url, &first_access).await.unwrap();
+    call(&server.url, &second_access).await.unwrap();
+
+    auth.logout(LogoutReq {
+        username: "tet".to_string(),
+        refresh_token,
+    })
+    .await
+    .unwrap();
+    // Every access token of the session, not only the latest
+    for access_token in [&first_access, &second_access] {
+        let err = call(&server.url, access_token).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        assert_eq!(err.message(), "Revoked token");
+    }
+    call(&server.url, &other_access).await.unwrap();
+}
+
+#[tokio::test]
+async fn reuse_denies_the_access_tokens() {
+    let server = common::start("sessions-reuse", CONFIG).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let (stolen, _) = login(&mut auth).await;
+    let (_, access_token) = refresh(&mut auth, &stolen).await.unwrap();
+    call(&server.url, &access_token).await.unwrap();
+
+    let err = refresh(&mut auth, &stolen).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+    let err = call(&server.url, &access_token).await.unwrap_err();
+    assert_eq!(err.message(), "Revoked token");
+}
@@ -0,0 +1,675 @@
+// The same scenario on every storage backend: users, refresh tokens and
+// invites have to behave alike whatever stores them
+
+mod common;
+
+use common::TestServer;
+use proto::client::admin::{
+    get_user_res, list_invites_res, list_users_res, revoke_user_sessions_res, DeleteUserReq,
+    GetUserReq, ListInvitesReq, ListUsersReq, ResetPasswordReq, RevokeUserSessionsReq,
+};
+use proto::client::auth::{
+    auth_client::AuthClient, get_access_token_res, get_refresh_token_res, signup_res,
+    GetAccessTokenReq, GetRefreshTokenReq, LogoutReq, SignupReq,
+};
+use proto::client::user::{
+    create_invite_token_res, get_email_res, get_invite_tokens_res, get_invite_tree_res,
+    get_invitees_res, get_refresh_tokens_res, ChangePasswordReq, CreateInviteTokenReq, GetEmailReq,
+    GetInviteTokensReq, GetInviteTreeReq, GetInviteesReq, GetRefreshTokensReq,
+    RevokeInviteTokenReq,
+};
+use proto::prost::Message;
+use proto::server::user::{
+    InviteToken, RefreshToken, TotpSecret, UserEmail, UserRecord, UserRoles,
+};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+use tonic::Code;
+
+const SLED_CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+const SQLITE_CONFIG: &str = r#"
+admin = "tet"
+
+[storage]
+backend = "sqlite"
+sqlite_path = "{dir}/users.sqlite"
+"#;
+
+// Return the refresh token
+async fn signup(
+    auth: &mut AuthClient<Channel>,
+    username: &str,
+    invite_code: &str,
+) -> Result<String, tonic::Status> {
+    let res = auth
+        .signup(SignupReq {
+            username: username.to_string(),
+            password: "password".to_string(),
+            invite_code: invite_code.to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(signup_res::Payload::Ok(ok)) => Ok(ok.refresh_token),
+        _ => panic!("no tokens"),
+    }
+}
+
+// Return the refresh token
+async fn login(
+    auth: &mut AuthClient<Channel>,
+    username: &str,
+    password: &str,
+) -> Result<String, tonic::Status> {
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: username.to_string(),
+            password: password.to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(get_refresh_token_res::Payload::Ok(ok)) => Ok(ok.refresh_token),
+        _ => panic!("no tokens"),
+    }
+}
+
+// Return the new refresh token
+async fn refresh(
+    auth: &mut AuthClient<Channel>,
+    username: &str,
+    refresh_token: &str,
+) -> Result<String, tonic::Status> {
+    let res = auth
+        .get_access_token(GetAccessTokenReq {
+            username: username.to_string(),
+            refresh_token: refresh_token.to_string(),
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(get_access_token_res::Payload::Ok(ok)) => Ok(ok.refresh_token),
+        _ => panic!("no tokens"),
+    }
+}
+
+async fn conformance(server: &TestServer) {
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let access_token = common::login(server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token.clone()).await;
+    let mut admin = common::admin_client(&server.url, access_token).await;
+
+    let create_invite = |max_uses: u32| CreateInviteTokenReq {
+        max_uses,
+        ..Default::default()
+    };
+    let invite = |res: tonic::Response<proto::client::user::CreateInviteTokenRes>| match res
+        .into_inner()
+        .payload
+    {
+        Some(create_invite_token_res::Payload::Ok(create_invite_token_res::Ok {
+            token: Some(token),
+        })) => token.token,
+        _ => panic!("no invite"),
+    };
+
+    // Signup
+    let err = signup(&mut auth, "tet", "").await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let err = signup(&mut auth, "bob", "").await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let shared = invite(tet.create_invite_token(create_invite(2)).await.unwrap());
+    let single = invite(tet.create_invite_token(create_invite(1)).await.unwrap());
+    let bob_signup = signup(&mut auth, "bob", &shared).await.unwrap();
+    signup(&mut auth, "carol", &shared).await.unwrap();
+    let err = signup(&mut auth, "dave", &shared).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    // A refused signup does not consume the invite
+    let err = signup(&mut auth, "bob", &single).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    signup(&mut auth, "dave", &single).await.unwrap();
+
+    // Invites
+    let res = tet
+        .get_invite_tokens(GetInviteTokensReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    let tokens = match res.payload {
+        Some(get_invite_tokens_res::Payload::Ok(ok)) => ok.tokens,
+        None => panic!("no payload"),
+    };
+    assert_eq!(tokens.len(), 2);
+    let shared_token = tokens.iter().find(|t| t.token == shared).unwrap();
+    assert!(shared_token.used);
+    assert_eq!(shared_token.used_by, ["bob", "carol"]);
+    assert_eq!(shared_token.max_uses, 2);
+    let single_token = tokens.iter().find(|t| t.token == single).unwrap();
+    assert_eq!(single_token.used_by, ["dave"]);
+
+    let res = tet
+        .get_invitees(GetInviteesReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    let invitations = match res.payload {
+        Some(get_invitees_res::Payload::Ok(ok)) => ok.invitations,
+        None => panic!("no payload"),
+    };
+    let invitees: Vec<_> = invitations.iter().map(|i| i.invitee.as_str()).collect();
+    assert_eq!(invitees, ["bob", "carol", "dave"]);
+    assert!(invitations.iter().all(|i| i.inviter == "tet" && i.date > 0));
+    assert_eq!(invitations[0].invite, shared);
+    assert_eq!(invitations[2].invite, single);
+
+    let res = tet
+        .get_invite_tree(GetInviteTreeReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_invite_tree_res::Payload::Ok(get_invite_tree_res::Ok { root: Some(root) })) => {
+            assert_eq!(root.username, "tet");
+            let invitees: Vec<_> = root.invitees.iter().map(|n| n.username.as_str()).collect();
+            assert_eq!(invitees, ["bob", "carol", "dave"]);
+        }
+        _ => panic!("no tree"),
+    }
+
+    let unused = invite(tet.create_invite_token(create_invite(1)).await.unwrap());
+    let revoke = || RevokeInviteTokenReq {
+        token: unused.clone(),
+    };
+    tet.revoke_invite_token(revoke()).await.unwrap();
+    let err = tet.revoke_invite_token(revoke()).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let err = signup(&mut auth, "erin", &unused).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    // Paging of the admin lists
+    let mut owners = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let res = admin
+            .list_invites(ListInvitesReq {
+                page_token,
+                page_size: 1,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let ok = match res.payload {
+            Some(list_invites_res::Payload::Ok(ok)) => ok,
+            None => panic!("no payload"),
+        };
+        owners.extend(ok.invites.into_iter().map(|invite| invite.owner));
+        if ok.next_page_token.is_empty() {
+            break;
+        }
+        page_token = ok.next_page_token;
+    }
+    assert_eq!(owners, ["tet", "tet"]);
+
+    let mut usernames = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let res = admin
+            .list_users(ListUsersReq {
+                page_token,
+                page_size: 3,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let ok = match res.payload {
+            Some(list_users_res::Payload::Ok(ok)) => ok,
+            None => panic!("no payload"),
+        };
+        usernames.extend(ok.users.into_iter().map(|user| user.username));
+        if ok.next_page_token.is_empty() {
+            break;
+        }
+        page_token = ok.next_page_token;
+    }
+    assert_eq!(usernames, ["bob", "carol", "dave", "tet"]);
+
+    let get_user = |username: &str| GetUserReq {
+        username: username.to_string(),
+    };
+    let res = admin.get_user(get_user("bob")).await.unwrap().into_inner();
+    match res.payload {
+        Some(get_user_res::Payload::Ok(get_user_res::Ok { user: Some(user) })) => {
+            assert_eq!(user.invited_by, "tet");
+            assert!(user.signup_date > 0);
+            assert!(user.last_login_date >= user.signup_date);
+        }
+        _ => panic!("no user"),
+    }
+
+    // Refresh tokens: rotation and reuse detection
+    let first = login(&mut auth, "bob", "password").await.unwrap();
+    let second = refresh(&mut auth, "bob", &first).await.unwrap();
+    let err = refresh(&mut auth, "bob", &first).await.unwrap_err();
+    assert_eq!(err.code(), Code::PermissionDenied);
+    let err = refresh(&mut auth, "bob", &second).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let err = refresh(&mut auth, "carol", &bob_signup).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let third = login(&mut auth, "bob", "password").await.unwrap();
+    let bob_access = common::login(server, "bob", "password").await;
+    let mut bob = common::user_client(&server.url, bob_access).await;
+    let sessions = |res: tonic::Response<proto::client::user::GetRefreshTokensRes>| match res
+        .into_inner()
+        .payload
+    {
+        Some(get_refresh_tokens_res::Payload::Ok(ok)) => ok.refresh_tokens,
+        None => panic!("no payload"),
+    };
+    // signup, compromised, third and the one of the client
+    let list = sessions(
+        bob.get_refresh_tokens(GetRefreshTokensReq {})
+            .await
+            .unwrap(),
+    );
+    assert_eq!(list.len(), 4);
+    assert_eq!(list.iter().filter(|token| token.compromised).count(), 1);
+    auth.logout(LogoutReq {
+        username: "bob".to_string(),
+        refresh_token: third.clone(),
+    })
+    .await
+    .unwrap();
+    let err = refresh(&mut auth, "bob", &third).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let list = sessions(
+        bob.get_refresh_tokens(GetRefreshTokensReq {})
+            .await
+            .unwrap(),
+    );
+    assert_eq!(list.len(), 3);
+    refresh(&mut auth, "bob", &bob_signup).await.unwrap();
+    let res = admin
+        .revoke_user_sessions(RevokeUserSessionsReq {
+            username: "bob".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        res.payload,
+        Some(revoke_user_sessions_res::Payload::Ok(
+            revoke_user_sessions_res::Ok { revoked: 3 }
+        ))
+    ));
+
+    // Passwords
+    let carol_access = common::login(server, "carol", "password").await;
+    let mut carol = common::user_client(&server.url, carol_access).await;
+    let change = |old_password: &str| ChangePasswordReq {
+        old_password: old_password.to_string(),
+        new_password: "new password".to_string(),
+        keep_current_session: true,
+    };
+    let err = carol.change_password(change("wrong")).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    carol.change_password(change("password")).await.unwrap();
+    let err = login(&mut auth, "carol", "password").await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    login(&mut auth, "carol", "new password").await.unwrap();
+    admin
+        .reset_password(ResetPasswordReq {
+            username: "dave".to_string(),
+            new_password: "reset password".to_string(),
+        })
+        .await
+        .unwrap();
+    login(&mut auth, "dave", "reset password").await.unwrap();
+
+    // Deletion, with the invites and the invitations of the user
+    carol.create_invite_token(create_invite(1)).await.unwrap();
+    admin
+        .delete_user(DeleteUserReq {
+            username: "carol".to_string(),
+        })
+        .await
+        .unwrap();
+    let err = admin.get_user(get_user("carol")).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+    let err = login(&mut auth, "carol", "new password").await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let res = admin
+        .list_invites(ListInvitesReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(list_invites_res::Payload::Ok(ok)) => {
+            assert!(ok.invites.iter().all(|invite| invite.owner == "tet"))
+        }
+        None => panic!("no payload"),
+    }
+    let res = tet
+        .get_invitees(GetInviteesReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_invitees_res::Payload::Ok(ok)) => {
+            let invitees: Vec<_> = ok.invitations.iter().map(|i| i.invitee.as_str()).collect();
+            assert_eq!(invitees, ["bob", "dave"]);
+        }
+        None => panic!("no payload"),
+    }
+}
+
+#[tokio::test]
+async fn sled_conformance() {
+    let server = common::start("storage-sled", SLED_CONFIG).await;
+    conformance(&server).await;
+}
+
+#[tokio::test]
+async fn sqlite_conformance() {
+    let server = common::start("storage-sqlite", SQLITE_CONFIG).await;
+    conformance(&server).await;
+    // Readable with standard tools
+    let db = rusqlite::Connection::open(server.dir.join("users.sqlite")).unwrap();
+    let mut statement = db
+        .prepare("SELECT username FROM users ORDER BY username")
+        .unwrap();
+    let usernames: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(usernames, ["bob", "dave", "tet"]);
+}
+
+const TOKEN: &str = "aaaaabbbbbccccc";
+
+// alice with a session and an invite, stored by the sled backend
+fn seed_sled(db_path: &Path) {
+    let db = sled::open(db_path).unwrap();
+    let hash =
+        argon2::hash_encoded(b"password", b"some salt bytes!", &argon2::Config::default()).unwrap();
+    let record = UserRecord {
+        version: 1,
+        password_hash: hash,
+        ..Default::default()
+    };
+    db.open_tree("users")
+        .unwrap()
+        .insert("alice", record.encode_to_vec())
+        .unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let token = RefreshToken {
+        creation_date: now,
+        expiration_date: now + 3600,
+        last_use: now,
+        family: "alicefamily0000".to_string(),
+        ..Default::default()
+    };
+    db.open_tree("refresh_tokens")
+        .unwrap()
+        .insert(format!("alice:{}", TOKEN), token.encode_to_vec())
+        .unwrap();
+    let invite = InviteToken {
+        max_uses: 1,
+        ..Default::default()
+    };
+    db.open_tree("invites")
+        .unwrap()
+        .insert("alice:0123456789", invite.encode_to_vec())
+        .unwrap();
+    db.flush().unwrap();
+}
+
+#[tokio::test]
+async fn a_new_sqlite_file_imports_sled() {
+    let server = common::start_with("storage-import", SQLITE_CONFIG, seed_sled).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    refresh(&mut auth, "alice", TOKEN).await.unwrap();
+    let access_token = common::login(&server, "alice", "password").await;
+    let mut alice = common::user_client(&server.url, access_token).await;
+    let res = alice
+        .get_invite_tokens(GetInviteTokensReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_invite_tokens_res::Payload::Ok(ok)) => assert_eq!(ok.tokens.len(), 1),
+        None => panic!("no payload"),
+    }
+    let db = rusqlite::Connection::open(server.dir.join("users.sqlite")).unwrap();
+    let version: u32 = db
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, 3);
+}
+
+fn seed_newer_sqlite(db_path: &Path) {
+    let db = rusqlite::Connection::open(db_path.parent().unwrap().join("users.sqlite")).unwrap();
+    db.execute_batch("PRAGMA user_version = 999").unwrap();
+}
+
+#[test]
+fn newer_sqlite_file_is_refused() {
+    let (status, stderr) =
+        common::run_until_exit_with("storage-newer", SQLITE_CONFIG, seed_newer_sqlite);
+    assert!(!status.success());
+    assert!(stderr.contains("version 999"), "{}", stderr);
+}
+
+// Before the records held them, schema version 3: bob is an admin with a
+// verified address, carol is disabled and alice has TOTP
+fn seed_user_fields(db_path: &Path) {
+    let db = sled::open(db_path).unwrap();
+    db.open_tree("meta")
+        .unwrap()
+        .insert("schema_version", &3u32.to_be_bytes())
+        .unwrap();
+    let hash =
+        argon2::hash_encoded(b"password", b"some salt bytes!", &argon2::Config::default()).unwrap();
+    let record = UserRecord {
+        version: 1,
+        password_hash: hash,
+        ..Default::default()
+    };
+    let users = db.open_tree("users").unwrap();
+    for username in ["alice", "bob", "carol"] {
+        users.insert(username, record.encode_to_vec()).unwrap();
+    }
+    let roles = UserRoles {
+        roles: vec!["admin".to_string()],
+    };
+    db.open_tree("roles")
+        .unwrap()
+        .insert("bob", roles.encode_to_vec())
+        .unwrap();
+    let email = UserEmail {
+        email: "bob@example.com".to_string(),
+        verified: true,
+        verified_date: 1,
+    };
+    db.open_tree("emails")
+        .unwrap()
+        .insert("bob", email.encode_to_vec())
+        .unwrap();
+    db.open_tree("disabled")
+        .unwrap()
+        .insert("carol", &[])
+        .unwrap();
+    let totp = TotpSecret {
+        secret: vec![7; 20],
+        confirmed: true,
+        last_step: 0,
+        recovery_codes: vec![vec![1; 32], vec![2; 32]],
+    };
+    db.open_tree("totp")
+        .unwrap()
+        .insert("alice", totp.encode_to_vec())
+        .unwrap();
+    db.flush().unwrap();
+}
+
+// The same users in a version 2 SQLite file
+fn seed_user_fields_sqlite(db_path: &Path) {
+    seed_user_fields(db_path);
+    let db = rusqlite::Connection::open(db_path.parent().unwrap().join("users.sqlite")).unwrap();
+    db.execute_batch(
+        "CREATE TABLE users (
+            username TEXT PRIMARY KEY NOT NULL,
+            version INTEGER NOT NULL,
+            password_hash TEXT NOT NULL,
+            created_date INTEGER NOT NULL,
+            last_login_date INTEGER NOT NULL
+        );
+        CREATE TABLE refresh_tokens (
+            username TEXT NOT NULL,
+            token TEXT NOT NULL,
+            family TEXT NOT NULL,
+            from_address TEXT NOT NULL,
+            creation_date INTEGER NOT NULL,
+            expiration_date INTEGER NOT NULL,
+            last_use INTEGER NOT NULL,
+            rotated INTEGER NOT NULL,
+            compromised INTEGER NOT NULL,
+            user_agent TEXT NOT NULL,
+            device_name TEXT NOT NULL,
+            client_id TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            PRIMARY KEY (username, token)
+        );
+        CREATE TABLE invites (
+            key TEXT PRIMARY KEY NOT NULL,
+            owner TEXT NOT NULL,
+            expiration_date INTEGER NOT NULL,
+            max_uses INTEGER NOT NULL,
+            note TEXT NOT NULL,
+            used_by TEXT NOT NULL
+        );
+        CREATE TABLE invitations (
+            inviter TEXT NOT NULL,
+            invitee TEXT NOT NULL,
+            date INTEGER NOT NULL,
+            invite TEXT NOT NULL,
+            PRIMARY KEY (inviter, invitee)
+        );
+        PRAGMA user_version = 2;",
+    )
+    .unwrap();
+    let sled_db = sled::open(db_path).unwrap();
+    for username in ["alice", "bob", "carol"] {
+        let record = sled_db.open_tree("users").unwrap().get(username).unwrap();
+        let record = UserRecord::decode(record.unwrap().as_ref()).unwrap();
+        db.execute(
+            "INSERT INTO users VALUES (?1, ?2, ?3, 0, 0)",
+            rusqlite::params![username, record.version, record.password_hash],
+        )
+        .unwrap();
+    }
+}
+
+async fn user_fields(server: &TestServer) {
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let err = login(&mut auth, "carol", "password").await.unwrap_err();
+    assert_eq!(err.message(), "Account disabled");
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: "alice".to_string(),
+            password: "password".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        res.payload,
+        Some(get_refresh_token_res::Payload::Mfa(_))
+    ));
+
+    let access_token = common::login(server, "bob", "password").await;
+    let mut admin = common::admin_client(&server.url, access_token.clone()).await;
+    let res = admin
+        .get_user(GetUserReq {
+            username: "alice".to_string(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_user_res::Payload::Ok(get_user_res::Ok { user: Some(user) })) => {
+            assert!(user.totp_enabled)
+        }
+        _ => panic!("no user"),
+    }
+    let res = admin
+        .list_users(ListUsersReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(list_users_res::Payload::Ok(ok)) => {
+            let users: Vec<_> = ok
+                .users
+                .iter()
+                .map(|user| (user.username.as_str(), user.roles.len(), user.disabled))
+                .collect();
+            assert_eq!(
+                users,
+                [
+                    ("alice", 0, false),
+                    ("bob", 1, false),
+                    ("carol", 0, true),
+                    ("tet", 1, false)
+                ]
+            );
+        }
+        None => panic!("no payload"),
+    }
+    let mut bob = common::user_client(&server.url, access_token).await;
+    let email = bob.get_email(GetEmailReq {}).await.unwrap().into_inner();
+    match email.payload {
+        Some(get_email_res::Payload::Ok(ok)) => {
+            let email = ok.email.unwrap();
+            assert_eq!(email.email, "bob@example.com");
+            assert!(email.verified);
+        }
+        None => panic!("no payload"),
+    }
+}
+
+#[tokio::test]
+async fn sled_records_get_the_user_fields() {
+    let server = common::start_with("storage-fields", SLED_CONFIG, seed_user_fields).await;
+    user_fields(&server).await;
+}
+
+#[tokio::test]
+async fn sqlite_upgrade_gets_the_user_fields() {
+    let server = common::start_with(
+        "storage-fields-sqlite",
+        SQLITE_CONFIG,
+        seed_user_fields_sqlite,
+    )
+    .await;
+    user_fields(&server).await;
+    let db = rusqlite::Connection::open(server.dir.join("users.sqlite")).unwrap();
+    let roles: String = db
+        .query_row(
+            "SELECT roles FROM users WHERE username = 'bob'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(roles, r#"["admin"]"#);
+}
@@ -0,0 +1,66 @@
+// Login throttling: failed attempts lock the account until the lockout ends or
+// someone with the permission clears it
+
+mod common;
+
+use proto::client::auth::{auth_client::AuthClient, GetRefreshTokenReq};
+use proto::client::user::{clear_lockout_res, ClearLockoutReq};
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+
+[throttle]
+max_user_attempts = 3
+max_ip_attempts = 100
+lockout = 60
+"#;
+
+#[tokio::test]
+async fn failed_logins_lock_the_account() {
+    let server = common::start("throttle", CONFIG).await;
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let login = |password: &str| GetRefreshTokenReq {
+        username: "tet".to_string(),
+        password: password.to_string(),
+        ..Default::default()
+    };
+
+    for _ in 0..2 {
+        let err = auth.get_refresh_token(login("wrong")).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+    // The third failure reaches the limit
+    let err = auth.get_refresh_token(login("wrong")).await.unwrap_err();
+    assert_eq!(err.code(), Code::ResourceExhausted);
+    let retry_after: u32 = err
+        .metadata()
+        .get("retry-after")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+    // Even the right password is refused during the lockout
+    let err = auth.get_refresh_token(login("password")).await.unwrap_err();
+    assert_eq!(err.code(), Code::ResourceExhausted);
+
+    let res = tet
+        .clear_lockout(ClearLockoutReq {
+            username: "tet".to_string(),
+            ip: String::new(),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(matches!(
+        res.payload,
+        Some(clear_lockout_res::Payload::Ok(clear_lockout_res::Ok {
+            cleared: true
+        }))
+    ));
+    auth.get_refresh_token(login("password")).await.unwrap();
+}
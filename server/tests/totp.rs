@@ -0,0 +1,185 @@
+// TOTP second factor: enrollment, the login challenge and the codes it accepts,
+// recovery codes included
+
+mod common;
+
+use common::TestServer;
+use hmac::{Hmac, Mac};
+use proto::client::auth::{
+    auth_client::AuthClient, get_refresh_token_res, verify_mfa_res, GetRefreshTokenReq,
+    VerifyMfaReq,
+};
+use proto::client::user::{
+    confirm_totp_res, enroll_totp_res, get_totp_res, ConfirmTotpReq, EnrollTotpReq, GetTotpReq,
+};
+use sha1::Sha1;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::transport::Channel;
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// RFC 6238 code of the step, what an authenticator app would show
+fn code(secret: &[u8], step: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!("{:06}", code % 1_000_000)
+}
+
+fn current_step() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 30
+}
+
+// Enroll and confirm tet, return the secret, the step used and the recovery codes
+async fn enable_totp(server: &TestServer) -> (Vec<u8>, u64, Vec<String>) {
+    let access_token = common::login(server, "tet", "password").await;
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let res = tet
+        .enroll_totp(EnrollTotpReq {})
+        .await
+        .unwrap()
+        .into_inner();
+    let secret = match res.payload {
+        Some(enroll_totp_res::Payload::Ok(ok)) => {
+            assert!(ok.uri.starts_with("otpauth://totp/"));
+            base32::decode(base32::Alphabet::RFC4648 { padding: false }, &ok.secret).unwrap()
+        }
+        None => panic!("no payload"),
+    };
+    let step = current_step();
+    let err = tet
+        .confirm_totp(ConfirmTotpReq {
+            code: "not a code".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    let res = tet
+        .confirm_totp(ConfirmTotpReq {
+            code: code(&secret, step),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let recovery_codes = match res.payload {
+        Some(confirm_totp_res::Payload::Ok(ok)) => ok.recovery_codes,
+        None => panic!("no payload"),
+    };
+    let res = tet.get_totp(GetTotpReq {}).await.unwrap().into_inner();
+    match res.payload {
+        Some(get_totp_res::Payload::Ok(ok)) => {
+            assert!(ok.enabled);
+            assert_eq!(ok.recovery_codes_left, recovery_codes.len() as u32);
+        }
+        None => panic!("no payload"),
+    }
+    (secret, step, recovery_codes)
+}
+
+// The password is not enough anymore, return the mfa token
+async fn password_step(auth: &mut AuthClient<Channel>) -> String {
+    let res = auth
+        .get_refresh_token(GetRefreshTokenReq {
+            username: "tet".to_string(),
+            password: "password".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(get_refresh_token_res::Payload::Mfa(mfa)) => mfa.mfa_token,
+        _ => panic!("no mfa challenge"),
+    }
+}
+
+// Return the access token
+async fn verify(
+    auth: &mut AuthClient<Channel>,
+    mfa_token: &str,
+    code: &str,
+) -> Result<String, tonic::Status> {
+    let res = auth
+        .verify_mfa(VerifyMfaReq {
+            mfa_token: mfa_token.to_string(),
+            code: code.to_string(),
+        })
+        .await?
+        .into_inner();
+    match res.payload {
+        Some(verify_mfa_res::Payload::Ok(ok)) => Ok(ok.access_token),
+        None => panic!("no payload"),
+    }
+}
+
+#[tokio::test]
+async fn login_needs_a_fresh_code() {
+    let server = common::start("totp", CONFIG).await;
+    let (secret, step, _) = enable_totp(&server).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+
+    let mfa_token = password_step(&mut auth).await;
+    let err = verify(&mut auth, &mfa_token, "abcdef").await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    // Already used to confirm the enrollment
+    let err = verify(&mut auth, &mfa_token, &code(&secret, step))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    // The next step is still in the accepted drift
+    verify(&mut auth, &mfa_token, &code(&secret, step + 1))
+        .await
+        .unwrap();
+    // The challenge is single use
+    let err = verify(&mut auth, &mfa_token, &code(&secret, step + 1))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn recovery_codes_are_single_use() {
+    let server = common::start("totp-recovery", CONFIG).await;
+    let (_, _, recovery_codes) = enable_totp(&server).await;
+    assert_eq!(recovery_codes.len(), 10);
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+
+    let mfa_token = password_step(&mut auth).await;
+    // Case and separators do not matter
+    let typed = recovery_codes[0].to_uppercase().replace('-', " ");
+    verify(&mut auth, &mfa_token, &typed).await.unwrap();
+
+    let mfa_token = password_step(&mut auth).await;
+    let err = verify(&mut auth, &mfa_token, &recovery_codes[0])
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    verify(&mut auth, &mfa_token, &recovery_codes[1])
+        .await
+        .unwrap();
+
+    let mfa_token = password_step(&mut auth).await;
+    let access_token = verify(&mut auth, &mfa_token, &recovery_codes[2])
+        .await
+        .unwrap();
+    let mut tet = common::user_client(&server.url, access_token).await;
+    let res = tet.get_totp(GetTotpReq {}).await.unwrap().into_inner();
+    match res.payload {
+        Some(get_totp_res::Payload::Ok(ok)) => assert_eq!(ok.recovery_codes_left, 7),
+        None => panic!("no payload"),
+    }
+}
@@ -0,0 +1,78 @@
+// User records, and the conversion of the raw hashes stored before them
+
+mod common;
+
+use proto::client::admin::{get_user_res, list_users_res, GetUserReq, ListUsersReq, UserDetails};
+use proto::client::auth::{auth_client::AuthClient, GetRefreshTokenReq};
+use tonic::Code;
+
+const CONFIG: &str = r#"
+admin = "tet"
+"#;
+
+// An argon2 hash alone, the value of a user before the records
+fn seed_legacy_user(db_path: &std::path::Path) {
+    let db = sled::open(db_path).unwrap();
+    let users = db.open_tree("users").unwrap();
+    let hash = argon2::hash_encoded(
+        b"legacy password",
+        b"some salt bytes!",
+        &argon2::Config::default(),
+    )
+    .unwrap();
+    users.insert("alice", hash.as_bytes()).unwrap();
+    db.flush().unwrap();
+}
+
+#[tokio::test]
+async fn legacy_users_are_migrated() {
+    let server = common::start_with("users", CONFIG, seed_legacy_user).await;
+    let mut auth = AuthClient::connect(server.url.clone()).await.unwrap();
+    let login = |password: &str| GetRefreshTokenReq {
+        username: "alice".to_string(),
+        password: password.to_string(),
+        ..Default::default()
+    };
+    let err = auth
+        .get_refresh_token(login("wrong password"))
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+    common::login(&server, "alice", "legacy password").await;
+    // Still valid once rehashed with the server's salt length
+    common::login(&server, "alice", "legacy password").await;
+
+    let access_token = common::login(&server, "tet", "password").await;
+    let mut admin = common::admin_client(&server.url, access_token).await;
+    let get_user = |username: &str| GetUserReq {
+        username: username.to_string(),
+    };
+    let details = |res: tonic::Response<proto::client::admin::GetUserRes>| -> UserDetails {
+        match res.into_inner().payload {
+            Some(get_user_res::Payload::Ok(get_user_res::Ok { user: Some(user) })) => user,
+            _ => panic!("no user"),
+        }
+    };
+
+    let alice = details(admin.get_user(get_user("alice")).await.unwrap());
+    assert_eq!(alice.signup_date, 0);
+    assert!(alice.last_login_date > 0);
+    let tet = details(admin.get_user(get_user("tet")).await.unwrap());
+    assert!(tet.signup_date > 0);
+    assert!(tet.last_login_date >= tet.signup_date);
+
+    let res = admin
+        .list_users(ListUsersReq::default())
+        .await
+        .unwrap()
+        .into_inner();
+    match res.payload {
+        Some(list_users_res::Payload::Ok(ok)) => {
+            let usernames: Vec<_> = ok.users.iter().map(|user| user.username.as_str()).collect();
+            assert_eq!(usernames, ["alice", "tet"]);
+        }
+        None => panic!("no payload"),
+    }
+    let err = admin.get_user(get_user("bob")).await.unwrap_err();
+    assert_eq!(err.code(), Code::NotFound);
+}